@@ -0,0 +1,120 @@
+// benches/keymap.rs
+// Per-key costs for a burst of plain ASCII typing (`cargo bench --bench keymap`): the key
+// mapping, against the per-character match table `map_key_event_to_fcitx` used before, and
+// the preedit width, tracked incrementally by `AppState` against measuring it whole.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use fcitx5_fep_rust::keymap::{keysyms, map_key_event_to_fcitx};
+use fcitx5_fep_rust::state::{AppState, FcitxUpdate};
+
+const BURST: &str = "The quick brown fox jumps over the lazy dog; 0123456789 !\"#$%&'()*+,-./:<=>?@[\\]^_`{|}~";
+
+/// The former table for printable ASCII, kept as the baseline.
+fn table_keysym(c: char) -> Option<u32> {
+    Some(match c {
+        ' ' => keysyms::XK_space,
+        '!' => keysyms::XK_exclam, '"' => keysyms::XK_quotedbl, '#' => keysyms::XK_numbersign,
+        '$' => keysyms::XK_dollar, '%' => keysyms::XK_percent, '&' => keysyms::XK_ampersand,
+        '\'' => keysyms::XK_apostrophe, '(' => keysyms::XK_parenleft, ')' => keysyms::XK_parenright,
+        '*' => keysyms::XK_asterisk, '+' => keysyms::XK_plus, ',' => keysyms::XK_comma,
+        '-' => keysyms::XK_minus, '.' => keysyms::XK_period, '/' => keysyms::XK_slash,
+        '0' => keysyms::XK_0, '1' => keysyms::XK_1, '2' => keysyms::XK_2, '3' => keysyms::XK_3,
+        '4' => keysyms::XK_4, '5' => keysyms::XK_5, '6' => keysyms::XK_6, '7' => keysyms::XK_7,
+        '8' => keysyms::XK_8, '9' => keysyms::XK_9,
+        ':' => keysyms::XK_colon, ';' => keysyms::XK_semicolon, '<' => keysyms::XK_less,
+        '=' => keysyms::XK_equal, '>' => keysyms::XK_greater, '?' => keysyms::XK_question,
+        '@' => keysyms::XK_at,
+        'A' => keysyms::XK_A, 'B' => keysyms::XK_B, 'C' => keysyms::XK_C, 'D' => keysyms::XK_D,
+        'E' => keysyms::XK_E, 'F' => keysyms::XK_F, 'G' => keysyms::XK_G, 'H' => keysyms::XK_H,
+        'I' => keysyms::XK_I, 'J' => keysyms::XK_J, 'K' => keysyms::XK_K, 'L' => keysyms::XK_L,
+        'M' => keysyms::XK_M, 'N' => keysyms::XK_N, 'O' => keysyms::XK_O, 'P' => keysyms::XK_P,
+        'Q' => keysyms::XK_Q, 'R' => keysyms::XK_R, 'S' => keysyms::XK_S, 'T' => keysyms::XK_T,
+        'U' => keysyms::XK_U, 'V' => keysyms::XK_V, 'W' => keysyms::XK_W, 'X' => keysyms::XK_X,
+        'Y' => keysyms::XK_Y, 'Z' => keysyms::XK_Z,
+        '[' => keysyms::XK_bracketleft, '\\' => keysyms::XK_backslash, ']' => keysyms::XK_bracketright,
+        '^' => keysyms::XK_asciicircum, '_' => keysyms::XK_underscore, '`' => keysyms::XK_grave,
+        'a' => keysyms::XK_a, 'b' => keysyms::XK_b, 'c' => keysyms::XK_c, 'd' => keysyms::XK_d,
+        'e' => keysyms::XK_e, 'f' => keysyms::XK_f, 'g' => keysyms::XK_g, 'h' => keysyms::XK_h,
+        'i' => keysyms::XK_i, 'j' => keysyms::XK_j, 'k' => keysyms::XK_k, 'l' => keysyms::XK_l,
+        'm' => keysyms::XK_m, 'n' => keysyms::XK_n, 'o' => keysyms::XK_o, 'p' => keysyms::XK_p,
+        'q' => keysyms::XK_q, 'r' => keysyms::XK_r, 's' => keysyms::XK_s, 't' => keysyms::XK_t,
+        'u' => keysyms::XK_u, 'v' => keysyms::XK_v, 'w' => keysyms::XK_w, 'x' => keysyms::XK_x,
+        'y' => keysyms::XK_y, 'z' => keysyms::XK_z,
+        '{' => keysyms::XK_braceleft, '|' => keysyms::XK_bar, '}' => keysyms::XK_braceright,
+        '~' => keysyms::XK_asciitilde,
+        _ => return None,
+    })
+}
+
+fn ascii_burst(c: &mut Criterion) {
+    let events: Vec<KeyEvent> = BURST
+        .chars()
+        .map(|ch| {
+            let modifiers = if ch.is_ascii_uppercase() { KeyModifiers::SHIFT } else { KeyModifiers::NONE };
+            KeyEvent::new(KeyCode::Char(ch), modifiers)
+        })
+        .collect();
+    let mut group = c.benchmark_group("map_key_event_to_fcitx");
+    group.throughput(Throughput::Elements(events.len() as u64));
+    group.bench_function("ascii_burst", |b| {
+        b.iter(|| {
+            for event in &events {
+                black_box(map_key_event_to_fcitx(black_box(event)));
+            }
+        })
+    });
+    group.finish();
+
+    // The keysym lookup alone: the former table against the code point the mapping uses now
+    let chars: Vec<char> = BURST.chars().collect();
+    let mut group = c.benchmark_group("ascii_keysym");
+    group.throughput(Throughput::Elements(chars.len() as u64));
+    group.bench_function("table_baseline", |b| {
+        b.iter(|| {
+            for &ch in &chars {
+                black_box(table_keysym(black_box(ch)));
+            }
+        })
+    });
+    group.bench_function("code_point", |b| {
+        b.iter(|| {
+            for &ch in &chars {
+                black_box(black_box(ch) as u32);
+            }
+        })
+    });
+    group.finish();
+}
+
+/// A burst of keys composed into one growing preedit, as an engine echoing Latin input reports it.
+fn preedit_burst(c: &mut Criterion) {
+    let updates: Vec<FcitxUpdate> = (1..=BURST.len())
+        .map(|end| FcitxUpdate::UpdatePreedit { text: BURST[..end].to_string(), cursor_pos: end as i32 })
+        .collect();
+    let mut group = c.benchmark_group("preedit_width");
+    group.throughput(Throughput::Elements(updates.len() as u64));
+    // Baseline: a fresh state per update measures the whole preedit every time
+    group.bench_function("whole_baseline", |b| {
+        b.iter(|| {
+            for update in &updates {
+                let mut state = AppState::new();
+                state.apply_update(black_box(update.clone()));
+                black_box(state.preedit_width);
+            }
+        })
+    });
+    group.bench_function("incremental", |b| {
+        b.iter(|| {
+            let mut state = AppState::new();
+            for update in &updates {
+                state.apply_update(black_box(update.clone()));
+                black_box(state.preedit_width);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ascii_burst, preedit_burst);
+criterion_main!(benches);
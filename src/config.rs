@@ -0,0 +1,679 @@
+// src/config.rs
+// Runtime configuration for the FEP, parsed from command-line arguments.
+
+use crate::error::FepError;
+use crate::fcitx::{FocusLossPolicy, CAPABILITY_FLAGS};
+use crate::keymap::keysyms;
+use crate::history::DEFAULT_HISTORY_SIZE;
+use crate::reconnect::{FailurePolicy, ReconnectConfig};
+use crate::sanitize::{CommitFilter, SanitizePolicy};
+use crate::sink::SinkSpec;
+use crate::utf8::Utf8Decoder;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Default maximum number of preedit characters drawn on screen.
+pub const DEFAULT_MAX_PREEDIT_LEN: usize = 256;
+
+/// Default time to wait for further Fcitx updates before rendering.
+pub const DEFAULT_RENDER_DEBOUNCE: Duration = Duration::from_millis(4);
+
+/// Program name reported to Fcitx unless `--program` is given.
+pub const DEFAULT_PROGRAM: &str = "fep-rust-example-async";
+
+/// Whether the renderer may use colors and text attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    Auto,   // Detect from TERM (a dumb terminal gets plain text)
+    Always, // Force colors and attributes on
+    Never,  // Plain text only, no colors or attributes
+}
+
+/// How the Tab key is routed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TabBehavior {
+    Forward,        // Always forward Tab to Fcitx
+    WhenCandidates, // Forward only while a candidate window may be open, else write a literal tab
+    Literal,        // Always write a literal tab
+}
+
+/// How committed text is shown in the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitDisplay {
+    Inline,   // Accumulate commits on the preedit line
+    Scroll,   // End each commit with a line break, so it scrolls up and the preedit stays at the bottom
+    Suppress, // Don't show commits; they only go to the other commit sinks
+}
+
+/// A key combination intercepted by the FEP instead of being forwarded to Fcitx.
+/// Written as modifiers and a key joined by '+', e.g. `ctrl+j`, `alt+enter`, `f12`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotkey {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl Hotkey {
+    /// Parses a hotkey specification such as `ctrl+j` or `alt+f5`.
+    pub fn parse(spec: &str) -> Result<Self, FepError> {
+        let invalid = || FepError::Config(format!("Invalid hotkey: '{}'", spec));
+        let mut modifiers = KeyModifiers::NONE;
+        let mut parts: Vec<&str> = spec.split('+').collect();
+        let key = parts.pop().filter(|key| !key.is_empty()).ok_or_else(invalid)?;
+        for modifier in parts {
+            modifiers |= match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(invalid()),
+            };
+        }
+        let code = match key.to_ascii_lowercase().as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "tab" => KeyCode::Tab,
+            "esc" | "escape" => KeyCode::Esc,
+            "backspace" => KeyCode::Backspace,
+            "delete" => KeyCode::Delete,
+            "space" => KeyCode::Char(' '),
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            lower => match (lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()), key.chars().count()) {
+                (Some(n), _) if (1..=24).contains(&n) => KeyCode::F(n),
+                (_, 1) => KeyCode::Char(key.chars().next().ok_or_else(invalid)?),
+                _ => return Err(invalid()),
+            },
+        };
+        Ok(Hotkey { code, modifiers })
+    }
+
+    /// Returns true if `key_event` is this hotkey.
+    /// Shift is ignored for character keys, since crossterm already reports the shifted character.
+    pub fn matches(&self, key_event: &KeyEvent) -> bool {
+        let mut modifiers = key_event.modifiers;
+        if matches!(key_event.code, KeyCode::Char(_)) && !self.modifiers.contains(KeyModifiers::SHIFT) {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        key_event.code == self.code && modifiers == self.modifiers
+    }
+}
+
+/// Runtime configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Maximum number of preedit characters rendered. Longer preedits are elided
+    /// in the middle with "…" (the full text is still kept in `AppState`).
+    pub max_preedit_len: usize,
+    /// One-shot mode: forward this (keysym, state) pair, print the result and exit.
+    pub send_key: Option<(u32, u32)>,
+    /// Where committed text is delivered. Defaults to the terminal only.
+    pub commit_sinks: Vec<SinkSpec>,
+    /// Log key-to-update latency and keep running min/avg/max.
+    pub metrics: bool,
+    /// Print per-key diagnostics (dropped keys, each latency sample) to stderr while running.
+    pub verbose: bool,
+    /// Styling override (`--color auto|always|never`).
+    pub color: ColorMode,
+    /// What to do with an active preedit on FocusOut (`--on-focus-loss commit|discard|engine`).
+    pub focus_loss: FocusLossPolicy,
+    /// Tab routing (`--tab forward|candidates|literal`).
+    pub tab: TabBehavior,
+    /// One-shot mode: print the available input methods and exit.
+    pub list_ims: bool,
+    /// One-shot mode: check the Fcitx setup, print a report and exit.
+    pub self_test: bool,
+    /// Hide the terminal cursor while the FEP runs (`--show-cursor` keeps it visible).
+    pub hide_cursor: bool,
+    /// Hotkey that commits the current preedit as-is (no default).
+    pub commit_preedit_key: Option<Hotkey>,
+    /// Hotkey that toggles the keybinding debug overlay (no default).
+    pub debug_overlay_key: Option<Hotkey>,
+    /// Pass unhandled printable keys through while Fcitx has no input method configured.
+    pub no_im_passthrough: bool,
+    /// How committed text is shown in the terminal.
+    pub commit_display: CommitDisplay,
+    /// Hotkey after which the next key bypasses Fcitx and is committed as-is.
+    pub literal_key: Option<Hotkey>,
+    /// Bytes emitted for symbolic keys that Fcitx doesn't handle, by keysym.
+    pub fallback_keys: Vec<(u32, String)>,
+    /// How to reconnect after losing the connection to Fcitx.
+    pub reconnect: ReconnectConfig,
+    /// Always forward Esc to Fcitx instead of the preedit-aware cancel/pass-through handling.
+    pub forward_esc: bool,
+    /// Always forward Delete to Fcitx, even with no preedit for it to act on.
+    pub forward_delete: bool,
+    /// Send FocusIn/FocusOut to Fcitx as the terminal gains and loses focus.
+    pub follow_terminal_focus: bool,
+    /// Forward function, lock, media and modifier keys to Fcitx instead of dropping them.
+    pub forward_unmapped_keys: bool,
+    /// How long to wait for back-to-back updates before rendering them together (0 = don't wait).
+    pub render_debounce: Duration,
+    /// Approximate size of a terminal cell in pixels (width, height), used to convert the
+    /// cursor cell into the rectangle sent with SetCursorRect.
+    pub cell_size: (u32, u32),
+    /// Echo printable keys immediately in a "pending" style before Fcitx responds.
+    pub optimistic_echo: bool,
+    /// Edit committed text locally (Home/End/Left/Right/Backspace/Delete) and submit it with Enter.
+    pub line_edit: bool,
+    /// Write a newline-delimited JSON event log to this file.
+    pub event_log: Option<PathBuf>,
+    /// Program name sent to Fcitx when creating the input context (e.g. the wrapped command).
+    pub program: String,
+    /// Remember the input method per program and restore it on startup.
+    pub remember_im: bool,
+    /// Send real key event timestamps to Fcitx (`--zero-key-time` sends the legacy 0).
+    pub key_timestamps: bool,
+    /// Enable the kitty keyboard protocol when the terminal supports it, so Ctrl+Shift
+    /// combinations and key releases are reported.
+    pub keyboard_enhancement: bool,
+    /// Draw Fcitx's candidate list below the preedit, this many rows high (0 = Fcitx draws it).
+    pub candidate_rows: usize,
+    /// Capability flags advertised to Fcitx in place of the default set (`--capability`).
+    pub capabilities: Option<u64>,
+    /// Most candidates of a page the candidate window shows (0 = all).
+    pub max_candidates: usize,
+    /// Drawn dim at the cursor while focused with no preedit; `{im}` is the input method (empty = none).
+    pub preedit_placeholder: String,
+    /// Terminal title set whenever the input method changes; `{im}` is its name and `{state}`
+    /// `on`/`off` (empty = leave the title alone).
+    pub im_title: String,
+    /// Record committed strings in a persistent history.
+    pub history: bool,
+    /// Maximum number of history entries kept.
+    pub history_size: usize,
+    /// Hotkey that cycles through recent commits (requires `history`).
+    pub history_key: Hotkey,
+    /// How control characters in committed text are neutralized.
+    pub commit_filter: CommitFilter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_preedit_len: DEFAULT_MAX_PREEDIT_LEN,
+            send_key: None,
+            commit_sinks: vec![SinkSpec::Terminal],
+            metrics: false,
+            verbose: false,
+            color: ColorMode::Auto,
+            focus_loss: FocusLossPolicy::Engine,
+            tab: TabBehavior::WhenCandidates,
+            list_ims: false,
+            self_test: false,
+            hide_cursor: true,
+            commit_preedit_key: None,
+            debug_overlay_key: None,
+            literal_key: None,
+            commit_display: CommitDisplay::Inline,
+            no_im_passthrough: false,
+            fallback_keys: default_fallback_keys(),
+            reconnect: ReconnectConfig::default(),
+            forward_esc: false,
+            forward_delete: false,
+            forward_unmapped_keys: false,
+            follow_terminal_focus: false,
+            render_debounce: DEFAULT_RENDER_DEBOUNCE,
+            cell_size: (8, 16),
+            optimistic_echo: false,
+            line_edit: false,
+            event_log: None,
+            program: DEFAULT_PROGRAM.to_string(),
+            remember_im: false,
+            key_timestamps: true,
+            keyboard_enhancement: true,
+            candidate_rows: 0,
+            capabilities: None,
+            max_candidates: 0,
+            preedit_placeholder: String::new(),
+            im_title: String::new(),
+            history: false,
+            history_size: DEFAULT_HISTORY_SIZE,
+            history_key: Hotkey { code: KeyCode::Char('r'), modifiers: KeyModifiers::ALT },
+            commit_filter: CommitFilter::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Parses the configuration from the process arguments.
+    pub fn from_args() -> Result<Self, FepError> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    /// Parses the configuration from an iterator of arguments (excluding the program name).
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, FepError> {
+        let mut config = Config::default();
+        let mut explicit_sinks = Vec::new();
+        let mut on_commit = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--max-preedit-len" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.max_preedit_len = value.parse().map_err(|_| {
+                        FepError::Config(format!("Invalid value for {}: '{}'", arg, value))
+                    })?;
+                }
+                "--send-key" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.send_key = Some(parse_key_spec(&value)?);
+                }
+                "--metrics" => config.metrics = true,
+                "--verbose" => config.verbose = true,
+                "--list-ims" => config.list_ims = true,
+                "--self-test" => config.self_test = true,
+                "--show-cursor" => config.hide_cursor = false,
+                "--forward-esc" => config.forward_esc = true,
+                "--forward-delete" => config.forward_delete = true,
+                "--follow-terminal-focus" => config.follow_terminal_focus = true,
+                "--optimistic-echo" => config.optimistic_echo = true,
+                "--line-edit" => config.line_edit = true,
+                "--program" => config.program = next_value(&mut args, &arg)?,
+                "--remember-im" => config.remember_im = true,
+                "--zero-key-time" => config.key_timestamps = false,
+                "--no-keyboard-enhancement" => config.keyboard_enhancement = false,
+                "--event-log" => config.event_log = Some(next_value(&mut args, &arg)?.into()),
+                "--history" => config.history = true,
+                "--candidate-window" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.candidate_rows = value.parse().map_err(|_| {
+                        FepError::Config(format!("Invalid value for {}: '{}'", arg, value))
+                    })?;
+                }
+                "--max-candidates" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.max_candidates = value.parse().map_err(|_| {
+                        FepError::Config(format!("Invalid value for {}: '{}'", arg, value))
+                    })?;
+                }
+                "--capability" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.capabilities = Some(parse_capabilities(&value)?);
+                }
+                "--preedit-placeholder" => config.preedit_placeholder = next_value(&mut args, &arg)?,
+                "--im-title" => config.im_title = next_value(&mut args, &arg)?,
+                "--history-size" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.history_size = value.parse().map_err(|_| {
+                        FepError::Config(format!("Invalid value for {}: '{}'", arg, value))
+                    })?;
+                }
+                "--history-key" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.history_key = Hotkey::parse(&value)?;
+                }
+                "--commit-preedit-key" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.commit_preedit_key = Some(Hotkey::parse(&value)?);
+                }
+                "--debug-overlay-key" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.debug_overlay_key = Some(Hotkey::parse(&value)?);
+                }
+                "--literal-key" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.literal_key = Some(Hotkey::parse(&value)?);
+                }
+                "--sanitize-commits" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.commit_filter.policy = match value.as_str() {
+                        "strict" => SanitizePolicy::Strict,
+                        "permissive" => SanitizePolicy::Permissive,
+                        _ => return Err(FepError::Config(format!("Invalid value for {}: '{}'", arg, value))),
+                    };
+                }
+                "--strip-commit-newlines" => config.commit_filter.allow_newline = false,
+                "--on-focus-loss" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.focus_loss = match value.as_str() {
+                        "commit" => FocusLossPolicy::Commit,
+                        "discard" => FocusLossPolicy::Discard,
+                        "engine" => FocusLossPolicy::Engine,
+                        _ => return Err(FepError::Config(format!("Invalid value for {}: '{}'", arg, value))),
+                    };
+                }
+                "--on-no-input-method" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.no_im_passthrough = match value.as_str() {
+                        "warn" => false,
+                        "passthrough" => true,
+                        _ => return Err(FepError::Config(format!("Invalid value for {}: '{}'", arg, value))),
+                    };
+                }
+                "--commit-display" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.commit_display = match value.as_str() {
+                        "inline" => CommitDisplay::Inline,
+                        "scroll" => CommitDisplay::Scroll,
+                        "suppress" => CommitDisplay::Suppress,
+                        _ => return Err(FepError::Config(format!("Invalid value for {}: '{}'", arg, value))),
+                    };
+                }
+                "--tab" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.tab = match value.as_str() {
+                        "forward" => TabBehavior::Forward,
+                        "candidates" => TabBehavior::WhenCandidates,
+                        "literal" => TabBehavior::Literal,
+                        _ => return Err(FepError::Config(format!("Invalid value for {}: '{}'", arg, value))),
+                    };
+                }
+                "--unmapped-keys" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.forward_unmapped_keys = match value.as_str() {
+                        "forward" => true,
+                        "drop" => false,
+                        _ => return Err(FepError::Config(format!("Invalid value for {}: '{}'", arg, value))),
+                    };
+                }
+                "--color" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.color = match value.as_str() {
+                        "auto" => ColorMode::Auto,
+                        "always" => ColorMode::Always,
+                        "never" => ColorMode::Never,
+                        _ => return Err(FepError::Config(format!("Invalid value for {}: '{}'", arg, value))),
+                    };
+                }
+                "--on-commit" => {
+                    // Split into argv with shell quoting rules and run without a shell, so committed
+                    // text (substituted for each `{}`) can never be interpreted as shell syntax.
+                    let value = next_value(&mut args, &arg)?;
+                    let argv = split_command(&value)
+                        .filter(|argv| !argv.is_empty())
+                        .ok_or_else(|| FepError::Config(format!("Invalid value for {}: '{}'", arg, value)))?;
+                    on_commit = Some(SinkSpec::Command(argv));
+                }
+                "--commit-sink" => {
+                    // May be given several times; replaces the default terminal sink.
+                    let value = next_value(&mut args, &arg)?;
+                    explicit_sinks.push(SinkSpec::parse(&value)?);
+                }
+                "--stream-grace-ms" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let millis: u64 = value.parse().map_err(|_| {
+                        FepError::Config(format!("Invalid value for {}: '{}'", arg, value))
+                    })?;
+                    config.reconnect.stream_grace = Duration::from_millis(millis);
+                }
+                "--render-debounce-ms" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let millis: u64 = value.parse().map_err(|_| {
+                        FepError::Config(format!("Invalid value for {}: '{}'", arg, value))
+                    })?;
+                    config.render_debounce = Duration::from_millis(millis);
+                }
+                "--cell-size" => {
+                    // <width>x<height> in pixels
+                    let value = next_value(&mut args, &arg)?;
+                    config.cell_size = value
+                        .split_once('x')
+                        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                        .filter(|&(w, h)| w > 0 && h > 0)
+                        .ok_or_else(|| FepError::Config(format!("Invalid value for {}: '{}'", arg, value)))?;
+                }
+                "--reconnect-timeout" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let secs: u64 = value.parse().map_err(|_| {
+                        FepError::Config(format!("Invalid value for {}: '{}'", arg, value))
+                    })?;
+                    config.reconnect.max_total = Duration::from_secs(secs);
+                }
+                "--on-fcitx-failure" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.reconnect.on_failure = match value.as_str() {
+                        "exit" => FailurePolicy::Exit,
+                        "passthrough" => FailurePolicy::Passthrough,
+                        _ => return Err(FepError::Config(format!("Invalid value for {}: '{}'", arg, value))),
+                    };
+                }
+                "--fallback-key" => {
+                    // <keysym>=<bytes>, overriding the default table entry for that keysym
+                    let value = next_value(&mut args, &arg)?;
+                    let (keysym, bytes) = parse_fallback_key(&value)?;
+                    config.fallback_keys.retain(|(k, _)| *k != keysym);
+                    config.fallback_keys.push((keysym, bytes));
+                }
+                _ => return Err(FepError::Config(format!("Unknown argument: '{}'", arg))),
+            }
+        }
+        if !explicit_sinks.is_empty() {
+            config.commit_sinks = explicit_sinks;
+        }
+        // The commit hook runs in addition to the sinks, and doesn't replace the terminal
+        config.commit_sinks.extend(on_commit);
+        Ok(config)
+    }
+
+    /// Returns the bytes to emit when Fcitx doesn't handle `keysym`, if any.
+    pub fn fallback_bytes(&self, keysym: u32) -> Option<&str> {
+        self.fallback_keys
+            .iter()
+            .find(|(k, _)| *k == keysym)
+            .map(|(_, bytes)| bytes.as_str())
+    }
+}
+
+/// Default fallback table: the VT sequences a terminal would send for these keys.
+fn default_fallback_keys() -> Vec<(u32, String)> {
+    [
+        (keysyms::XK_Tab, "\t"),
+        (keysyms::XK_Return, "\r"),
+        (keysyms::XK_BackSpace, "\x7f"),
+        (keysyms::XK_Escape, "\x1b"),
+        (keysyms::XK_Delete, "\x1b[3~"),
+        (keysyms::XK_Up, "\x1b[A"),
+        (keysyms::XK_Down, "\x1b[B"),
+        (keysyms::XK_Right, "\x1b[C"),
+        (keysyms::XK_Left, "\x1b[D"),
+        (keysyms::XK_Page_Up, "\x1b[5~"),
+        (keysyms::XK_Page_Down, "\x1b[6~"),
+        (keysyms::XK_Home, "\x1b[H"),
+        (keysyms::XK_End, "\x1b[F"),
+        (keysyms::XK_Insert, "\x1b[2~"),
+        (keysyms::XK_ISO_Left_Tab, "\x1b[Z"),
+    ]
+    .into_iter()
+    .map(|(keysym, bytes)| (keysym, bytes.to_string()))
+    .collect()
+}
+
+/// Parses a `<keysym>=<bytes>` fallback entry. The keysym is a name or number as in
+/// `--send-key`; the bytes may use the escapes `\e`, `\t`, `\r`, `\n`, `\\` and `\xNN`.
+fn parse_fallback_key(spec: &str) -> Result<(u32, String), FepError> {
+    let invalid = || FepError::Config(format!("Invalid fallback key: '{}'", spec));
+    let (keysym, bytes) = spec.split_once('=').ok_or_else(invalid)?;
+    let (keysym, _) = parse_key_spec(keysym)?;
+    Ok((keysym, unescape(bytes).ok_or_else(invalid)?))
+}
+
+/// Expands backslash escapes in a command-line value. `\xNN` is a raw byte, so a
+/// multibyte character can be spelled as its UTF-8 sequence (`\xe3\x81\x82` is "あ");
+/// bytes that don't form valid UTF-8 become U+FFFD.
+fn unescape(s: &str) -> Option<String> {
+    let mut decoder = Utf8Decoder::new();
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push_str(&decoder.decode(c.encode_utf8(&mut [0; 4]).as_bytes()));
+            continue;
+        }
+        let byte = match chars.next()? {
+            'e' => 0x1b,
+            't' => b'\t',
+            'r' => b'\r',
+            'n' => b'\n',
+            '\\' => b'\\',
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16).ok()?
+            }
+            _ => return None,
+        };
+        out.push_str(&decoder.decode(&[byte]));
+    }
+    out.push_str(&decoder.finish());
+    Some(out)
+}
+
+/// Returns the value following an option, or an error if it is missing.
+fn next_value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, FepError> {
+    args.next()
+        .ok_or_else(|| FepError::Config(format!("Missing value for {}", option)))
+}
+
+/// Parses a `<keysym>[:<state>]` key specification.
+/// The keysym may be hex (`0xff0d`) or a symbolic name (`Return`); the state is a
+/// decimal or hex X11 modifier mask.
+fn parse_key_spec(spec: &str) -> Result<(u32, u32), FepError> {
+    let invalid = || FepError::Config(format!("Invalid key specification: '{}'", spec));
+    let (keysym, state) = match spec.split_once(':') {
+        Some((keysym, state)) => (keysym, Some(state)),
+        None => (spec, None),
+    };
+    // Names take precedence over decimal numbers so that e.g. "7" means the '7' key.
+    let keysym = keysyms::from_name(keysym)
+        .or_else(|| parse_number(keysym))
+        .ok_or_else(invalid)?;
+    // A Unicode keysym must encode a valid character (no surrogates, nothing past U+10FFFF)
+    if keysym & 0xff00_0000 == 0x0100_0000 && keysyms::to_char(keysym).is_none() {
+        return Err(invalid());
+    }
+    let state = match state {
+        Some(state) => parse_number(state).ok_or_else(invalid)?,
+        None => 0,
+    };
+    Ok((keysym, state))
+}
+
+/// Parses a comma-separated list of capability flag names (as in Fcitx's CapabilityFlag,
+/// case-insensitive) or `0x`-prefixed hex masks into one mask. `none` is the empty set.
+fn parse_capabilities(spec: &str) -> Result<u64, FepError> {
+    let mut caps = 0;
+    for flag in spec.split(',').map(str::trim) {
+        if flag.eq_ignore_ascii_case("none") {
+            continue;
+        }
+        let bits = match flag.strip_prefix("0x").or_else(|| flag.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => CAPABILITY_FLAGS.iter().find(|(name, _)| name.eq_ignore_ascii_case(flag)).map(|&(_, bits)| bits),
+        };
+        caps |= bits.ok_or_else(|| {
+            let names: Vec<&str> = CAPABILITY_FLAGS.iter().map(|&(name, _)| name).collect();
+            FepError::Config(format!(
+                "Unknown capability flag '{}'. Valid flags: {} (or a 0x mask, or none)",
+                flag,
+                names.join(", ")
+            ))
+        })?;
+    }
+    Ok(caps)
+}
+
+/// Splits a command line into arguments like a POSIX shell would, without expanding
+/// anything: whitespace separates arguments, single quotes keep everything literally,
+/// double quotes keep everything but `\"`, `\\`, `\$` and `` \` `` escapes, and a backslash
+/// outside quotes escapes the next character. Returns `None` for an unterminated quote or a
+/// trailing backslash.
+fn split_command(line: &str) -> Option<Vec<String>> {
+    let mut argv = Vec::new();
+    let mut arg: Option<String> = None; // The argument being built, if one was started
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => argv.extend(arg.take()),
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => arg.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            c @ ('"' | '\\' | '$' | '`') => arg.push(c),
+                            '\n' => {} // Line continuation
+                            c => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                        },
+                        c => arg.push(c),
+                    }
+                }
+            }
+            '\\' => match chars.next()? {
+                '\n' => {} // Line continuation
+                c => arg.get_or_insert_with(String::new).push(c),
+            },
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    argv.extend(arg);
+    Some(argv)
+}
+
+/// Parses a decimal or `0x`-prefixed hex number.
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(words: &[&str]) -> Option<Vec<String>> {
+        Some(words.iter().map(|word| word.to_string()).collect())
+    }
+
+    #[test]
+    fn split_command_follows_shell_quoting() {
+        assert_eq!(split_command("notify-send IME {}"), argv(&["notify-send", "IME", "{}"]));
+        assert_eq!(split_command("  tee  -a '/tmp/my log'  "), argv(&["tee", "-a", "/tmp/my log"]));
+        assert_eq!(split_command(r#"sh -c "echo \"\$1\" >>log" fep {}"#), argv(&["sh", "-c", r#"echo "$1" >>log"#, "fep", "{}"]));
+        assert_eq!(split_command(r"a\ b 'c'd'' "), argv(&["a b", "cd"]));
+        assert_eq!(split_command(r#"x "" ''"#), argv(&["x", "", ""]));
+        // Quotes keep backslashes that escape nothing, and `;` or `$(...)` are just text
+        assert_eq!(split_command(r#""a\b" 'c\d' ;rm $(x)"#), argv(&[r"a\b", r"c\d", ";rm", "$(x)"]));
+        assert_eq!(split_command(""), argv(&[]));
+    }
+
+    #[test]
+    fn split_command_rejects_unterminated_quotes() {
+        assert_eq!(split_command("echo 'oops"), None);
+        assert_eq!(split_command("echo \"oops"), None);
+        assert_eq!(split_command("echo oops\\"), None);
+    }
+
+    #[test]
+    fn on_commit_takes_a_quoted_command() {
+        let config = Config::parse(["--on-commit".to_string(), "tee -a 'my commits.txt'".to_string()]).unwrap();
+        assert!(config.commit_sinks.contains(&SinkSpec::Command(argv(&["tee", "-a", "my commits.txt"]).unwrap())));
+        assert!(Config::parse(["--on-commit".to_string(), "'".to_string()]).is_err());
+        assert!(Config::parse(["--on-commit".to_string(), " ".to_string()]).is_err());
+    }
+
+    #[test]
+    fn unescape_decodes_utf8_byte_escapes() {
+        assert_eq!(unescape(r"\e[A").as_deref(), Some("\x1b[A"));
+        assert_eq!(unescape(r"\xe3\x81\x82").as_deref(), Some("あ"));
+        assert_eq!(unescape(r"a\xe3\x81").as_deref(), Some("a\u{fffd}"));
+        assert_eq!(unescape(r"\q"), None);
+    }
+}
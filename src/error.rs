@@ -4,12 +4,43 @@
 use std::{fmt, io};
 use zbus; // Add zbus for its error type
 
+/// The step of `FcitxClient::connect` that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectStep {
+    BusConnect,         // Connecting to the D-Bus session bus
+    ControllerProxy,    // Creating the Fcitx controller proxy
+    InputMethodProxy,   // Creating the proxy for the D-Bus frontend's InputMethod1
+    CreateInputContext, // Calling CreateInputContext
+    InputContextProxy,  // Creating the proxy for the new input context
+    FocusIn,            // Sending the initial FocusIn
+}
+
+impl fmt::Display for ConnectStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let step = match self {
+            ConnectStep::BusConnect => "connect to the D-Bus session bus",
+            ConnectStep::ControllerProxy => "create the Fcitx controller proxy",
+            ConnectStep::InputMethodProxy => "create the Fcitx input method proxy",
+            ConnectStep::CreateInputContext => "create an input context",
+            ConnectStep::InputContextProxy => "create the input context proxy",
+            ConnectStep::FocusIn => "focus the input context",
+        };
+        f.write_str(step)
+    }
+}
+
 #[derive(Debug)]
 pub enum FepError {
     Io(io::Error),
     TerminalSetup(String),
     FcitxConnection(String),
+    BusLost(String), // The D-Bus session bus itself went away (not just Fcitx)
+    UpdatesEnded(String), // The Fcitx update stream ended and couldn't be resubscribed in time
+    Connect { step: ConnectStep, source: zbus::Error }, // A specific step of connecting to Fcitx failed
     Zbus(zbus::Error), // Include zbus::Error
+    Config(String), // Invalid command-line arguments or configuration values
+    CommitSink(Vec<(String, io::Error)>), // One or more commit sinks failed (sink name, error)
+    FrontendUnavailable(String), // Fcitx runs, but its D-Bus frontend (Controller1/InputContext1) is missing
     // Add other specific error types as needed
 }
 
@@ -19,7 +50,59 @@ impl fmt::Display for FepError {
             FepError::Io(err) => write!(f, "IO Error: {}", err),
             FepError::TerminalSetup(msg) => write!(f, "Terminal Setup Error: {}", msg),
             FepError::FcitxConnection(msg) => write!(f, "Fcitx Connection Error: {}", msg),
+            FepError::BusLost(msg) => write!(f, "D-Bus Session Lost: {}", msg),
+            FepError::UpdatesEnded(msg) => write!(f, "Fcitx Updates Ended: {}", msg),
+            FepError::Connect { step, source } => {
+                write!(f, "Fcitx Connection Error: failed to {}: {}", step, source)
+            }
             FepError::Zbus(err) => write!(f, "D-Bus Error: {}", err),
+            FepError::Config(msg) => write!(f, "Configuration Error: {}", msg),
+            FepError::FrontendUnavailable(msg) => write!(f, "Fcitx D-Bus Frontend Error: {}", msg),
+            FepError::CommitSink(failures) => {
+                write!(f, "Commit Sink Error:")?;
+                for (i, (sink, err)) in failures.iter().enumerate() {
+                    write!(f, "{} {}: {}", if i == 0 { "" } else { ";" }, sink, err)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FepError {
+    /// Returns true if the error means the connection to Fcitx was lost or broken,
+    /// i.e. reconnecting may help.
+    pub fn is_connection_error(&self) -> bool {
+        matches!(
+            self,
+            FepError::FcitxConnection(_)
+                | FepError::BusLost(_)
+                | FepError::UpdatesEnded(_)
+                | FepError::Connect { .. }
+                | FepError::Zbus(_)
+        )
+    }
+
+    /// Maps a failed D-Bus call (`call` names it) to `BusLost` if the bus connection itself
+    /// broke (its socket was closed or reset), or to `FcitxConnection` otherwise, e.g. when
+    /// Fcitx is no longer on the bus.
+    pub fn from_call(call: &str, err: zbus::Error) -> Self {
+        let bus_lost = match &err {
+            zbus::Error::InputOutput(io_err) => matches!(
+                io_err.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        };
+        let msg = format!("{} failed: {}", call, err);
+        if bus_lost {
+            FepError::BusLost(msg)
+        } else {
+            FepError::FcitxConnection(msg)
         }
     }
 }
@@ -29,6 +112,7 @@ impl std::error::Error for FepError {
         match self {
             FepError::Io(err) => Some(err),
             FepError::Zbus(err) => Some(err),
+            FepError::Connect { source, .. } => Some(source),
             _ => None,
         }
     }
@@ -48,10 +132,3 @@ impl From<zbus::Error> for FepError {
         FepError::Zbus(err)
     }
 }
-
-// Allow converting FepError into Box<dyn Error> for main result type
-impl From<FepError> for Box<dyn std::error::Error> {
-    fn from(err: FepError) -> Self {
-        Box::new(err)
-    }
-}
@@ -0,0 +1,140 @@
+// src/event_log.rs
+// Machine-readable event log (enabled with --event-log <path>): one JSON object per line.
+
+use crate::error::FepError;
+use crate::state::FcitxUpdate;
+use crossterm::event::KeyEvent;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// Schema version written as `"v"` in every record. Bump it when fields change meaning
+/// or are removed; adding fields or event kinds is backwards compatible.
+pub const EVENT_LOG_VERSION: u32 = 1;
+
+/// Writes newline-delimited JSON records of key, forwarding, update and commit events.
+///
+/// Records are handed to a writer thread over a channel, so logging never blocks the event
+/// loop; the thread buffers writes and flushes whenever it has caught up. Logging is best
+/// effort: write errors are reported once and further records are dropped.
+pub struct EventLog {
+    tx: Option<mpsc::Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+    started: Instant,
+}
+
+impl EventLog {
+    /// Opens (truncating) the log file and starts the writer thread.
+    pub fn open(path: &Path) -> Result<Self, FepError> {
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(path)
+            .map_err(|e| FepError::Config(format!("Failed to open event log {}: {}", path.display(), e)))?;
+        let (tx, rx) = mpsc::channel::<String>();
+        let writer = std::thread::spawn(move || {
+            let mut out = BufWriter::new(file);
+            while let Ok(record) = rx.recv() {
+                let mut result = out.write_all(record.as_bytes());
+                // Flush once the queue is drained, so the file is current when the FEP is idle
+                while let (Ok(()), Ok(record)) = (&result, rx.try_recv()) {
+                    result = out.write_all(record.as_bytes());
+                }
+                if let Err(e) = result.and_then(|_| out.flush()) {
+                    eprintln!("Event log write failed, disabling it: {}", e);
+                    return;
+                }
+            }
+        });
+        Ok(EventLog { tx: Some(tx), writer: Some(writer), started: Instant::now() })
+    }
+
+    /// A key event read from the terminal.
+    pub fn key(&self, key_event: &KeyEvent) {
+        self.record("key", &format!(
+            "\"code\":{},\"modifiers\":{},\"kind\":{}",
+            json_string(&format!("{:?}", key_event.code)),
+            json_string(&format!("{:?}", key_event.modifiers)),
+            json_string(&format!("{:?}", key_event.kind))
+        ));
+    }
+
+    /// A key event forwarded to Fcitx, with whether Fcitx handled it.
+    pub fn forwarded(&self, keysym: u32, state: u32, handled: bool) {
+        self.record("forward", &format!("\"keysym\":{},\"state\":{},\"handled\":{}", keysym, state, handled));
+    }
+
+    /// An update received from Fcitx.
+    pub fn update(&self, update: &FcitxUpdate) {
+        let fields = match update {
+            FcitxUpdate::CommitString(text) => format!("\"kind\":\"commit\",\"text\":{}", json_string(text)),
+            FcitxUpdate::CommitStringWithCursor { text, cursor } => format!(
+                "\"kind\":\"commit\",\"text\":{},\"cursor\":{}",
+                json_string(text),
+                cursor
+            ),
+            FcitxUpdate::UpdatePreedit { text, cursor_pos } => format!(
+                "\"kind\":\"preedit\",\"text\":{},\"cursor\":{}",
+                json_string(text),
+                cursor_pos
+            ),
+            FcitxUpdate::ClientSideUi { candidates, highlighted, aux_visible, has_prev, has_next } => format!(
+                "\"kind\":\"ui\",\"visible\":{},\"candidates\":[{}],\"highlighted\":{},\"has_prev\":{},\"has_next\":{}",
+                *aux_visible || !candidates.is_empty(),
+                candidates.iter().map(|candidate| json_string(candidate)).collect::<Vec<_>>().join(","),
+                highlighted.map_or("null".to_string(), |index| index.to_string()),
+                has_prev,
+                has_next
+            ),
+            FcitxUpdate::CurrentIm(name) => format!("\"kind\":\"im\",\"name\":{}", json_string(name)),
+            FcitxUpdate::InputMethodsChanged => "\"kind\":\"im_config\"".to_string(),
+        };
+        self.record("update", &fields);
+    }
+
+    /// Text written to the terminal and commit sinks.
+    pub fn commit(&self, text: &str) {
+        self.record("commit", &format!("\"text\":{}", json_string(text)));
+    }
+
+    fn record(&self, event: &str, fields: &str) {
+        let Some(tx) = &self.tx else { return };
+        let record = format!(
+            "{{\"v\":{},\"t_ms\":{},\"event\":\"{}\",{}}}\n",
+            EVENT_LOG_VERSION,
+            self.started.elapsed().as_millis(),
+            event,
+            fields
+        );
+        let _ = tx.send(record); // The writer may have given up; logging is best effort
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer drain and flush what is queued, then exit.
+        self.tx = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Encodes `s` as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
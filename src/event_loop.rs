@@ -1,241 +1,500 @@
 // src/event_loop.rs
 // The main asynchronous event loop using tokio::select!
 
+use crate::config::{Config, TabBehavior};
 use crate::error::FepError;
 use crate::fcitx::FcitxClient;
+use crate::history::History;
+use crate::im_memory::ImMemory;
+use crate::keymap::{keysyms, map_key_event_to_fcitx, map_unmapped_key, masks};
+use crate::line_editor::LineEdit;
+use crate::metrics::LatencyMetrics;
+use crate::overlay::KeyDebugOverlay;
+use crate::reconnect::{self, FailurePolicy};
 use crate::state::{AppState, FcitxUpdate}; // Import FcitxUpdate
-use crate::terminal::Terminal;
+use crate::terminal::{Terminal, TerminalInput};
+use crate::xkb::KeycodeResolver;
 
-use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
-use futures_util::{StreamExt}; // StreamExt for stream methods like next()
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures_util::{FutureExt, Stream, StreamExt}; // StreamExt for next(), FutureExt for now_or_never()
 use tokio::select; // The core macro for concurrent async operations
+use zbus::zvariant::OwnedObjectPath;
 
-// --- X11 Keysym Definitions ---
-// Provides constants for common key symbols used by Fcitx.
-mod keysyms {
-    pub const XK_BackSpace: u32 = 0xff08;
-    pub const XK_Tab: u32 = 0xff09;
-    pub const XK_Return: u32 = 0xff0d; // Enter key
-    pub const XK_Escape: u32 = 0xff1b;
-    pub const XK_Left: u32 = 0xff51;
-    pub const XK_Up: u32 = 0xff52;
-    pub const XK_Right: u32 = 0xff53;
-    pub const XK_Down: u32 = 0xff54;
-    pub const XK_Delete: u32 = 0xffff;
-    pub const XK_space: u32 = 0x0020;
-    pub const XK_exclam: u32 = 0x0021; // !
-    pub const XK_quotedbl: u32 = 0x0022; // "
-    pub const XK_numbersign: u32 = 0x0023; // #
-    pub const XK_dollar: u32 = 0x0024; // $
-    pub const XK_percent: u32 = 0x0025; // %
-    pub const XK_ampersand: u32 = 0x0026; // &
-    pub const XK_apostrophe: u32 = 0x0027; // '
-    pub const XK_parenleft: u32 = 0x0028; // (
-    pub const XK_parenright: u32 = 0x0029; // )
-    pub const XK_asterisk: u32 = 0x002a; // *
-    pub const XK_plus: u32 = 0x002b; // +
-    pub const XK_comma: u32 = 0x002c; // ,
-    pub const XK_minus: u32 = 0x002d; // -
-    pub const XK_period: u32 = 0x002e; // .
-    pub const XK_slash: u32 = 0x002f; // /
-    pub const XK_0: u32 = 0x0030;
-    pub const XK_1: u32 = 0x0031;
-    pub const XK_2: u32 = 0x0032;
-    pub const XK_3: u32 = 0x0033;
-    pub const XK_4: u32 = 0x0034;
-    pub const XK_5: u32 = 0x0035;
-    pub const XK_6: u32 = 0x0036;
-    pub const XK_7: u32 = 0x0037;
-    pub const XK_8: u32 = 0x0038;
-    pub const XK_9: u32 = 0x0039;
-    pub const XK_colon: u32 = 0x003a; // :
-    pub const XK_semicolon: u32 = 0x003b; // ;
-    pub const XK_less: u32 = 0x003c; // <
-    pub const XK_equal: u32 = 0x003d; // =
-    pub const XK_greater: u32 = 0x003e; // >
-    pub const XK_question: u32 = 0x003f; // ?
-    pub const XK_at: u32 = 0x0040; // @
-    pub const XK_A: u32 = 0x0041;
-    pub const XK_B: u32 = 0x0042;
-    pub const XK_C: u32 = 0x0043;
-    pub const XK_D: u32 = 0x0044;
-    pub const XK_E: u32 = 0x0045;
-    pub const XK_F: u32 = 0x0046;
-    pub const XK_G: u32 = 0x0047;
-    pub const XK_H: u32 = 0x0048;
-    pub const XK_I: u32 = 0x0049;
-    pub const XK_J: u32 = 0x004a;
-    pub const XK_K: u32 = 0x004b;
-    pub const XK_L: u32 = 0x004c;
-    pub const XK_M: u32 = 0x004d;
-    pub const XK_N: u32 = 0x004e;
-    pub const XK_O: u32 = 0x004f;
-    pub const XK_P: u32 = 0x0050;
-    pub const XK_Q: u32 = 0x0051;
-    pub const XK_R: u32 = 0x0052;
-    pub const XK_S: u32 = 0x0053;
-    pub const XK_T: u32 = 0x0054;
-    pub const XK_U: u32 = 0x0055;
-    pub const XK_V: u32 = 0x0056;
-    pub const XK_W: u32 = 0x0057;
-    pub const XK_X: u32 = 0x0058;
-    pub const XK_Y: u32 = 0x0059;
-    pub const XK_Z: u32 = 0x005a;
-    pub const XK_bracketleft: u32 = 0x005b; // [
-    pub const XK_backslash: u32 = 0x005c; // \ -> Note: Often requires Shift on some layouts
-    pub const XK_bracketright: u32 = 0x005d; // ]
-    pub const XK_asciicircum: u32 = 0x005e; // ^
-    pub const XK_underscore: u32 = 0x005f; // _
-    pub const XK_grave: u32 = 0x0060; // `
-    pub const XK_a: u32 = 0x0061;
-    pub const XK_b: u32 = 0x0062;
-    pub const XK_c: u32 = 0x0063;
-    pub const XK_d: u32 = 0x0064;
-    pub const XK_e: u32 = 0x0065;
-    pub const XK_f: u32 = 0x0066;
-    pub const XK_g: u32 = 0x0067;
-    pub const XK_h: u32 = 0x0068;
-    pub const XK_i: u32 = 0x0069;
-    pub const XK_j: u32 = 0x006a;
-    pub const XK_k: u32 = 0x006b;
-    pub const XK_l: u32 = 0x006c;
-    pub const XK_m: u32 = 0x006d;
-    pub const XK_n: u32 = 0x006e;
-    pub const XK_o: u32 = 0x006f;
-    pub const XK_p: u32 = 0x0070;
-    pub const XK_q: u32 = 0x0071;
-    pub const XK_r: u32 = 0x0072;
-    pub const XK_s: u32 = 0x0073;
-    pub const XK_t: u32 = 0x0074;
-    pub const XK_u: u32 = 0x0075;
-    pub const XK_v: u32 = 0x0076;
-    pub const XK_w: u32 = 0x0077;
-    pub const XK_x: u32 = 0x0078;
-    pub const XK_y: u32 = 0x0079;
-    pub const XK_z: u32 = 0x007a;
-    pub const XK_braceleft: u32 = 0x007b; // {
-    pub const XK_bar: u32 = 0x007c; // |
-    pub const XK_braceright: u32 = 0x007d; // }
-    pub const XK_asciitilde: u32 = 0x007e; // ~
+/// Whether an unmodified Tab should be forwarded to Fcitx rather than written literally.
+fn tab_goes_to_fcitx(behavior: TabBehavior, app_state: &AppState) -> bool {
+    match behavior {
+        TabBehavior::Forward => true,
+        TabBehavior::WhenCandidates => app_state.candidates_may_be_open(),
+        TabBehavior::Literal => false,
+    }
 }
 
-// --- X11 Modifier Masks ---
-// Provides constants for modifier key states.
-mod masks {
-    pub const ShiftMask: u32 = 1 << 0;
-    pub const LockMask: u32 = 1 << 1; // Caps Lock
-    pub const ControlMask: u32 = 1 << 2;
-    pub const Mod1Mask: u32 = 1 << 3; // Alt key (usually)
-    pub const Mod2Mask: u32 = 1 << 4; // Num Lock key (usually)
-    pub const Mod3Mask: u32 = 1 << 5; // Often unused
-    pub const Mod4Mask: u32 = 1 << 6; // Super/Win key (usually)
-    pub const Mod5Mask: u32 = 1 << 7; // Often ISO_Level3_Shift (AltGr)
+/// What an unmodified Esc does when it isn't simply forwarded (see `esc_action`).
+#[derive(Debug, PartialEq, Eq)]
+enum EscAction {
+    /// Cancel the active conversion: reset the context and clear the preedit.
+    CancelPreedit,
+    /// No conversion to cancel: pass Esc through to the application (e.g. vim).
+    PassThrough,
 }
 
-/// Maps a crossterm KeyEvent to Fcitx compatible (keysym, keycode, state).
-/// Returns None if the key event should not be forwarded to Fcitx.
-fn map_key_event_to_fcitx(key_event: &KeyEvent) -> Option<(u32, u32, u32)> {
-    let mut state = 0u32;
-    // Map crossterm modifiers to X11 state mask
-    if key_event.modifiers.contains(KeyModifiers::SHIFT) {
-        state |= masks::ShiftMask;
+/// Preedit-aware Esc handling. Returns `None` when the key goes to Fcitx like any other:
+/// with --forward-esc, or for Esc with modifiers.
+fn esc_action(key_event: &KeyEvent, app_state: &AppState, config: &Config) -> Option<EscAction> {
+    if key_event.code != KeyCode::Esc || !key_event.modifiers.is_empty() || config.forward_esc {
+        return None;
     }
-    if key_event.modifiers.contains(KeyModifiers::CONTROL) {
-        state |= masks::ControlMask;
+    Some(if app_state.preedit_string.is_empty() { EscAction::PassThrough } else { EscAction::CancelPreedit })
+}
+
+/// Returns the update arriving right behind the previous one, to be rendered in the same
+/// frame: within `debounce`, or with a zero debounce only one that is already queued.
+/// `None` once the updates pause (or the stream ends, which the next regular read reports).
+async fn next_queued_update<S>(updates: &mut S, debounce: std::time::Duration) -> Option<Result<FcitxUpdate, FepError>>
+where
+    S: Stream<Item = Result<FcitxUpdate, FepError>> + Unpin,
+{
+    if debounce.is_zero() {
+        updates.next().now_or_never().flatten()
+    } else {
+        tokio::time::timeout(debounce, updates.next()).await.ok().flatten()
     }
-    if key_event.modifiers.contains(KeyModifiers::ALT) {
-        state |= masks::Mod1Mask; // Assuming Alt is Mod1
+}
+
+/// The text a key inserts after the literal key (--literal-key), bypassing Fcitx: its
+/// character, or the fallback bytes of a symbolic key. `None` for Esc (which disarms the
+/// literal key) and for keys with Ctrl/Alt.
+fn literal_text(key_event: &KeyEvent, config: &Config) -> Option<String> {
+    let plain = !key_event.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+    match key_event.code {
+        KeyCode::Esc => None,
+        KeyCode::Char(c) if plain => Some(c.to_string()),
+        _ => map_key_event_to_fcitx(key_event)
+            .and_then(|(keysym, _, _)| config.fallback_bytes(keysym))
+            .filter(|_| plain)
+            .map(str::to_string),
+    }
+}
+
+/// Preedit-aware Delete: whether an unmodified Delete bypasses Fcitx. The engine deletes
+/// forward in its composition, but with none there is nothing for it to do, so the key goes
+/// straight to the terminal. Never with --forward-delete.
+fn delete_goes_to_terminal(key_event: &KeyEvent, app_state: &AppState, config: &Config) -> bool {
+    key_event.code == KeyCode::Delete
+        && key_event.modifiers.is_empty()
+        && !config.forward_delete
+        && app_state.preedit_string.is_empty()
+        && app_state.optimistic_echo.is_empty()
+}
+
+/// Printable ASCII that is written straight through, skipping the D-Bus round-trip (fast path),
+/// because Fcitx would only decline it or commit it unchanged: while the input method is
+/// inactive (a keyboard layout) and nothing is being composed, or when there is no input
+/// method at all (`no_input_method`, with --on-no-input-method passthrough). Keys with Ctrl
+/// or Alt always go to Fcitx, which may have hotkeys on them.
+fn fast_path_char(key_event: &KeyEvent, app_state: &AppState, no_input_method: bool) -> Option<char> {
+    let KeyCode::Char(c @ ' '..='~') = key_event.code else { return None };
+    if key_event.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+        return None;
+    }
+    let im_inactive = app_state.im.as_ref().is_some_and(|im| im.active == Some(false))
+        && app_state.preedit_string.is_empty()
+        && app_state.optimistic_echo.is_empty();
+    (im_inactive || no_input_method).then_some(c)
+}
+
+/// Whether a reconnect got the same input context path as the lost connection.
+fn is_reused_context_path(previous: Option<&OwnedObjectPath>, current: Option<&OwnedObjectPath>) -> bool {
+    current.is_some() && current == previous
+}
+
+/// Remembers the input method used with `program`. Failing to save is logged only.
+fn remember_im(memory: &mut ImMemory, program: &str, im: &str) {
+    if let Err(e) = memory.set(program, im) {
+        eprintln!("Failed to save the input method for {}: {}", program, e);
+    }
+}
+
+/// Reports the input method configuration after it was checked: a missing input method
+/// otherwise just looks like the FEP ignoring every key.
+fn report_input_methods(has_input_methods: Option<bool>, config: &Config) {
+    match has_input_methods {
+        Some(false) => eprintln!(
+            "Fcitx has no input methods configured (only keyboard layouts), so it won't convert \
+             anything. Add one with fcitx5-configtool. {}",
+            if config.no_im_passthrough {
+                "Until then typed characters are passed through."
+            } else {
+                "Use --on-no-input-method passthrough to type without one."
+            }
+        ),
+        Some(true) => println!("Fcitx has input methods configured."),
+        None => {}
+    }
+}
+
+/// Records a commit in the history. Failing to write the history file is logged but
+/// doesn't interrupt typing.
+fn record_commit(history: &mut History, text: &str) {
+    if let Err(e) = history.record(text) {
+        eprintln!("Failed to record commit in history: {}", e);
+    }
+}
+
+/// Returns true for the Ctrl+C key event, which is how Ctrl+C arrives in raw mode.
+fn is_ctrl_c(key_event: &KeyEvent) -> bool {
+    key_event.code == KeyCode::Char('c') && key_event.modifiers.contains(KeyModifiers::CONTROL)
+}
+
+/// Sends the on-screen cursor position to Fcitx (as an approximate pixel rectangle), so the
+/// candidate popup follows the wrapped preedit cursor. `FcitxClient::set_cursor_rect` skips a
+/// rectangle it already sent. Failing to query the position is not fatal; the popup just
+/// keeps its old place.
+///
+/// The event loop calls this once after focus-in and then only while `AppState::wants_cursor_rect`,
+/// i.e. while there is a preedit and Fcitx reports a visible candidate/aux UI (or hasn't reported
+/// UI state at all). A burst of plain typing that Fcitx commits directly therefore makes no
+/// SetCursorRect call instead of one per key, and composing makes one per cursor move
+/// (`fcitx::tests::cursor_rect_is_sent_once_per_cursor_move_while_composing` counts them).
+async fn update_cursor_rect(terminal: &Terminal, fcitx_client: &mut FcitxClient<'_>, config: &Config) -> Result<(), FepError> {
+    let cell = match terminal.cursor_cell() {
+        Ok(cell) => cell,
+        Err(e) => {
+            eprintln!("Could not query cursor position: {}", e);
+            return Ok(());
+        }
+    };
+    let (cell_w, cell_h) = config.cell_size;
+    let (col, row) = (cell.0 as u32, cell.1 as u32);
+    fcitx_client
+        .set_cursor_rect((col * cell_w) as i32, (row * cell_h) as i32, 0, cell_h as i32)
+        .await
+}
+
+/// Maps a key event to the (keysym, keycode, state) forwarded to Fcitx, including the
+/// function/lock/media/modifier keys with `--unmapped-keys forward`.
+fn map_key(key_event: &KeyEvent, keycodes: &KeycodeResolver, config: &Config) -> Option<(u32, u32, u32)> {
+    map_key_event_to_fcitx(key_event)
+        .or_else(|| map_unmapped_key(key_event).filter(|_| config.forward_unmapped_keys))
+        .map(|key| keycodes.resolve(key))
+}
+
+/// One-shot mode: forwards a single key event to Fcitx, prints the resulting
+/// commit/preedit updates, and returns. Used by `--send-key` for scripting.
+pub async fn run_send_key(
+    fcitx_client: &mut FcitxClient<'_>,
+    keysym: u32,
+    state: u32,
+) -> Result<(), FepError> {
+    // Subscribe before forwarding so that no update is missed.
+    let mut fcitx_updates = fcitx_client.receive_updates().await?;
+
+    let handled = fcitx_client.forward_key_event(keysym, 0, state, false).await?;
+    println!("handled: {}", handled);
+
+    // Collect the updates Fcitx sends in response, until it goes quiet.
+    loop {
+        match tokio::time::timeout(SEND_KEY_QUIET_PERIOD, fcitx_updates.next()).await {
+            Ok(Some(Ok(FcitxUpdate::CommitString(text)))) => println!("commit: {}", text),
+            Ok(Some(Ok(FcitxUpdate::CommitStringWithCursor { text, cursor }))) => {
+                println!("commit: {} (cursor {})", text, cursor)
+            }
+            Ok(Some(Ok(FcitxUpdate::UpdatePreedit { text, cursor_pos }))) => {
+                println!("preedit: {} (cursor {})", text, cursor_pos)
+            }
+            Ok(Some(Ok(FcitxUpdate::ClientSideUi { candidates, .. }))) => println!("candidates: {}", candidates.join(" ")),
+            Ok(Some(Ok(FcitxUpdate::CurrentIm(name)))) => println!("input method: {}", name),
+            Ok(Some(Ok(FcitxUpdate::InputMethodsChanged))) => println!("input method configuration changed"),
+            Ok(Some(Err(e))) => return Err(e),
+            Ok(None) | Err(_) => break, // Stream ended or no more updates
+        }
     }
-    // Note: Handling SUPER (Mod4Mask), AltGr (Mod5Mask), CapsLock, NumLock
-    // would require more complex state tracking or platform APIs.
-
-    // Map crossterm KeyCode to X11 Keysym
-    let keysym = match key_event.code {
-        // --- Character Keys ---
-        // crossterm provides the character considering Shift state,
-        // so we map directly to the corresponding keysym constant.
-        KeyCode::Char(c) => match c {
-            ' ' => keysyms::XK_space,
-            '!' => keysyms::XK_exclam, '"' => keysyms::XK_quotedbl, '#' => keysyms::XK_numbersign,
-            '$' => keysyms::XK_dollar, '%' => keysyms::XK_percent, '&' => keysyms::XK_ampersand,
-            '\'' => keysyms::XK_apostrophe, '(' => keysyms::XK_parenleft, ')' => keysyms::XK_parenright,
-            '*' => keysyms::XK_asterisk, '+' => keysyms::XK_plus, ',' => keysyms::XK_comma,
-            '-' => keysyms::XK_minus, '.' => keysyms::XK_period, '/' => keysyms::XK_slash,
-            '0' => keysyms::XK_0, '1' => keysyms::XK_1, '2' => keysyms::XK_2, '3' => keysyms::XK_3,
-            '4' => keysyms::XK_4, '5' => keysyms::XK_5, '6' => keysyms::XK_6, '7' => keysyms::XK_7,
-            '8' => keysyms::XK_8, '9' => keysyms::XK_9,
-            ':' => keysyms::XK_colon, ';' => keysyms::XK_semicolon, '<' => keysyms::XK_less,
-            '=' => keysyms::XK_equal, '>' => keysyms::XK_greater, '?' => keysyms::XK_question,
-            '@' => keysyms::XK_at,
-            'A' => keysyms::XK_A, 'B' => keysyms::XK_B, 'C' => keysyms::XK_C, 'D' => keysyms::XK_D,
-            'E' => keysyms::XK_E, 'F' => keysyms::XK_F, 'G' => keysyms::XK_G, 'H' => keysyms::XK_H,
-            'I' => keysyms::XK_I, 'J' => keysyms::XK_J, 'K' => keysyms::XK_K, 'L' => keysyms::XK_L,
-            'M' => keysyms::XK_M, 'N' => keysyms::XK_N, 'O' => keysyms::XK_O, 'P' => keysyms::XK_P,
-            'Q' => keysyms::XK_Q, 'R' => keysyms::XK_R, 'S' => keysyms::XK_S, 'T' => keysyms::XK_T,
-            'U' => keysyms::XK_U, 'V' => keysyms::XK_V, 'W' => keysyms::XK_W, 'X' => keysyms::XK_X,
-            'Y' => keysyms::XK_Y, 'Z' => keysyms::XK_Z,
-            '[' => keysyms::XK_bracketleft, '\\' => keysyms::XK_backslash, ']' => keysyms::XK_bracketright,
-            '^' => keysyms::XK_asciicircum, '_' => keysyms::XK_underscore, '`' => keysyms::XK_grave,
-            'a' => keysyms::XK_a, 'b' => keysyms::XK_b, 'c' => keysyms::XK_c, 'd' => keysyms::XK_d,
-            'e' => keysyms::XK_e, 'f' => keysyms::XK_f, 'g' => keysyms::XK_g, 'h' => keysyms::XK_h,
-            'i' => keysyms::XK_i, 'j' => keysyms::XK_j, 'k' => keysyms::XK_k, 'l' => keysyms::XK_l,
-            'm' => keysyms::XK_m, 'n' => keysyms::XK_n, 'o' => keysyms::XK_o, 'p' => keysyms::XK_p,
-            'q' => keysyms::XK_q, 'r' => keysyms::XK_r, 's' => keysyms::XK_s, 't' => keysyms::XK_t,
-            'u' => keysyms::XK_u, 'v' => keysyms::XK_v, 'w' => keysyms::XK_w, 'x' => keysyms::XK_x,
-            'y' => keysyms::XK_y, 'z' => keysyms::XK_z,
-            '{' => keysyms::XK_braceleft, '|' => keysyms::XK_bar, '}' => keysyms::XK_braceright,
-            '~' => keysyms::XK_asciitilde,
-            // For other characters (e.g., non-ASCII), pass the Unicode codepoint directly.
-            // Fcitx might interpret this based on layout or internal state.
-            _ => c as u32,
-        },
-
-        // --- Special Keys ---
-        KeyCode::Backspace => keysyms::XK_BackSpace,
-        KeyCode::Enter => keysyms::XK_Return,
-        KeyCode::Left => keysyms::XK_Left,
-        KeyCode::Right => keysyms::XK_Right,
-        KeyCode::Up => keysyms::XK_Up,
-        KeyCode::Down => keysyms::XK_Down,
-        KeyCode::Tab => keysyms::XK_Tab,
-        KeyCode::Delete => keysyms::XK_Delete,
-        KeyCode::Esc => keysyms::XK_Escape,
-        // Add Home, End, PageUp, PageDown, Insert, F1-F12 etc. if needed
-        // KeyCode::Home => keysyms::XK_Home,
-        // KeyCode::End => keysyms::XK_End,
-        // KeyCode::PageUp => keysyms::XK_Page_Up,
-        // KeyCode::PageDown => keysyms::XK_Page_Down,
-        // KeyCode::Insert => keysyms::XK_Insert,
-        // KeyCode::F(n) => keysyms::XK_F1 + (n as u32 - 1),
-
-        // Ignore keys not explicitly handled
-        _ => return None,
+    Ok(())
+}
+
+/// How long `run_send_key` waits for further updates before exiting.
+const SEND_KEY_QUIET_PERIOD: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Connects to Fcitx and runs the event loop, reconnecting (with a throttled backoff)
+/// whenever the connection is lost. The initial connection is not retried, so a missing
+/// Fcitx is reported right away. Once reconnecting is given up, either returns an error
+/// or continues in passthrough mode, depending on `config.reconnect.on_failure`.
+/// The current client is kept in `fcitx_slot`, so that whatever ends the session the caller
+/// can still say goodbye to Fcitx with `shutdown`; it is emptied when the connection is lost.
+pub async fn run_session(
+    terminal: &mut Terminal,
+    app_state: &mut AppState,
+    fcitx_slot: &mut Option<FcitxClient<'static>>,
+    config: &Config,
+) -> Result<(), FepError> {
+    // The history outlives reconnects, so it is loaded once here
+    let mut history = if config.history {
+        Some(History::load(config.history_size)?)
+    } else {
+        None
+    };
+
+    let mut im_memory = if config.remember_im {
+        Some(ImMemory::load()?)
+    } else {
+        None
     };
 
-    // Use 0 as a placeholder keycode. Fcitx generally works well with keysym + state.
-    let keycode = 0;
+    // The keymap doesn't change with the connection, so it is compiled once as well
+    let keycodes = KeycodeResolver::new();
+
+    let mut reconnecting = false;
+    let mut previous_ic_path = None;
+    loop {
+        let client = if reconnecting {
+            match reconnect::connect_with_backoff(&config.reconnect, &config.program).await {
+                Some(client) => client,
+                None => {
+                    return match config.reconnect.on_failure {
+                        FailurePolicy::Exit => Err(FepError::FcitxConnection(
+                            "Could not reconnect to Fcitx; giving up".to_string(),
+                        )),
+                        FailurePolicy::Passthrough => run_passthrough(terminal, app_state, config, false).await.map(|_| ()),
+                    };
+                }
+            }
+        } else {
+            FcitxClient::connect(&config.program).await?
+        };
+        let fcitx_client = fcitx_slot.insert(client);
+        fcitx_client.set_key_timestamps(config.key_timestamps);
+        // Draw the candidate list ourselves (--candidate-window) instead of Fcitx's popup,
+        // unless an explicit capability set (--capability) replaces the default one
+        if let Some(caps) = config.capabilities {
+            if let Err(e) = fcitx_client.set_capabilities(caps).await {
+                eprintln!("Could not set the input context capabilities: {}", e);
+            }
+        } else if config.candidate_rows > 0 {
+            if let Err(e) = fcitx_client.enable_client_side_ui().await {
+                eprintln!("Could not enable the in-terminal candidate window: {}", e);
+            }
+        }
+
+        // Each client subscribes to its own context's signals in run_event_loop, so a reused
+        // path would not lose updates; it does hint at a stale context on the Fcitx side.
+        let ic_path = fcitx_client.ic_path().cloned();
+        if reconnecting && is_reused_context_path(previous_ic_path.as_ref(), ic_path.as_ref()) {
+            eprintln!(
+                "Warning: Fcitx returned the same input context path ({}) as before reconnecting.",
+                ic_path.as_ref().map_or(String::new(), |path| path.to_string())
+            );
+        }
+        previous_ic_path = ic_path;
+
+        // Restore the input method last used with this program (--remember-im)
+        if let Some(im) = im_memory.as_ref().and_then(|memory| memory.get(&config.program)) {
+            if let Err(e) = fcitx_client.set_current_im(im).await {
+                eprintln!("Could not restore input method {}: {}", im, e);
+            }
+        }
+
+        // Likewise the page size; full candidate pages correct it for engines that override it.
+        if let Some(page_size) = fcitx_client.page_size() {
+            app_state.known_page_size = Some(page_size);
+        }
+
+        // Start from the input method Fcitx reported at connect; CurrentIM signals update it.
+        app_state.im = fcitx_client.initial_im().cloned();
+        if let Some(im) = &app_state.im {
+            println!("Input method: {} ({})", im.name, match im.active {
+                Some(true) => "active",
+                Some(false) => "inactive",
+                None => "state unknown",
+            });
+        }
+
+        if fcitx_client.has_input_methods() == Some(false) {
+            report_input_methods(Some(false), config);
+        }
 
-    Some((keysym, keycode, state))
+        let result = run_event_loop(terminal, fcitx_client, app_state, &mut history, &mut im_memory, &keycodes, config).await;
+        match result {
+            Err(FepError::BusLost(reason)) => {
+                // Without a session bus (logout, dbus restart) no Fcitx call can succeed, so keep
+                // the terminal usable in passthrough mode until the bus accepts connections again.
+                eprintln!(
+                    "Lost the D-Bus session bus ({}). Typing passes through without an input method \
+                     until it returns.",
+                    reason
+                );
+                // No FocusOut on shutdown: without a bus it could only time out
+                *fcitx_slot = None;
+                app_state.focused = false;
+                app_state.apply_update(FcitxUpdate::UpdatePreedit { text: String::new(), cursor_pos: 0 });
+                terminal.render(app_state)?;
+                if !run_passthrough(terminal, app_state, config, true).await? {
+                    return Ok(()); // Quit while in passthrough mode
+                }
+                eprintln!("The D-Bus session bus is back; reconnecting to Fcitx...");
+                reconnecting = true;
+            }
+            Err(e) if e.is_connection_error() => {
+                eprintln!("Lost connection to Fcitx ({}), reconnecting...", e);
+                // Tear down the old client (its proxies and connection) before creating a new
+                // context; its signal subscriptions already ended with run_event_loop.
+                *fcitx_slot = None;
+                app_state.focused = false;
+                // The preedit belonged to the lost input context.
+                app_state.apply_update(FcitxUpdate::UpdatePreedit { text: String::new(), cursor_pos: 0 });
+                terminal.render(app_state)?;
+                reconnecting = true;
+            }
+            // Any other exit (quit, signal, error) leaves the client for the caller's `shutdown`
+            result => return result,
+        }
+    }
 }
 
+/// Says goodbye to Fcitx when the FEP exits, however the session ended: settles the preedit
+/// (see --on-focus-loss), shows any resulting commit and sends FocusOut. Each D-Bus step is
+/// bounded by `SHUTDOWN_TIMEOUT`, so a wedged bus can't keep the terminal from being restored.
+pub async fn shutdown(
+    terminal: &mut Terminal,
+    fcitx_client: Option<FcitxClient<'_>>,
+    app_state: &mut AppState,
+    config: &Config,
+) {
+    let Some(mut fcitx_client) = fcitx_client else { return };
+    let settled = tokio::time::timeout(SHUTDOWN_TIMEOUT, fcitx_client.settle_preedit(config.focus_loss, app_state)).await;
+    if let Ok(Err(e)) = settled {
+        eprintln!("Failed to settle the preedit on exit: {}", e);
+    }
+    fcitx_client.disconnect_with_timeout(SHUTDOWN_TIMEOUT).await;
+    app_state.focused = false; // Also takes down the placeholder
+    if let Err(e) = terminal.render(app_state) {
+        eprintln!("Failed to render on exit: {}", e);
+    }
+}
+
+/// How long shutdown waits for each D-Bus cleanup step (settling the preedit, FocusOut).
+pub const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Interval between attempts to resubscribe to Fcitx updates after the stream ended
+/// (within `ReconnectConfig::stream_grace`).
+const STREAM_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Runs without Fcitx: typed characters are written through as-is and symbolic keys
+/// use the fallback table. Used after reconnecting has been given up, and while the session
+/// bus is gone (`wait_for_bus`).
+/// With `wait_for_bus`, returns `true` as soon as the session bus accepts connections again;
+/// if it doesn't return within the reconnect timeout, `--on-fcitx-failure` decides whether to
+/// exit with an error or keep passing keys through. Returns `false` when the user quits.
+async fn run_passthrough(
+    terminal: &mut Terminal,
+    app_state: &mut AppState,
+    config: &Config,
+    wait_for_bus: bool,
+) -> Result<bool, FepError> {
+    eprintln!("Continuing in passthrough mode without an input method.");
+    let mut key_stream = terminal.input_stream();
+    let sigint = tokio::signal::ctrl_c();
+    tokio::pin!(sigint);
+    let bus_returned = reconnect::wait_for_session_bus(&config.reconnect);
+    tokio::pin!(bus_returned);
+    let mut waiting_for_bus = wait_for_bus;
+    loop {
+        let key_event = select! {
+            input = key_stream.next() => match input {
+                Some(input) => match input? {
+                    TerminalInput::Key(key_event) => key_event,
+                    TerminalInput::Focus(_) => continue, // There is no input context to focus
+                },
+                None => break,
+            },
+            result = &mut sigint => {
+                result?;
+                break;
+            }
+            returned = &mut bus_returned, if waiting_for_bus => {
+                if returned {
+                    terminal.flush_commits(app_state)?;
+                    return Ok(true);
+                }
+                waiting_for_bus = false;
+                match config.reconnect.on_failure {
+                    FailurePolicy::Exit => {
+                        terminal.flush_commits(app_state)?;
+                        return Err(FepError::BusLost("the session bus did not come back; giving up".to_string()));
+                    }
+                    FailurePolicy::Passthrough => continue,
+                }
+            }
+        };
+        if is_ctrl_c(&key_event) {
+            break;
+        }
+        if key_event.kind == KeyEventKind::Release {
+            continue;
+        }
+        let plain = !key_event.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+        match key_event.code {
+            KeyCode::Char(c) if plain => app_state.commit_string.push(c),
+            _ => {
+                let bytes = map_key_event_to_fcitx(&key_event)
+                    .and_then(|(keysym, _, _)| config.fallback_bytes(keysym))
+                    .filter(|_| plain);
+                match bytes {
+                    Some(bytes) => app_state.commit_string.push_str(bytes),
+                    None => continue,
+                }
+            }
+        }
+        terminal.render(app_state)?;
+    }
+    terminal.flush_commits(app_state)?;
+    Ok(false)
+}
 
 /// Runs the main asynchronous event loop, handling terminal input and Fcitx D-Bus signals.
-pub async fn run_event_loop<'a>(
-    terminal: &'a mut Terminal, // Borrow terminal mutably
-    fcitx_client: &'a mut FcitxClient<'a>, // Borrow client mutably
-    app_state: &'a mut AppState, // Borrow state mutably
+pub async fn run_event_loop(
+    terminal: &mut Terminal, // Borrow terminal mutably
+    fcitx_client: &mut FcitxClient<'_>, // Borrow client mutably
+    app_state: &mut AppState, // Borrow state mutably
+    history: &mut Option<History>, // Commit history, if enabled
+    im_memory: &mut Option<ImMemory>, // Per-program input method memory, if enabled
+    keycodes: &KeycodeResolver, // Keycodes for forwarded keys (xkb, if available)
+    config: &Config,
 ) -> Result<(), FepError> {
     println!("Entering async event loop...");
 
+    // Latency instrumentation, only allocated when --metrics is given
+    let mut metrics = config.metrics.then(LatencyMetrics::new);
+    // Keybinding debug overlay, while it is shown
+    let mut overlay: Option<KeyDebugOverlay> = None;
+    // Whether the literal key was pressed (--literal-key); its hint doesn't survive a reconnect
+    let mut literal_armed = false;
+    app_state.status_hint = None;
+    app_state.focused = fcitx_client.is_focused();
+
     // Get the asynchronous streams for terminal events and Fcitx updates
-    let mut key_stream = terminal.key_event_stream();
+    let mut key_stream = terminal.input_stream();
     let mut fcitx_updates = fcitx_client.receive_updates().await?; // Setup signal listeners
+    // End of the grace period for resubscribing, while the update stream keeps ending
+    let mut stream_grace_end = None;
+
+    // SIGINT backstop. In raw mode Ctrl+C arrives as a key event (handled in Branch 1),
+    // so this only fires for signals sent from outside (e.g. `kill -INT`). Both paths
+    // break out of the loop, so the same shutdown sequence runs either way.
+    let sigint = tokio::signal::ctrl_c();
+    tokio::pin!(sigint);
 
     // Perform an initial render of the empty state
     terminal.render(app_state)?;
+    // Place Fcitx's UI once right after focus-in, whether or not a popup is visible yet
+    update_cursor_rect(terminal, fcitx_client, config).await?;
 
     // Main loop: concurrently wait for events from either stream
     loop {
+        // Terminal focus changes (--follow-terminal-focus) reach Fcitx once they settle
+        let focus_deadline = fcitx_client.focus_request_deadline();
+        let focus_settled = tokio::time::sleep_until(
+            focus_deadline.map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std),
+        );
         select! {
             // Biasing can prioritize one stream slightly if needed, but usually not necessary.
             // biased;
@@ -243,26 +502,249 @@ pub async fn run_event_loop<'a>(
             // Branch 1: Handle Terminal Input Events
             maybe_key_event = key_stream.next() => {
                 match maybe_key_event {
-                    Some(Ok(key_event)) => {
-                        // Check for Ctrl+C specifically (if not handled by tokio::signal)
-                        // This provides an in-loop exit mechanism.
-                        if key_event.code == KeyCode::Char('c') && key_event.modifiers.contains(KeyModifiers::CONTROL) {
+                    Some(Ok(TerminalInput::Focus(focused))) => {
+                        println!("Terminal focus {}.", if focused { "gained" } else { "lost" });
+                        fcitx_client.request_focus(focused);
+                    }
+                    Some(Ok(TerminalInput::Key(key_event))) => {
+                        // Ctrl+C is delivered as a key event in raw mode; this is the primary exit path.
+                        if is_ctrl_c(&key_event) {
                              println!("Ctrl+C detected in terminal stream. Exiting loop.");
                              break; // Exit the event loop
                         }
+                        // Settle a pending focus change first, so the key meets the final focus state
+                        if fcitx_client.focus_request_deadline().is_some() {
+                            fcitx_client.apply_focus_request(config.focus_loss, app_state).await?;
+                            app_state.focused = fcitx_client.is_focused();
+                        }
 
                         println!("Terminal Event: {:?}", key_event); // Log received event
+                        if let Some(log) = terminal.event_log() {
+                            log.key(&key_event);
+                        }
+
+                        // Releases only arrive with the kitty keyboard protocol (see `Terminal::new`).
+                        // They go straight to Fcitx, which uses them for modifier-only shortcuts;
+                        // none of the local key handling below reacts to them.
+                        if key_event.kind == KeyEventKind::Release {
+                            if overlay.is_none() {
+                                if let Some((keysym, keycode, state)) = map_key(&key_event, keycodes, config) {
+                                    let handled = fcitx_client.forward_key_event(keysym, keycode, state, true).await?;
+                                    if let Some(log) = terminal.event_log() {
+                                        log.forwarded(keysym, state, handled);
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+
+                        // Keybinding debug overlay: while shown, keys are only recorded and displayed,
+                        // never forwarded, so toggling it off resumes with the composition untouched.
+                        if config.debug_overlay_key.is_some_and(|hotkey| hotkey.matches(&key_event)) {
+                            overlay = match overlay {
+                                Some(_) => {
+                                    terminal.render(app_state)?;
+                                    None
+                                }
+                                None => {
+                                    let overlay = KeyDebugOverlay::new();
+                                    terminal.render_overlay(&overlay.lines())?;
+                                    Some(overlay)
+                                }
+                            };
+                            continue;
+                        }
+                        if let Some(overlay) = overlay.as_mut() {
+                            overlay.record(&key_event, map_key(&key_event, keycodes, config));
+                            terminal.render_overlay(&overlay.lines())?;
+                            continue;
+                        }
+
+                        // Literal next key: after the hotkey, the next key bypasses Fcitx and is committed
+                        // as its character (symbolic keys as their fallback bytes). Esc disarms it.
+                        if literal_armed {
+                            literal_armed = false;
+                            app_state.status_hint = None;
+                            if let Some(text) = literal_text(&key_event, config) {
+                                app_state.push_commit(&text);
+                            }
+                            terminal.render(app_state)?;
+                            continue;
+                        }
+                        if config.literal_key.is_some_and(|hotkey| hotkey.matches(&key_event)) {
+                            literal_armed = true;
+                            app_state.status_hint = Some(" [literal]".to_string());
+                            terminal.render(app_state)?;
+                            continue;
+                        }
+
+                        // History recall: the hotkey cycles through recent commits, showing the
+                        // selected one in place of the preedit. Enter commits it, Esc dismisses it,
+                        // and any other key dismisses it and is then processed normally.
+                        if let Some(history) = history.as_mut() {
+                            if config.history_key.matches(&key_event) {
+                                if let Some(entry) = history.recall_next().map(str::to_string) {
+                                    // Let the engine see the recalled text as surrounding text
+                                    fcitx_client.set_surrounding_text(&entry, entry.chars().count() as u32).await?;
+                                    app_state.recall = Some(entry);
+                                    terminal.render(app_state)?;
+                                }
+                                continue;
+                            }
+                            if let Some(entry) = app_state.recall.take() {
+                                history.reset_recall();
+                                match key_event.code {
+                                    KeyCode::Enter => {
+                                        fcitx_client.commit_text(&entry, app_state).await?;
+                                        record_commit(history, &entry);
+                                        terminal.render(app_state)?;
+                                        continue;
+                                    }
+                                    KeyCode::Esc => {
+                                        terminal.render(app_state)?;
+                                        continue;
+                                    }
+                                    _ => terminal.render(app_state)?,
+                                }
+                            }
+                        }
+
+                        // Intercept the "commit current preedit" hotkey before normal forwarding
+                        if config.commit_preedit_key.is_some_and(|hotkey| hotkey.matches(&key_event)) {
+                            if !app_state.preedit_string.is_empty() {
+                                fcitx_client.commit_preedit().await?;
+                                app_state.commit_preedit();
+                                terminal.render(app_state)?;
+                            }
+                            continue;
+                        }
+
+                        // Preedit-aware Esc: cancel an active conversion, otherwise pass Esc
+                        // through (e.g. for vim) instead of letting the engine swallow it.
+                        if let Some(action) = esc_action(&key_event, app_state, config) {
+                            match action {
+                                EscAction::CancelPreedit => {
+                                    fcitx_client.reset().await?;
+                                    app_state.apply_update(FcitxUpdate::UpdatePreedit { text: String::new(), cursor_pos: 0 });
+                                }
+                                EscAction::PassThrough => {
+                                    if let Some(bytes) = config.fallback_bytes(keysyms::XK_Escape) {
+                                        app_state.commit_string.push_str(bytes);
+                                    }
+                                }
+                            }
+                            terminal.render(app_state)?;
+                            continue;
+                        }
+
+                        // Tab routing (--tab): write a literal tab instead of forwarding it when configured
+                        if key_event.code == KeyCode::Tab && key_event.modifiers.is_empty() && !tab_goes_to_fcitx(config.tab, app_state) {
+                            app_state.push_commit("\t");
+                            terminal.render(app_state)?;
+                            continue;
+                        }
+
+                        // Line-edit mode: with no composition in progress, editing keys act on the
+                        // local line, whose text is reported to Fcitx as the surrounding text.
+                        if app_state.preedit_string.is_empty() && app_state.optimistic_echo.is_empty() {
+                            if let Some(line) = app_state.line.as_mut() {
+                                match line.handle_key(&key_event) {
+                                    LineEdit::Edited => {
+                                        let (text, cursor) = (line.text().to_string(), line.cursor() as u32);
+                                        fcitx_client.set_surrounding_text(&text, cursor).await?;
+                                        terminal.render(app_state)?;
+                                        continue;
+                                    }
+                                    LineEdit::Submit(text) => {
+                                        terminal.submit_line(&text)?;
+                                        fcitx_client.set_surrounding_text("", 0).await?;
+                                        terminal.render(app_state)?;
+                                        continue;
+                                    }
+                                    LineEdit::NotHandled => {}
+                                }
+                            }
+                        }
+
+                        if delete_goes_to_terminal(&key_event, app_state, config) {
+                            if let Some(bytes) = config.fallback_bytes(keysyms::XK_Delete) {
+                                app_state.commit_string.push_str(bytes);
+                                terminal.render(app_state)?;
+                            }
+                            continue;
+                        }
+
+                        // Candidate paging: use InputContext1's PrevPage/NextPage when this Fcitx has
+                        // them; otherwise the Page keysym is forwarded below for the engine to handle.
+                        if !app_state.preedit_string.is_empty() && key_event.modifiers.is_empty() && fcitx_client.supports_paging() {
+                            match key_event.code {
+                                KeyCode::PageUp => {
+                                    fcitx_client.prev_page().await?;
+                                    continue;
+                                }
+                                KeyCode::PageDown => {
+                                    fcitx_client.next_page().await?;
+                                    continue;
+                                }
+                                _ => {}
+                            }
+                        }
+
+                        let no_input_method = config.no_im_passthrough && fcitx_client.has_input_methods() == Some(false);
+                        if let Some(c) = fast_path_char(&key_event, app_state, no_input_method) {
+                            app_state.push_commit(c.encode_utf8(&mut [0; 4]));
+                            terminal.render(app_state)?;
+                            continue;
+                        }
 
                         // Map the crossterm event to Fcitx parameters
-                        if let Some((keysym, keycode, state)) = map_key_event_to_fcitx(&key_event) {
+                        if let Some((keysym, keycode, state)) = map_key(&key_event, keycodes, config) {
+                            if let Some(metrics) = metrics.as_mut() {
+                                metrics.key_forwarded();
+                            }
+                            // Optimistic echo: show printable keys right away, before Fcitx responds
+                            let echoed = match key_event.code {
+                                KeyCode::Char(c) if config.optimistic_echo && state & (masks::ControlMask | masks::Mod1Mask) == 0 => {
+                                    app_state.echo_optimistically(c);
+                                    terminal.render(app_state)?;
+                                    true
+                                }
+                                _ => false,
+                            };
                             // Forward the mapped event to Fcitx asynchronously
+                            if config.verbose && !fcitx_client.is_focused() {
+                                eprintln!("[debug] Key not forwarded: the input context is not focused.");
+                            }
                             match fcitx_client.forward_key_event(keysym, keycode, state, false).await { // Assuming key press (is_release = false)
                                 Ok(handled) => {
+                                    if let Some(log) = terminal.event_log() {
+                                        log.forwarded(keysym, state, handled);
+                                    }
                                     if !handled {
+                                        if let Some(metrics) = metrics.as_mut() {
+                                            metrics.key_unhandled();
+                                        }
+                                        // The engine didn't take the key: roll back its echo
+                                        if echoed {
+                                            app_state.rollback_optimistic();
+                                            terminal.render(app_state)?;
+                                        }
                                         // Fcitx did not consume the event.
-                                        // A more advanced FEP might insert the character directly here,
-                                        // but that requires careful state management. We ignore it for now.
                                         println!("Key event not handled by Fcitx.");
+                                        // Emit the fallback bytes for symbolic keys (Tab, Enter, arrows, ...)
+                                        // so they don't vanish when the engine is inactive.
+                                        let plain = state & (masks::ControlMask | masks::Mod1Mask) == 0;
+                                        if let Some(bytes) = config.fallback_bytes(keysym).filter(|_| plain) {
+                                            app_state.commit_string.push_str(bytes);
+                                            terminal.render(app_state)?;
+                                        } else if let KeyCode::Char(c) = key_event.code {
+                                            // Without any input method nothing would ever insert it
+                                            // (--on-no-input-method passthrough)
+                                            if plain && config.no_im_passthrough && fcitx_client.has_input_methods() == Some(false) {
+                                                app_state.push_commit(&c.to_string());
+                                                terminal.render(app_state)?;
+                                            }
+                                        }
                                     }
                                     // We expect Fcitx to potentially send back updates (preedit/commit)
                                     // via the fcitx_updates stream, which will trigger rendering.
@@ -274,8 +756,10 @@ pub async fn run_event_loop<'a>(
                                 }
                             }
                         } else {
-                            // Key was not mapped (e.g., unsupported special key)
-                            println!("Key ignored (no mapping to Fcitx parameters).");
+                            // Key was not mapped (e.g. a function key without --unmapped-keys forward)
+                            if config.verbose {
+                                eprintln!("[debug] Key dropped (no mapping to Fcitx parameters): {:?}", key_event.code);
+                            }
                         }
                     }
                     Some(Err(e)) => {
@@ -296,11 +780,75 @@ pub async fn run_event_loop<'a>(
                  match maybe_fcitx_update {
                     Some(Ok(update)) => {
                         // Received an update (CommitString or UpdatePreedit) from Fcitx
+                        stream_grace_end = None; // The stream works (again)
                         println!("Fcitx Update Received: {:?}", update);
+                        // Commits are not trusted to be free of escape sequences
+                        let update = config.commit_filter.apply_to_update(update);
+                        if let Some(log) = terminal.event_log() {
+                            log.update(&update);
+                        }
+                        if let (Some(memory), FcitxUpdate::CurrentIm(im)) = (im_memory.as_mut(), &update) {
+                            remember_im(memory, &config.program, im);
+                        }
+                        if let Some(metrics) = metrics.as_mut() {
+                            if !matches!(update, FcitxUpdate::ClientSideUi { .. } | FcitxUpdate::CurrentIm(_) | FcitxUpdate::InputMethodsChanged) {
+                                let latency = metrics.update_received();
+                                if let Some(latency) = latency.filter(|_| config.verbose) {
+                                    eprintln!("[debug] key-to-update latency: {:?} ({})", latency, metrics.summary());
+                                }
+                            }
+                        }
+                        if let (Some(history), FcitxUpdate::CommitString(text) | FcitxUpdate::CommitStringWithCursor { text, .. }) = (history.as_mut(), &update) {
+                            record_commit(history, text);
+                        }
+                        let mut committed = matches!(update, FcitxUpdate::CommitString(_) | FcitxUpdate::CommitStringWithCursor { .. });
+                        let mut im_config_changed = matches!(update, FcitxUpdate::InputMethodsChanged);
                         // Apply the update to the application state
                         app_state.apply_update(update);
-                        // Re-render the terminal to reflect the new state
+                        // Collect updates arriving back-to-back (e.g. a commit followed by an
+                        // empty preedit) so they are coalesced and rendered once below.
+                        // Without a debounce, only updates already queued in this turn are taken.
+                        while let Some(queued) = next_queued_update(&mut fcitx_updates, config.render_debounce).await {
+                            let queued = queued?;
+                            println!("Fcitx Update Received (queued): {:?}", queued);
+                            let queued = config.commit_filter.apply_to_update(queued);
+                            if let Some(log) = terminal.event_log() {
+                                log.update(&queued);
+                            }
+                            if let (Some(memory), FcitxUpdate::CurrentIm(im)) = (im_memory.as_mut(), &queued) {
+                                remember_im(memory, &config.program, im);
+                            }
+                            if let (Some(history), FcitxUpdate::CommitString(text) | FcitxUpdate::CommitStringWithCursor { text, .. }) = (history.as_mut(), &queued) {
+                                record_commit(history, text);
+                            }
+                            committed |= matches!(queued, FcitxUpdate::CommitString(_) | FcitxUpdate::CommitStringWithCursor { .. });
+                            im_config_changed |= matches!(queued, FcitxUpdate::InputMethodsChanged);
+                            app_state.apply_update(queued);
+                        }
+                        if im_config_changed {
+                            let before = fcitx_client.has_input_methods();
+                            let now = fcitx_client.recheck_input_methods().await;
+                            if now != before {
+                                report_input_methods(now, config);
+                            }
+                            if let Some(page_size) = fcitx_client.page_size() {
+                                app_state.known_page_size = Some(page_size);
+                            }
+                        }
+                        // In line-edit mode commits land in the line; keep Fcitx's view of it current
+                        if let Some((text, cursor)) = app_state.line_surrounding_text().filter(|_| committed) {
+                            fcitx_client.set_surrounding_text(&text, cursor).await?;
+                        }
+                        // Re-render the terminal to reflect the new state (flushes buffered commits first).
+                        // While the debug overlay is shown, the state is only updated and drawn once it closes.
+                        if overlay.is_some() {
+                            continue;
+                        }
                         terminal.render(app_state)?;
+                        // Keep Fcitx's candidate popup next to where the preedit cursor really is
+                        if app_state.wants_cursor_rect() {
+                            update_cursor_rect(terminal, fcitx_client, config).await?;
+                        }
                     }
                     Some(Err(e)) => {
                         // Error receiving or processing an Fcitx update signal
@@ -308,18 +856,233 @@ pub async fn run_event_loop<'a>(
                         return Err(e); // Propagate the error
                     }
                     None => {
-                        // The Fcitx update stream ended unexpectedly.
-                        // This might indicate the Fcitx connection was lost.
+                        // The Fcitx update stream ended unexpectedly. Signal subscriptions outlive
+                        // Fcitx restarts (the match rules stay on the bus), so this usually means
+                        // the bus connection itself closed. A brief hiccup may only have ended the
+                        // stream, though: resubscribe on the same connection for a grace period
+                        // before going through a full reconnect and a new input context. The grace
+                        // period runs from the first end until an update arrives again, so a stream
+                        // that keeps ending right after resubscribing can't postpone the reconnect.
                         println!("Fcitx update stream ended unexpectedly.");
-                        // Return an error indicating the connection issue.
-                        return Err(FepError::FcitxConnection("Fcitx update stream unexpectedly ended".to_string()));
+                        let deadline = *stream_grace_end.get_or_insert_with(|| tokio::time::Instant::now() + config.reconnect.stream_grace);
+                        match fcitx_client.resubscribe_updates(deadline, STREAM_RETRY_INTERVAL).await {
+                            Ok(updates) => {
+                                fcitx_updates = updates;
+                                println!("Fcitx update stream recovered on the existing connection; no reconnect needed.");
+                            }
+                            Err(e) => {
+                                println!("Fcitx update stream did not recover within {:?}; reconnecting.", config.reconnect.stream_grace);
+                                return Err(e); // UpdatesEnded: run_session reconnects to Fcitx
+                            }
+                        }
                     }
                  }
             }
+
+            // Branch 3: SIGINT from outside the terminal (backstop for the in-stream Ctrl+C)
+            result = &mut sigint => {
+                result?;
+                println!("SIGINT received. Exiting loop.");
+                break; // Exit the event loop
+            }
+
+            // Branch 4: a terminal focus change has stood for the debounce period
+            _ = focus_settled, if focus_deadline.is_some() => {
+                fcitx_client.apply_focus_request(config.focus_loss, app_state).await?;
+                app_state.focused = fcitx_client.is_focused();
+                if overlay.is_none() {
+                    terminal.render(app_state)?;
+                }
+            }
         } // end select!
     } // end loop
 
+    // Write out any commits still buffered so nothing is lost on shutdown.
+    terminal.flush_commits(app_state)?;
+
+    if let Some(metrics) = &metrics {
+        println!("Key-to-update latency: {}", metrics.summary());
+    }
+
     println!("Exiting async event loop normally.");
     Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::test_support::{capture, CLEAR_FROM_ORIGIN, SAVE_ORIGIN};
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn composing(preedit: &str) -> AppState {
+        let mut state = AppState::new();
+        state.apply_update(FcitxUpdate::UpdatePreedit { text: preedit.to_string(), cursor_pos: preedit.len() as i32 });
+        state
+    }
+
+    #[tokio::test]
+    async fn commit_and_empty_preedit_are_rendered_in_one_frame() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut updates = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
+        let (mut terminal, output) = capture();
+        let mut state = composing("かくてい");
+        terminal.render(&mut state).unwrap();
+        output.take();
+
+        // Fcitx finishing a conversion: the commit, then the empty preedit right behind it
+        tx.send(Ok(FcitxUpdate::CommitString("確定".to_string()))).unwrap();
+        tx.send(Ok(FcitxUpdate::UpdatePreedit { text: String::new(), cursor_pos: 0 })).unwrap();
+        let debounce = Config::default().render_debounce;
+        state.apply_update(updates.next().await.unwrap().unwrap());
+        let mut coalesced = 0;
+        while let Some(queued) = next_queued_update(&mut updates, debounce).await {
+            state.apply_update(queued.unwrap());
+            coalesced += 1;
+        }
+        terminal.render(&mut state).unwrap();
+
+        assert_eq!(coalesced, 1);
+        // One frame: the preedit region is cleared (again by the commit flush) and the commit
+        // written, with no preedit drawn after it
+        assert_eq!(output.take(), format!("{0}{0}確定{1}", CLEAR_FROM_ORIGIN, SAVE_ORIGIN));
+        assert!(next_queued_update(&mut updates, debounce).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn zero_debounce_takes_only_updates_already_queued() {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut updates = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
+        tx.send(Ok(FcitxUpdate::CommitString("a".to_string()))).unwrap();
+        let zero = std::time::Duration::ZERO;
+        assert!(matches!(next_queued_update(&mut updates, zero).await, Some(Ok(FcitxUpdate::CommitString(_)))));
+        assert!(next_queued_update(&mut updates, zero).await.is_none());
+    }
+
+    #[test]
+    fn detects_a_context_path_reused_after_reconnecting() {
+        let path = |p: &str| OwnedObjectPath::try_from(p).unwrap();
+        let first = path("/org/freedesktop/portal/inputcontext/1");
+        assert!(is_reused_context_path(Some(&first), Some(&first.clone())));
+        assert!(!is_reused_context_path(Some(&first), Some(&path("/org/freedesktop/portal/inputcontext/2"))));
+        assert!(!is_reused_context_path(None, Some(&first)));
+        assert!(!is_reused_context_path(None, None));
+    }
+
+    #[test]
+    fn tab_routing_per_mode() {
+        let idle = AppState::new();
+        let mut candidates_open = composing("へんかん");
+        candidates_open.ui_visible = Some(true);
+        let mut popup_hidden = composing("へんかん");
+        popup_hidden.ui_visible = Some(false);
+        for state in [&idle, &candidates_open, &popup_hidden] {
+            assert!(tab_goes_to_fcitx(TabBehavior::Forward, state));
+            assert!(!tab_goes_to_fcitx(TabBehavior::Literal, state));
+        }
+        assert!(!tab_goes_to_fcitx(TabBehavior::WhenCandidates, &idle));
+        assert!(tab_goes_to_fcitx(TabBehavior::WhenCandidates, &candidates_open));
+        assert!(!tab_goes_to_fcitx(TabBehavior::WhenCandidates, &popup_hidden));
+        // Without UI reports from Fcitx, a composition is assumed to have its candidates open
+        assert!(tab_goes_to_fcitx(TabBehavior::WhenCandidates, &composing("へんかん")));
+    }
+
+    #[test]
+    fn literal_space_is_committed_as_a_space() {
+        let config = Config::default();
+        let mut state = AppState::new();
+        let text = literal_text(&key(KeyCode::Char(' ')), &config).unwrap();
+        state.push_commit(&text);
+        assert_eq!(state.take_commit(), " ");
+        assert_eq!(literal_text(&key(KeyCode::Enter), &config).as_deref(), Some("\r"));
+    }
+
+    #[test]
+    fn literal_key_is_disarmed_by_esc_and_ignores_ctrl_and_alt() {
+        let config = Config::default();
+        assert_eq!(literal_text(&key(KeyCode::Esc), &config), None);
+        assert_eq!(literal_text(&KeyEvent::new(KeyCode::Char('a'), KeyModifiers::CONTROL), &config), None);
+        assert_eq!(literal_text(&KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT), &config), None);
+        assert_eq!(literal_text(&key(KeyCode::F(1)), &config), None);
+    }
+
+    #[test]
+    fn delete_without_a_preedit_goes_to_the_terminal() {
+        let config = Config::default();
+        let (mut terminal, output) = capture();
+        let mut state = AppState::new();
+        assert!(delete_goes_to_terminal(&key(KeyCode::Delete), &state, &config));
+        state.push_commit(config.fallback_bytes(keysyms::XK_Delete).unwrap());
+        terminal.render(&mut state).unwrap();
+        assert!(output.take().contains("\x1b[3~"));
+    }
+
+    #[test]
+    fn delete_goes_to_fcitx_during_a_preedit_or_with_forward_delete() {
+        let config = Config::default();
+        assert!(!delete_goes_to_terminal(&key(KeyCode::Delete), &composing("へんかん"), &config));
+        let forward = Config { forward_delete: true, ..Config::default() };
+        assert!(!delete_goes_to_terminal(&key(KeyCode::Delete), &AppState::new(), &forward));
+        let ctrl_delete = KeyEvent::new(KeyCode::Delete, KeyModifiers::CONTROL);
+        assert!(!delete_goes_to_terminal(&ctrl_delete, &AppState::new(), &config));
+        assert!(!delete_goes_to_terminal(&key(KeyCode::Backspace), &AppState::new(), &config));
+    }
+
+    fn with_im(mut state: AppState, name: &str) -> AppState {
+        state.apply_update(FcitxUpdate::CurrentIm(name.to_string()));
+        state
+    }
+
+    #[test]
+    fn ascii_skips_fcitx_while_the_input_method_is_inactive() {
+        let idle = with_im(AppState::new(), "keyboard-us");
+        assert_eq!(fast_path_char(&key(KeyCode::Char('a')), &idle, false), Some('a'));
+        assert_eq!(fast_path_char(&key(KeyCode::Char(' ')), &idle, false), Some(' '));
+        let shifted = KeyEvent::new(KeyCode::Char('A'), KeyModifiers::SHIFT);
+        assert_eq!(fast_path_char(&shifted, &idle, false), Some('A'));
+        // Without any input method, whatever the state
+        assert_eq!(fast_path_char(&key(KeyCode::Char('a')), &AppState::new(), true), Some('a'));
+    }
+
+    #[test]
+    fn keys_go_to_fcitx_while_an_input_method_may_use_them() {
+        let a = key(KeyCode::Char('a'));
+        // Active or not yet known input method
+        assert_eq!(fast_path_char(&a, &with_im(AppState::new(), "mozc"), false), None);
+        assert_eq!(fast_path_char(&a, &AppState::new(), false), None);
+        // A composition left over from before the switch
+        assert_eq!(fast_path_char(&a, &with_im(composing("か"), "keyboard-us"), false), None);
+        // Hotkeys, non-ASCII and symbolic keys
+        let idle = with_im(AppState::new(), "keyboard-us");
+        assert_eq!(fast_path_char(&KeyEvent::new(KeyCode::Char(' '), KeyModifiers::CONTROL), &idle, false), None);
+        assert_eq!(fast_path_char(&KeyEvent::new(KeyCode::Char('x'), KeyModifiers::ALT), &idle, true), None);
+        assert_eq!(fast_path_char(&key(KeyCode::Char('é')), &idle, false), None);
+        assert_eq!(fast_path_char(&key(KeyCode::Enter), &idle, false), None);
+    }
+
+    #[test]
+    fn esc_cancels_an_active_preedit() {
+        let state = composing("へんかん");
+        assert_eq!(esc_action(&key(KeyCode::Esc), &state, &Config::default()), Some(EscAction::CancelPreedit));
+    }
+
+    #[test]
+    fn esc_without_a_preedit_passes_through() {
+        let config = Config::default();
+        assert_eq!(esc_action(&key(KeyCode::Esc), &AppState::new(), &config), Some(EscAction::PassThrough));
+        assert_eq!(config.fallback_bytes(keysyms::XK_Escape), Some("\x1b"));
+    }
+
+    #[test]
+    fn esc_is_forwarded_with_forward_esc_or_modifiers() {
+        let config = Config { forward_esc: true, ..Config::default() };
+        assert_eq!(esc_action(&key(KeyCode::Esc), &composing("あ"), &config), None);
+        assert_eq!(esc_action(&key(KeyCode::Esc), &AppState::new(), &config), None);
+        let alt_esc = KeyEvent::new(KeyCode::Esc, KeyModifiers::ALT);
+        assert_eq!(esc_action(&alt_esc, &composing("あ"), &Config::default()), None);
+        assert_eq!(esc_action(&key(KeyCode::Char('a')), &composing("あ"), &Config::default()), None);
+    }
+}
@@ -1,19 +1,38 @@
-use crate::error::FepError;
-use crate::state::FcitxUpdate;
+use crate::error::{ConnectStep, FepError};
+use crate::state::{AppState, FcitxUpdate, ImStatus};
 use std::collections::HashMap;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 // use std::convert::TryFrom; // 不要になる可能性
 // use std::time::Duration; // 不要になる
-use zbus::{Connection, Proxy}; // blocking を削除
+use serde::Deserialize;
+use zbus::Connection;
 use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Type, Value}; // Owned* 型を使うことが多い
-use zbus_macros::{proxy, DeserializeInto, Serialize};
+use zbus_macros::proxy;
+use futures_util::future;
+use futures_util::stream;
 use futures_util::{Stream, StreamExt}; // Stream と StreamExt をインポート
-use tokio_stream::wrappers::SignalStream; // SignalStream をインポート
+
+/// What happens to an active preedit when the FEP sends FocusOut.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FocusLossPolicy {
+    Commit,  // Commit the preedit as-is first
+    Discard, // Reset the input context first, dropping the preedit
+    Engine,  // Leave it to the engine (it may commit, keep or drop it)
+}
 
 // --- 定数と Proxy 定義 (変更なし) ---
 const FCITX5_SERVICE: &str = "org.fcitx.Fcitx5";
-const FCITX5_IFACE_CONTROLLER: &str = "org.fcitx.Fcitx.Controller1";
+const FCITX5_IFACE_INPUT_METHOD: &str = "org.fcitx.Fcitx.InputMethod1";
 const FCITX5_IFACE_IC: &str = "org.fcitx.Fcitx.InputContext1";
-const FCITX5_PATH: &str = "/org/fcitx/Fcitx5";
+const FCITX5_INPUT_METHOD_PATH: &str = "/inputmethod";
+
+/// An AvailableInputMethods entry: (unique name, name, native name, icon, label, language
+/// code, configurable).
+type RawInputMethod = (String, String, String, String, String, String, bool);
+/// A GetConfig description entry, which the FEP doesn't use.
+type RawConfigDescription = (String, String, String, OwnedValue, HashMap<String, OwnedValue>);
 
 #[proxy(
     interface = "org.fcitx.Fcitx.Controller1",
@@ -21,15 +40,61 @@ const FCITX5_PATH: &str = "/org/fcitx/Fcitx5";
     default_path = "/org/fcitx/Fcitx5"
 )]
 trait FcitxController {
-    /// CreateInputContext method (async)
-    #[zbus(name = "CreateInputContext")] // 明示的に名前を指定
-    async fn create_input_context(
+    /// AvailableInputMethods method (async)
+    /// Each entry is (unique name, name, native name, icon, label, language code, configurable).
+    #[zbus(name = "AvailableInputMethods")]
+    async fn available_input_methods(
+        &self,
+    ) -> zbus::Result<Vec<RawInputMethod>>;
+
+    /// CurrentInputMethod method (async): unique name of the current input method.
+    #[zbus(name = "CurrentInputMethod")]
+    async fn current_input_method(&self) -> zbus::Result<String>;
+
+    /// State method (async): 0 = no input context focused, 1 = inactive, 2 = active.
+    #[zbus(name = "State")]
+    async fn state(&self) -> zbus::Result<i32>;
+
+    /// SetCurrentIM method (async): switches the focused input context to an input method.
+    #[zbus(name = "SetCurrentIM")]
+    async fn set_current_im(&self, im: &str) -> zbus::Result<()>;
+
+    /// CurrentInputMethodGroup method (async): name of the active input method group.
+    #[zbus(name = "CurrentInputMethodGroup")]
+    async fn current_input_method_group(&self) -> zbus::Result<String>;
+
+    /// InputMethodGroupInfo method (async): the group's default layout and its input
+    /// methods as (unique name, layout) pairs.
+    #[zbus(name = "InputMethodGroupInfo")]
+    async fn input_method_group_info(&self, name: &str) -> zbus::Result<(String, Vec<(String, String)>)>;
+
+    /// GetConfig method (async): the values of a configuration (e.g. `fcitx://config/global`)
+    /// as nested string dictionaries, and its description, which the FEP doesn't use.
+    #[zbus(name = "GetConfig")]
+    async fn get_config(
         &self,
-        args: &HashMap<&str, zbus::zvariant::Value<'_>>,
-    ) -> zbus::Result<(OwnedObjectPath, u32)>; // OwnedObjectPath を使用
+        uri: &str,
+    ) -> zbus::Result<(OwnedValue, Vec<RawConfigDescription>)>;
+
+    /// InputMethodGroupsChanged signal receiver: the input method configuration changed.
+    #[zbus(signal)]
+    async fn input_method_groups_changed(&self) -> zbus::Result<()>;
 }
 
-#[proxy(interface = "org.fcitx.Fcitx.InputContext1")]
+/// The D-Bus frontend addon's entry point, which hands out input contexts.
+#[proxy(
+    interface = "org.fcitx.Fcitx.InputMethod1",
+    default_service = "org.fcitx.Fcitx5",
+    default_path = "/inputmethod"
+)]
+trait FcitxInputMethod {
+    /// CreateInputContext method (async): takes (key, value) hints such as ("program", name)
+    /// and returns the new context's path and its UUID.
+    #[zbus(name = "CreateInputContext")]
+    async fn create_input_context(&self, args: &[(&str, &str)]) -> zbus::Result<(OwnedObjectPath, Vec<u8>)>;
+}
+
+#[proxy(interface = "org.fcitx.Fcitx.InputContext1", default_service = "org.fcitx.Fcitx5")]
 trait FcitxInputContext {
     /// ProcessKeyEvent method (async)
     #[zbus(name = "ProcessKeyEvent")]
@@ -54,6 +119,22 @@ trait FcitxInputContext {
     #[zbus(name = "Reset")]
     async fn reset(&self) -> zbus::Result<()>;
 
+    /// SetSurroundingText method (async)
+    #[zbus(name = "SetSurroundingText")]
+    async fn set_surrounding_text(&self, text: &str, cursor: u32, anchor: u32) -> zbus::Result<()>;
+
+    /// PrevPage method (async). Only in recent Fcitx versions; see `InputContextFeatures`.
+    #[zbus(name = "PrevPage")]
+    async fn prev_page(&self) -> zbus::Result<()>;
+
+    /// NextPage method (async). Only in recent Fcitx versions; see `InputContextFeatures`.
+    #[zbus(name = "NextPage")]
+    async fn next_page(&self) -> zbus::Result<()>;
+
+    /// SetCapability method (async): capability flags of the client.
+    #[zbus(name = "SetCapability")]
+    async fn set_capability(&self, caps: u64) -> zbus::Result<()>;
+
     /// SetCursorRect method (async, example)
     #[zbus(name = "SetCursorRect")]
     async fn set_cursor_rect(&self, x: i32, y: i32, w: i32, h: i32) -> zbus::Result<()>;
@@ -65,128 +146,894 @@ trait FcitxInputContext {
     #[zbus(signal)]
     async fn commit_string(&self, str: String) -> zbus::Result<()>;
 
+    /// CommitStringWithCursor signal receiver: a commit with the cursor placed at `cursor`
+    /// (characters into `text`). Fcitx versions without it keep using CommitString.
+    #[zbus(signal)]
+    async fn commit_string_with_cursor(&self, text: String, cursor: u32) -> zbus::Result<()>;
+
     /// UpdateFormattedPreedit signal receiver
     #[zbus(signal)]
     async fn update_formatted_preedit(&self, text: Vec<FormattedText>, cursor_pos: i32) -> zbus::Result<()>;
 
+    /// UpdatePreedit signal receiver: the unformatted preedit, emitted instead of (or besides)
+    /// UpdateFormattedPreedit by older or minimal Fcitx setups.
+    #[zbus(signal)]
+    async fn update_preedit(&self, str: String, cursor_pos: i32) -> zbus::Result<()>;
+
+    /// CurrentIM signal receiver: the input method of this context changed.
+    #[zbus(signal)]
+    async fn current_im(&self, name: String, unique_name: String, lang_code: String) -> zbus::Result<()>;
+
+    /// UpdateClientSideUI signal receiver. Only its aux/candidate parts are used, to track
+    /// whether Fcitx shows a popup; versions without it never emit it.
+    #[zbus(signal)]
+    async fn update_client_side_ui(
+        &self,
+        preedit: Vec<FormattedText>,
+        cursor_pos: i32,
+        aux_up: Vec<FormattedText>,
+        aux_down: Vec<FormattedText>,
+        candidates: Vec<(String, String)>,
+        candidate_index: i32,
+        layout_hint: i32,
+        has_prev: bool,
+        has_next: bool,
+    ) -> zbus::Result<()>;
+
     // DeleteSurroundingText signal (example)
     // #[zbus(signal)]
     // async fn delete_surrounding_text(&self, offset: i32, n_chars: u32) -> zbus::Result<()>;
 }
 
-#[derive(DeserializeInto, Type, Debug, Clone)]
+#[derive(Deserialize, Type, Debug, Clone)]
 pub struct FormattedText {
-    text: String,
-    format: i32,
+    pub text: String,
+    pub format: i32, // Fcitx TextFormatFlag bits (underline, highlight, ...)
+}
+
+/// An input method configured in Fcitx, as reported by AvailableInputMethods.
+#[derive(Debug, Clone)]
+pub struct InputMethodInfo {
+    pub unique_name: String, // Name to use when switching input methods (e.g. "mozc")
+    pub name: String, // Human-readable name
+    pub native_name: String,
+    pub label: String, // Short label shown in indicators
+    pub language_code: String,
+}
+
+/// Connects to Fcitx and returns the configured input methods (async).
+/// Does not create an input context.
+pub async fn list_input_methods() -> Result<Vec<InputMethodInfo>, FepError> {
+    let connection = Connection::session().await
+        .map_err(|source| FepError::Connect { step: ConnectStep::BusConnect, source })?;
+    let controller_proxy = FcitxControllerProxy::new(&connection).await
+        .map_err(|source| FepError::Connect { step: ConnectStep::ControllerProxy, source })?;
+
+    let ims = controller_proxy.available_input_methods().await
+        .map_err(|e| FepError::from_call("AvailableInputMethods", e))?;
+    Ok(ims
+        .into_iter()
+        .map(|(unique_name, name, native_name, _icon, label, language_code, _configurable)| InputMethodInfo {
+            unique_name,
+            name,
+            native_name,
+            label,
+            language_code,
+        })
+        .collect())
+}
+
+/// One check of `--self-test`: what was checked and the finding, `Err` if it's a problem.
+#[derive(Debug, Clone)]
+pub struct SelfTestCheck {
+    pub name: &'static str,
+    pub outcome: Result<String, String>,
+}
+
+/// Checks the parts of the Fcitx setup the FEP relies on, for `--self-test` (async): the
+/// session bus, the D-Bus frontend addon, an input context and its interface, and the
+/// input method configuration. Stops at the first check the later ones depend on.
+pub async fn self_test(program: &str) -> Vec<SelfTestCheck> {
+    match Connection::session().await {
+        Ok(connection) => {
+            let mut checks = vec![SelfTestCheck { name: "session bus", outcome: Ok("connected".to_string()) }];
+            checks.extend(self_test_on(connection, program).await);
+            checks
+        }
+        Err(e) => vec![SelfTestCheck { name: "session bus", outcome: Err(e.to_string()) }],
+    }
+}
+
+/// The checks of `self_test` after the bus connection, over `connection` (async).
+pub async fn self_test_on(connection: Connection, program: &str) -> Vec<SelfTestCheck> {
+    let mut checks = Vec::new();
+    let mut check = |name, outcome: Result<String, String>| {
+        let ok = outcome.is_ok();
+        checks.push(SelfTestCheck { name, outcome });
+        ok
+    };
+
+    // Unlike connecting, the self-test treats an Fcitx it can't introspect as a failure
+    let frontend = match probe_frontend(&connection).await {
+        Ok(Ok(())) => Ok(format!("{} offers CreateInputContext", FCITX5_IFACE_INPUT_METHOD)),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(e) => Err(format!("could not reach Fcitx on the bus ({}); is fcitx5 running?", e)),
+    };
+    if !check("D-Bus frontend", frontend) {
+        return checks;
+    }
+
+    let mut client = match FcitxClient::connect_on(connection.clone(), program).await {
+        Ok(client) => client,
+        Err(e) => {
+            check("input context", Err(e.to_string()));
+            return checks;
+        }
+    };
+    let Some(ic_path) = client.ic_path().cloned() else {
+        return checks; // Only a disconnected client has no context
+    };
+    check("input context", Ok(format!("created at {}", ic_path.as_str())));
+
+    let interface = match introspect(&connection, ic_path).await {
+        Ok(xml) => match interface_xml(&xml, FCITX5_IFACE_IC) {
+            Some(iface) => {
+                let missing = missing_ic_members(iface);
+                let features = ic_features(iface);
+                if missing.is_empty() {
+                    Ok(format!(
+                        "complete (paging: {}, commits with cursor: {})",
+                        if features.paging { "yes" } else { "no" },
+                        if features.commit_with_cursor { "yes" } else { "no" }
+                    ))
+                } else {
+                    Err(format!("missing {}", missing.join(", ")))
+                }
+            }
+            None => Err(format!("not exposed. {}", ENABLE_FRONTEND_HINT)),
+        },
+        Err(e) => Err(format!("could not introspect the input context: {}", e)),
+    };
+    check(FCITX5_IFACE_IC, interface);
+
+    let input_methods = match client.has_input_methods() {
+        Some(true) => Ok("an input method besides keyboard layouts is configured".to_string()),
+        Some(false) => Err("only keyboard layouts are configured; add an input method with fcitx5-configtool".to_string()),
+        None => Ok("unknown (Fcitx did not report its input method groups)".to_string()),
+    };
+    check("input methods", input_methods);
+
+    client.disconnect().await;
+    checks
+}
+
+// Fcitx capability flags (fcitx-utils CapabilityFlag)
+const CAPABILITY_PREEDIT: u64 = 1 << 1;
+const CAPABILITY_FORMATTED_PREEDIT: u64 = 1 << 4;
+const CAPABILITY_SURROUNDING_TEXT: u64 = 1 << 6;
+const CAPABILITY_CLIENT_SIDE_INPUT_PANEL: u64 = 1 << 39;
+const CAPABILITY_COMMIT_STRING_WITH_CURSOR: u64 = 1 << 41;
+
+/// Capability flags by their CapabilityFlag names, for `--capability`.
+pub const CAPABILITY_FLAGS: &[(&str, u64)] = &[
+    ("ClientSideUI", 1 << 0),
+    ("Preedit", CAPABILITY_PREEDIT),
+    ("ClientSideControlState", 1 << 2),
+    ("Password", 1 << 3),
+    ("FormattedPreedit", CAPABILITY_FORMATTED_PREEDIT),
+    ("ClientUnfocusCommit", 1 << 5),
+    ("SurroundingText", CAPABILITY_SURROUNDING_TEXT),
+    ("Email", 1 << 7),
+    ("Digit", 1 << 8),
+    ("Uppercase", 1 << 9),
+    ("Lowercase", 1 << 10),
+    ("NoAutoUpperCase", 1 << 11),
+    ("Url", 1 << 12),
+    ("Dialable", 1 << 13),
+    ("Number", 1 << 14),
+    ("NoOnScreenKeyboard", 1 << 15),
+    ("SpellCheck", 1 << 16),
+    ("NoSpellCheck", 1 << 17),
+    ("WordCompletion", 1 << 18),
+    ("UppercaseWords", 1 << 19),
+    ("UppwercaseSentences", 1 << 20), // Sic, as spelled in Fcitx
+    ("Alpha", 1 << 21),
+    ("Name", 1 << 22),
+    ("GetIMInfoOnFocus", 1 << 23),
+    ("RelativeRect", 1 << 24),
+    ("ClientSideInputPanel", CAPABILITY_CLIENT_SIDE_INPUT_PANEL),
+    ("CommitStringWithCursor", CAPABILITY_COMMIT_STRING_WITH_CURSOR),
+];
+
+/// InputContext1 methods and signals the FEP relies on.
+const REQUIRED_IC_MEMBERS: &[(&str, &str)] = &[
+    ("method", "ProcessKeyEvent"),
+    ("method", "FocusIn"),
+    ("method", "FocusOut"),
+    ("method", "Reset"),
+    ("method", "SetCursorRect"),
+    ("signal", "CommitString"),
+];
+
+/// Optional InputContext1 members, detected by introspection.
+/// Everything is assumed unavailable if introspection fails.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InputContextFeatures {
+    /// `PrevPage`/`NextPage` for explicit candidate paging.
+    pub paging: bool,
+    /// `CommitStringWithCursor`, sent by engines that place the cursor inside their commit.
+    pub commit_with_cursor: bool,
+}
+
+/// What to do about a disabled D-Bus frontend, appended to `FepError::FrontendUnavailable`.
+const ENABLE_FRONTEND_HINT: &str = "Fcitx is running, but its D-Bus frontend addon seems to be disabled. \
+     Enable \"DBus Frontend\" (and \"DBus\") under Addons in fcitx5-configtool, or remove it from \
+     DisabledAddons in ~/.config/fcitx5/config, then restart Fcitx with `fcitx5 -r`.";
+
+/// Returns the introspection XML of `path` on the Fcitx service (async).
+async fn introspect(connection: &Connection, path: OwnedObjectPath) -> zbus::Result<String> {
+    let proxy = zbus::fdo::IntrospectableProxy::builder(connection)
+        .destination(FCITX5_SERVICE)?
+        .path(path)?
+        .build()
+        .await?;
+    Ok(proxy.introspect().await?)
+}
+
+/// Returns the part of introspection `xml` that describes interface `name`.
+/// A cheap textual check is enough here; the interfaces are small.
+fn interface_xml<'x>(xml: &'x str, name: &str) -> Option<&'x str> {
+    let iface = &xml[xml.find(&format!("<interface name=\"{}\"", name))?..];
+    Some(&iface[..iface.find("</interface>").unwrap_or(iface.len())])
+}
+
+/// Checks, before creating an input context, that the D-Bus frontend offers
+/// CreateInputContext (async). Without the frontend addon the call fails or yields a context
+/// that is never served. Fails only on a definite answer: if Fcitx can't be introspected
+/// (e.g. it isn't running yet), connecting proceeds and reports its own error.
+async fn check_frontend(connection: &Connection) -> Result<(), FepError> {
+    probe_frontend(connection).await.unwrap_or(Ok(()))
+}
+
+/// Introspects the D-Bus frontend's input method object and checks it (async). The outer
+/// error means Fcitx couldn't be asked at all.
+async fn probe_frontend(connection: &Connection) -> zbus::Result<Result<(), FepError>> {
+    match introspect(connection, ObjectPath::from_static_str_unchecked(FCITX5_INPUT_METHOD_PATH).into()).await {
+        Ok(xml) => Ok(frontend_status(&xml)),
+        Err(e) if is_unknown_object(&e) => Ok(frontend_status("")),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether Fcitx answered that the object doesn't exist: Fcitx is running, but nothing
+/// serves the path.
+fn is_unknown_object(e: &zbus::Error) -> bool {
+    const UNKNOWN_OBJECT: &str = "org.freedesktop.DBus.Error.UnknownObject";
+    match e {
+        zbus::Error::FDO(e) => matches!(**e, zbus::fdo::Error::UnknownObject(_)),
+        zbus::Error::MethodError(name, ..) => name.as_str() == UNKNOWN_OBJECT,
+        _ => false,
+    }
+}
+
+/// Whether the introspection `xml` of the input method object offers CreateInputContext.
+fn frontend_status(xml: &str) -> Result<(), FepError> {
+    match interface_xml(xml, FCITX5_IFACE_INPUT_METHOD) {
+        Some(iface) if iface.contains("<method name=\"CreateInputContext\"") => Ok(()),
+        Some(_) => Err(FepError::FrontendUnavailable(format!(
+            "{} has no CreateInputContext method. {}",
+            FCITX5_IFACE_INPUT_METHOD, ENABLE_FRONTEND_HINT
+        ))),
+        None => Err(FepError::FrontendUnavailable(format!(
+            "{} does not expose {}. {}",
+            FCITX5_INPUT_METHOD_PATH, FCITX5_IFACE_INPUT_METHOD, ENABLE_FRONTEND_HINT
+        ))),
+    }
+}
+
+/// Introspects the input context, warns about missing members and reports which
+/// optional members are available (async).
+/// A version/interface mismatch otherwise shows up later as opaque method-not-found or
+/// deserialization errors. Introspection errors are only logged; the only failure is a
+/// context without InputContext1 at all, which means the D-Bus frontend isn't serving it.
+async fn check_input_context_interface(connection: &Connection, ic_path: &OwnedObjectPath) -> Result<InputContextFeatures, FepError> {
+    let xml = match introspect(connection, ic_path.clone()).await {
+        Ok(xml) => xml,
+        Err(e) => {
+            eprintln!("Warning: could not introspect the Fcitx input context: {}", e);
+            return Ok(InputContextFeatures::default());
+        }
+    };
+
+    let Some(iface) = interface_xml(&xml, FCITX5_IFACE_IC) else {
+        return Err(FepError::FrontendUnavailable(format!(
+            "the input context at {} does not expose {}. {}",
+            ic_path.as_str(), FCITX5_IFACE_IC, ENABLE_FRONTEND_HINT
+        )));
+    };
+    let missing = missing_ic_members(iface);
+    if !missing.is_empty() {
+        eprintln!(
+            "Warning: Fcitx's {} is missing {}; some features may not work with this Fcitx version.",
+            FCITX5_IFACE_IC,
+            missing.join(", ")
+        );
+    }
+    Ok(ic_features(iface))
+}
+
+/// The required InputContext1 members missing from the interface XML `iface`.
+fn missing_ic_members(iface: &str) -> Vec<String> {
+    let mut missing: Vec<String> = REQUIRED_IC_MEMBERS
+        .iter()
+        .filter(|(kind, name)| !iface.contains(&format!("<{} name=\"{}\"", kind, name)))
+        .map(|(kind, name)| format!("{} {}", kind, name))
+        .collect();
+    // Either preedit signal will do; UpdatePreedit is the fallback
+    if !iface.contains("<signal name=\"UpdateFormattedPreedit\"") && !iface.contains("<signal name=\"UpdatePreedit\"") {
+        missing.push("signal UpdateFormattedPreedit".to_string());
+    }
+    missing
+}
+
+/// The optional members present in the InputContext1 interface XML `iface`.
+fn ic_features(iface: &str) -> InputContextFeatures {
+    let has_method = |name: &str| iface.contains(&format!("<method name=\"{}\"", name));
+    InputContextFeatures {
+        paging: has_method("PrevPage") && has_method("NextPage"),
+        commit_with_cursor: iface.contains("<signal name=\"CommitStringWithCursor\""),
+    }
+}
+
+/// Queries the current input method and whether it is active (async).
+/// Older Fcitx versions may not answer; that is only logged, leaving the status unknown.
+async fn query_im_status(controller_proxy: &FcitxControllerProxy<'_>) -> Option<ImStatus> {
+    let name = match controller_proxy.current_input_method().await {
+        Ok(name) => name,
+        Err(e) => {
+            eprintln!("[debug] could not query the current input method: {}", e);
+            return None;
+        }
+    };
+    let active = match controller_proxy.state().await {
+        Ok(state) => Some(state == 2),
+        Err(e) => {
+            eprintln!("[debug] could not query the input method state: {}", e);
+            None
+        }
+    };
+    Some(ImStatus { name, active })
+}
+
+/// Queries whether the current input method group has an input method besides keyboard
+/// layouts (async). Without one, Fcitx declines every key. `None` if it can't be queried.
+async fn query_has_input_methods(controller_proxy: &FcitxControllerProxy<'_>) -> Option<bool> {
+    let group = match controller_proxy.current_input_method_group().await {
+        Ok(group) => group,
+        Err(e) => {
+            eprintln!("[debug] could not query the current input method group: {}", e);
+            return None;
+        }
+    };
+    match controller_proxy.input_method_group_info(&group).await {
+        Ok((_layout, ims)) => Some(ims.iter().any(|(im, _layout)| !im.starts_with("keyboard-"))),
+        Err(e) => {
+            eprintln!("[debug] could not query input method group {}: {}", group, e);
+            None
+        }
+    }
+}
+
+/// Queries Fcitx's global candidates-per-page setting (Behavior/DefaultPageSize) (async).
+/// Engines may override it; the event loop also learns the size from full pages.
+/// `None` if it can't be queried or parsed.
+async fn query_page_size(controller_proxy: &FcitxControllerProxy<'_>) -> Option<usize> {
+    let (values, _description) = match controller_proxy.get_config("fcitx://config/global").await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("[debug] could not query the global Fcitx configuration: {}", e);
+            return None;
+        }
+    };
+    let page_size = config_group(values.into())
+        .and_then(|global| global.get("Behavior").and_then(|behavior| config_group(behavior.try_clone().ok()?.into())))
+        .and_then(|behavior| behavior.get("DefaultPageSize").and_then(|size| <&str>::try_from(&**size).ok()?.parse().ok()))
+        .filter(|&size: &usize| size > 0);
+    if page_size.is_none() {
+        eprintln!("[debug] the global Fcitx configuration has no usable Behavior/DefaultPageSize");
+    }
+    page_size
+}
+
+/// Unwraps a configuration group (a string-keyed dictionary, possibly inside a variant).
+fn config_group(value: Value<'_>) -> Option<HashMap<String, OwnedValue>> {
+    match value {
+        Value::Value(inner) => config_group(*inner),
+        value => HashMap::try_from(value).ok(),
+    }
+}
+
+/// Turns an InputContext1 signal into an update; `None` for signals the FEP doesn't use.
+/// Once Fcitx has sent a formatted preedit (`formatted_seen`), plain UpdatePreedit signals
+/// only repeat it and are dropped.
+fn ic_signal_update(message: &zbus::Message, formatted_seen: &mut bool) -> Option<Result<FcitxUpdate, FepError>> {
+    let signal_error = |name: &str, e: zbus::Error| FepError::FcitxConnection(format!("{} signal error: {}", name, e));
+    if let Some(signal) = CommitString::from_message(message.clone()) {
+        return Some(signal.args()
+            .map(|args| FcitxUpdate::CommitString(args.str))
+            .map_err(|e| signal_error("CommitString", e)));
+    }
+    if let Some(signal) = CommitStringWithCursor::from_message(message.clone()) {
+        return Some(signal.args()
+            .map(|args| FcitxUpdate::CommitStringWithCursor { text: args.text, cursor: args.cursor as usize })
+            .map_err(|e| signal_error("CommitStringWithCursor", e)));
+    }
+    if let Some(signal) = UpdateFormattedPreedit::from_message(message.clone()) {
+        *formatted_seen = true;
+        return Some(signal.args()
+            .map(|args| FcitxUpdate::UpdatePreedit {
+                text: args.text.into_iter().map(|s| s.text).collect(),
+                cursor_pos: args.cursor_pos,
+            })
+            .map_err(|e| signal_error("UpdateFormattedPreedit", e)));
+    }
+    if let Some(signal) = UpdatePreedit::from_message(message.clone()) {
+        if *formatted_seen {
+            return None;
+        }
+        return Some(signal.args()
+            .map(|args| FcitxUpdate::UpdatePreedit { text: args.str, cursor_pos: args.cursor_pos })
+            .map_err(|e| signal_error("UpdatePreedit", e)));
+    }
+    if let Some(signal) = CurrentIm::from_message(message.clone()) {
+        return Some(signal.args()
+            .map(|args| FcitxUpdate::CurrentIm(args.unique_name))
+            .map_err(|e| signal_error("CurrentIM", e)));
+    }
+    if let Some(signal) = UpdateClientSideUi::from_message(message.clone()) {
+        return Some(signal.args()
+            .map(|args| FcitxUpdate::ClientSideUi {
+                // Each candidate is (label, text), e.g. ("1. ", "日本語")
+                candidates: args.candidates.into_iter().map(|(label, text)| label + &text).collect(),
+                highlighted: usize::try_from(args.candidate_index).ok(),
+                aux_visible: !args.aux_up.is_empty() || !args.aux_down.is_empty(),
+                has_prev: args.has_prev,
+                has_next: args.has_next,
+            })
+            .map_err(|e| signal_error("UpdateClientSideUI", e)));
+    }
+    None
 }
 
 // --- Fcitx Client Implementation (Async) ---
 
+/// The stream of Fcitx updates returned by `FcitxClient::receive_updates`.
+pub type UpdateStream<'a> = Pin<Box<dyn Stream<Item = Result<FcitxUpdate, FepError>> + Send + 'a>>;
+
 pub struct FcitxClient<'a> {
     connection: Connection, // Async Connection
     // controller_proxy: FcitxControllerProxy<'a>, // Async Proxy type
     ic_proxy: Option<FcitxInputContextProxy<'a>>, // Async Proxy
     ic_path: Option<OwnedObjectPath>, // Owned path
+    injected_tx: broadcast::Sender<FcitxUpdate>, // Sender for synthetic updates (see `update_injector`)
+    features: InputContextFeatures, // Optional members this Fcitx version provides
+    initial_im: Option<ImStatus>, // Input method at connect time, if it could be queried
+    has_input_methods: Option<bool>, // Whether any input method is configured, as of the last check
+    page_size: Option<usize>, // Fcitx's global candidates per page, as of the last check
+    key_clock: Option<Instant>, // Origin of key event timestamps; None sends time = 0
+    focused: bool, // Whether our input context has focus (tracked from our own FocusIn/FocusOut)
+    focus_request: Option<(bool, Instant)>, // Focus change waiting for FOCUS_DEBOUNCE to pass, and when it's due
+    cursor_rect: Option<(i32, i32, i32, i32)>, // Last rectangle sent with SetCursorRect
 }
 
-// We need a helper struct to hold the streams because the proxy reference cannot be held across awaits easily
-struct FcitxSignalStreams<'a> {
-     commit_stream: SignalStream<'a, String>,
-     preedit_stream: SignalStream<'a, (Vec<FormattedText>, i32)>,
-     // Add other signal streams if needed
-}
+/// Synthetic updates each update stream can hold before it falls behind and skips some.
+const INJECTED_UPDATE_CAPACITY: usize = 1024;
+
+/// How long a requested focus change must stand before FocusIn/FocusOut is sent, so a
+/// burst of toggles collapses into at most one call for its final state.
+const FOCUS_DEBOUNCE: Duration = Duration::from_millis(50);
 
 
 impl<'a> FcitxClient<'a> {
     /// Establishes an async connection and creates an input context.
-    pub async fn connect() -> Result<Self, FepError> {
+    /// `program` identifies the client to Fcitx, which keeps per-program input method state.
+    pub async fn connect(program: &str) -> Result<Self, FepError> {
         println!("Connecting to Fcitx5 via D-Bus (async)...");
         let connection = Connection::session().await // await for async connection
-            .map_err(|e| FepError::FcitxConnection(e.to_string()))?;
+            .map_err(|source| FepError::Connect { step: ConnectStep::BusConnect, source })?;
         println!("D-Bus session connection established.");
+        Self::connect_on(connection, program).await
+    }
 
+    /// Creates an input context over an existing D-Bus connection (async).
+    /// Besides the session bus, this can be a private bus or a peer-to-peer connection to
+    /// an in-process service implementing Controller1/InputContext1, which lets the real
+    /// proxy definitions be exercised without a running Fcitx.
+    pub async fn connect_on(connection: Connection, program: &str) -> Result<Self, FepError> {
         let controller_proxy = FcitxControllerProxy::new(&connection).await // await proxy creation
-            .map_err(|e| FepError::FcitxConnection(format!("Failed to create controller proxy: {}", e)))?;
-        println!("Fcitx controller proxy created.");
-
-        let mut args = HashMap::new();
-        args.insert("program", Value::from("fep-rust-example-async").into());
+            .map_err(|source| FepError::Connect { step: ConnectStep::ControllerProxy, source })?;
+        let input_method_proxy = FcitxInputMethodProxy::new(&connection).await
+            .map_err(|source| FepError::Connect { step: ConnectStep::InputMethodProxy, source })?;
+        println!("Fcitx controller and input method proxies created.");
+        check_frontend(&connection).await?;
 
         println!("Calling CreateInputContext (async)...");
-        let (ic_path, _ic_caps) = controller_proxy.create_input_context(&args).await // await method call
-            .map_err(|e| FepError::FcitxConnection(format!("Failed to create input context: {}", e)))?;
+        let (ic_path, _uuid) = input_method_proxy.create_input_context(&[("program", program)]).await // await method call
+            .map_err(|source| FepError::Connect { step: ConnectStep::CreateInputContext, source })?;
         println!("Input Context created at path: {}", ic_path);
 
         // Create the async proxy for the Input Context
+        let ic_proxy_error = |source| FepError::Connect { step: ConnectStep::InputContextProxy, source };
         let ic_proxy = FcitxInputContextProxy::builder(&connection)
-            .path(ic_path.clone())? // Use clone of OwnedObjectPath
+            .path(ic_path.clone()).map_err(ic_proxy_error)? // Use clone of OwnedObjectPath
             .build().await // await async build
-            .map_err(|e| FepError::FcitxConnection(format!("Failed to create IC proxy: {}", e)))?;
+            .map_err(ic_proxy_error)?;
         println!("Input context proxy created.");
 
-        let mut client = FcitxClient {
-            connection,
-            ic_proxy: Some(ic_proxy),
-            ic_path: Some(ic_path),
-        };
-
         // Activate the input context (async)
-        client.focus_in().await?;
+        ic_proxy.focus_in().await
+            .map_err(|source| FepError::Connect { step: ConnectStep::FocusIn, source })?;
         println!("Input context focused.");
 
-        Ok(client)
+        // Check that this Fcitx version exposes what we rely on (non-fatal)
+        let features = check_input_context_interface(&connection, &ic_path).await?;
+        if features.commit_with_cursor {
+            println!("Fcitx sends CommitStringWithCursor; cursor positions in commits are honored.");
+        }
+
+        // Query the current input method now (after FocusIn, so State refers to our context),
+        // so the first render already knows it. Later changes arrive as CurrentIM signals.
+        let initial_im = query_im_status(&controller_proxy).await;
+        let has_input_methods = query_has_input_methods(&controller_proxy).await;
+        let page_size = query_page_size(&controller_proxy).await;
+
+        let (injected_tx, _) = broadcast::channel(INJECTED_UPDATE_CAPACITY);
+        Ok(FcitxClient {
+            connection,
+            ic_proxy: Some(ic_proxy),
+            ic_path: Some(ic_path),
+            injected_tx,
+            features,
+            initial_im,
+            has_input_methods,
+            page_size,
+            key_clock: Some(Instant::now()),
+            focused: true, // FocusIn was sent above
+            focus_request: None,
+            cursor_rect: None,
+        })
     }
 
-    /// Returns a combined stream of relevant Fcitx updates.
-    pub async fn receive_updates(&self) -> Result<impl Stream<Item = Result<FcitxUpdate, FepError>> + '_, FepError> {
+    /// Returns a combined stream of relevant Fcitx updates, which ends when the D-Bus
+    /// connection closes. Each call subscribes anew and can be made again on the same client.
+    /// Ordering: the input context's signals are delivered in exactly the order Fcitx sent
+    /// them, so a commit is never applied after a preedit that followed it (or before one
+    /// that preceded it). Configuration changes and injected updates are merged in as they come.
+    pub async fn receive_updates(&self) -> Result<UpdateStream<'a>, FepError> {
         let proxy = self.ic_proxy.as_ref().ok_or_else(|| FepError::FcitxConnection("Input context proxy not available for signals".to_string()))?;
 
-        let commit_signal_stream = proxy.receive_commit_string().await
-             .map_err(|e| FepError::FcitxConnection(format!("Failed to receive CommitString signal: {}", e)))?;
-        let preedit_signal_stream = proxy.receive_update_formatted_preedit().await
-             .map_err(|e| FepError::FcitxConnection(format!("Failed to receive UpdateFormattedPreedit signal: {}", e)))?;
+        // One subscription for all of the context's signals keeps them in arrival order;
+        // separate per-signal streams merged afterwards could reorder a commit and a preedit.
+        let ic_signal_stream = proxy.inner().receive_all_signals().await
+             .map_err(|e| FepError::FcitxConnection(format!("Failed to receive InputContext1 signals: {}", e)))?;
+        let mut formatted_seen = false;
+        let ic_stream = ic_signal_stream.filter_map(move |message| future::ready(ic_signal_update(&message, &mut formatted_seen)));
 
-        let commit_stream = commit_signal_stream.map(|args_result| {
-             args_result
-                 .map(|args| FcitxUpdate::CommitString(args.str))
-                 .map_err(|e| FepError::FcitxConnection(format!("CommitString signal error: {}", e)))
-        });
+        // Changes to the input method configuration come from the controller, not the context
+        let controller_proxy = FcitxControllerProxy::new(&self.connection).await
+             .map_err(|e| FepError::from_call("Controller proxy", e))?;
+        let groups_signal_stream = controller_proxy.receive_input_method_groups_changed().await
+             .map_err(|e| FepError::FcitxConnection(format!("Failed to receive InputMethodGroupsChanged signal: {}", e)))?;
+        let groups_stream = groups_signal_stream.map(|_| Ok(FcitxUpdate::InputMethodsChanged));
 
-        let preedit_stream = preedit_signal_stream.map(|args_result| {
-             args_result.map(|args| {
-                 // args は (Vec<FormattedText>, i32) 型
-                 let text = args.text.into_iter().map(|s| s.text).collect::<String>();
-                 let cursor_pos = args.cursor_pos; // カーソル位置を取得
-                 println!("Raw Preedit Signal: text='{}', cursor_pos={}", text, cursor_pos);
-                 // 新しい FcitxUpdate::UpdatePreedit を使用
-                 FcitxUpdate::UpdatePreedit { text, cursor_pos }
-             })
-             .map_err(|e| FepError::FcitxConnection(format!("UpdateFormattedPreedit signal error: {}", e)))
+        let signals = tokio_stream::StreamExt::merge(ic_stream, groups_stream);
+
+        // Synthetic updates from `update_injector` flow through the same stream. Every call
+        // subscribes its own receiver, so each stream gets the updates injected after it was
+        // created. A stream that falls more than INJECTED_UPDATE_CAPACITY behind skips ahead.
+        let injected_stream = stream::unfold(self.injected_tx.subscribe(), |mut injected_rx| async move {
+            loop {
+                match injected_rx.recv().await {
+                    Ok(update) => return Some((Ok(update), injected_rx)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
         });
 
-        Ok(tokio_stream::StreamExt::merge(commit_stream, preedit_stream))
+        // The injector's sender lives as long as the client, so the merged stream is ended
+        // explicitly once the signal streams end (the connection closed): a `None` marker
+        // after the last signal stops it.
+        let signals = signals.map(Some).chain(stream::once(future::ready(None)));
+        let updates = stream::select(signals, injected_stream.map(Some))
+            .take_while(|update| future::ready(update.is_some()))
+            .filter_map(future::ready);
+        Ok(updates.boxed())
+    }
+
+    /// Subscribes to updates again after a stream from `receive_updates` ended (async).
+    /// Retries every `retry` until `deadline`, without a new input context, for bus hiccups
+    /// that end the stream while the connection survives. Fails with `UpdatesEnded` once the
+    /// deadline passes (at once if it already has), meaning a full reconnect is needed.
+    pub async fn resubscribe_updates(&self, deadline: tokio::time::Instant, retry: Duration) -> Result<UpdateStream<'a>, FepError> {
+        let mut last_error = None;
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep_until(deadline.min(tokio::time::Instant::now() + retry)).await;
+            match self.receive_updates().await {
+                Ok(updates) => return Ok(updates),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(FepError::UpdatesEnded(match last_error {
+            Some(e) => format!("resubscribing failed until the grace period ran out: {}", e),
+            None => "no grace period for resubscribing".to_string(),
+        }))
+    }
+
+    /// Returns the D-Bus connection this client talks to Fcitx over, for calls the client
+    /// doesn't wrap (addon or experimental methods): build proxies on it, or clone it (cheap)
+    /// to keep it beyond the borrow. It stays open as long as either the client or a clone lives.
+    /// Changing this input context out of band (focus, reset, capabilities) isn't reflected
+    /// in `AppState` or in this client's own focus tracking, so the two can drift apart.
+    /// The connection belongs to this client; after a reconnect, get the new one.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
     }
 
-    /// Sends FocusIn signal (async).
+    /// Returns a sender for injecting synthetic `FcitxUpdate`s.
+    /// Updates sent through it are merged into the stream returned by `receive_updates`,
+    /// so they go through `apply_update` and `render` exactly like real signals.
+    /// Intended for testing and for embedders prototyping rendering, not as a production IME.
+    /// Every stream from `receive_updates` that is alive when an update is sent receives it;
+    /// sending fails if there is none. The sender belongs to this client; after a reconnect,
+    /// get a new one.
+    pub fn update_injector(&self) -> broadcast::Sender<FcitxUpdate> {
+        self.injected_tx.clone()
+    }
+
+    /// Whether the FEP's input context currently has focus.
+    /// Tracked locally from the FocusIn/FocusOut calls this client makes; Fcitx doesn't report it.
+    pub fn is_focused(&self) -> bool {
+        self.focused
+    }
+
+    /// Asks for the context to be focused or unfocused, without calling Fcitx yet: the change
+    /// is applied by `apply_focus_request` once no other request came in for `FOCUS_DEBOUNCE`.
+    /// A request back to the current state cancels a pending one, so a FocusOut immediately
+    /// followed by FocusIn makes no D-Bus call at all.
+    pub fn request_focus(&mut self, focused: bool) {
+        self.focus_request = (focused != self.focused).then(|| (focused, Instant::now() + FOCUS_DEBOUNCE));
+    }
+
+    /// When the pending focus request is due, if there is one.
+    pub fn focus_request_deadline(&self) -> Option<Instant> {
+        self.focus_request.map(|(_, due)| due)
+    }
+
+    /// Applies the pending focus request now, whether or not it is due (async). Losing focus
+    /// settles the preedit according to `policy` first, as on exit.
+    pub async fn apply_focus_request(&mut self, policy: FocusLossPolicy, state: &mut AppState) -> Result<(), FepError> {
+        match self.focus_request.take() {
+            Some((true, _)) => self.focus_in().await,
+            Some((false, _)) => self.focus_out_with_policy(policy, state).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Sends FocusIn signal (async). Does nothing if the context is already focused.
     pub async fn focus_in(&mut self) -> Result<(), FepError> {
+        if self.focused {
+            return Ok(());
+        }
         if let Some(proxy) = self.ic_proxy.as_mut() {
-            proxy.focus_in().await.map_err(|e| FepError::FcitxConnection(format!("FocusIn failed: {}", e)))?;
+            proxy.focus_in().await.map_err(|e| FepError::from_call("FocusIn", e))?;
+            self.focused = true;
         }
         Ok(())
     }
 
-     /// Sends FocusOut signal (async).
+     /// Sends FocusOut signal (async). Does nothing if the context isn't focused.
     pub async fn focus_out(&mut self) -> Result<(), FepError> {
+        if !self.focused {
+            return Ok(());
+        }
         if let Some(proxy) = self.ic_proxy.as_mut() {
-            proxy.focus_out().await.map_err(|e| FepError::FcitxConnection(format!("FocusOut failed: {}", e)))?;
+            proxy.focus_out().await.map_err(|e| FepError::from_call("FocusOut", e))?;
+            self.focused = false;
         }
         Ok(())
     }
 
+    /// Settles an active preedit before focus is lost, as `policy` says (async).
+    /// With `Commit` the preedit moves into the state's commit buffer; with `Discard` it is
+    /// cleared; with `Engine` nothing happens and the engine decides on FocusOut.
+    pub async fn settle_preedit(&mut self, policy: FocusLossPolicy, state: &mut AppState) -> Result<(), FepError> {
+        if state.preedit_string.is_empty() {
+            return Ok(());
+        }
+        match policy {
+            FocusLossPolicy::Commit => {
+                self.commit_preedit().await?;
+                state.commit_preedit();
+            }
+            FocusLossPolicy::Discard => {
+                self.reset().await?;
+                state.apply_update(FcitxUpdate::UpdatePreedit { text: String::new(), cursor_pos: 0 });
+            }
+            FocusLossPolicy::Engine => {}
+        }
+        Ok(())
+    }
+
+    /// Sends FocusOut after settling the preedit according to `policy` (async).
+    pub async fn focus_out_with_policy(&mut self, policy: FocusLossPolicy, state: &mut AppState) -> Result<(), FepError> {
+        self.settle_preedit(policy, state).await?;
+        self.focus_out().await
+    }
+
     /// Sends Reset signal (async).
      pub async fn reset(&mut self) -> Result<(), FepError> {
         if let Some(proxy) = self.ic_proxy.as_mut() {
-            proxy.reset().await.map_err(|e| FepError::FcitxConnection(format!("Reset failed: {}", e)))?;
+            proxy.reset().await.map_err(|e| FepError::from_call("Reset", e))?;
+        }
+        Ok(())
+    }
+
+    /// Tells Fcitx where the cursor is, so its candidate popup is placed next to it (async).
+    /// Does nothing if the rectangle is the one sent last.
+    pub async fn set_cursor_rect(&mut self, x: i32, y: i32, w: i32, h: i32) -> Result<(), FepError> {
+        if self.cursor_rect == Some((x, y, w, h)) {
+            return Ok(());
+        }
+        if let Some(proxy) = self.ic_proxy.as_mut() {
+            proxy.set_cursor_rect(x, y, w, h).await.map_err(|e| FepError::from_call("SetCursorRect", e))?;
+            self.cursor_rect = Some((x, y, w, h));
+        }
+        Ok(())
+    }
+
+    /// Reports the text around the cursor to Fcitx, with the cursor at `cursor` (characters) (async).
+    pub async fn set_surrounding_text(&mut self, text: &str, cursor: u32) -> Result<(), FepError> {
+        if let Some(proxy) = self.ic_proxy.as_mut() {
+            proxy.set_surrounding_text(text, cursor, cursor).await.map_err(|e| FepError::from_call("SetSurroundingText", e))?;
+        }
+        Ok(())
+    }
+
+    /// Commits `text` as if the engine had committed it (async): it is inserted into the commit
+    /// buffer (or the edited line) and reaches the terminal and sinks with the next render.
+    ///
+    /// InputContext1 has no method for a client to commit text, so for this path the FEP is
+    /// the source of truth and Fcitx is only told afterwards, through SetSurroundingText, so
+    /// that context-aware engines see the inserted text before the cursor. (For commits coming
+    /// from CommitString the engine is the source of truth and the FEP only displays them.)
+    /// A composition in progress is left alone and stays after the inserted text.
+    pub async fn commit_text(&mut self, text: &str, state: &mut AppState) -> Result<(), FepError> {
+        state.push_commit(text);
+        let (surrounding, cursor) = state
+            .line_surrounding_text()
+            .unwrap_or_else(|| (text.to_string(), text.chars().count() as u32));
+        self.set_surrounding_text(&surrounding, cursor).await
+    }
+
+    /// Asks Fcitx to leave drawing its input panel (preedit, aux text, candidates) to the
+    /// FEP, which then receives it through UpdateClientSideUI (async). SurroundingText is
+    /// advertised too, since Fcitx ignores SetSurroundingText (see `commit_text`) from a
+    /// context without it. So is CommitStringWithCursor where this Fcitx has it: Fcitx only
+    /// sends the signal to clients that ask for it.
+    pub async fn enable_client_side_ui(&mut self) -> Result<(), FepError> {
+        self.set_capabilities(self.default_capabilities()).await
+    }
+
+    /// The capability flags `enable_client_side_ui` advertises.
+    fn default_capabilities(&self) -> u64 {
+        let mut caps = CAPABILITY_PREEDIT
+            | CAPABILITY_FORMATTED_PREEDIT
+            | CAPABILITY_SURROUNDING_TEXT
+            | CAPABILITY_CLIENT_SIDE_INPUT_PANEL;
+        if self.features.commit_with_cursor {
+            caps |= CAPABILITY_COMMIT_STRING_WITH_CURSOR;
+        }
+        caps
+    }
+
+    /// Advertises exactly `caps` (see `CAPABILITY_FLAGS`) instead of the default set, for
+    /// reproducing engine behavior that depends on capabilities (`--capability`) (async).
+    pub async fn set_capabilities(&mut self, caps: u64) -> Result<(), FepError> {
+        if let Some(proxy) = self.ic_proxy.as_mut() {
+            proxy.set_capability(caps).await
+                .map_err(|e| FepError::from_call("SetCapability", e))?;
+        }
+        Ok(())
+    }
+
+    /// Chooses between real key event timestamps (the default) and the legacy time = 0.
+    pub fn set_key_timestamps(&mut self, enabled: bool) {
+        self.key_clock = enabled.then(Instant::now);
+    }
+
+    /// Timestamp for ProcessKeyEvent: milliseconds since connect, starting at 1 so it is
+    /// never the "no time" value 0. Monotonic, so a release is never stamped before its press.
+    /// Wraps after ~49 days, like X11 server time.
+    fn key_time(&self) -> u32 {
+        self.key_clock.map_or(0, |clock| (clock.elapsed().as_millis() as u32).wrapping_add(1))
+    }
+
+    /// Switches this (focused) input context to the input method `im` (async).
+    pub async fn set_current_im(&mut self, im: &str) -> Result<(), FepError> {
+        let controller_proxy = FcitxControllerProxy::new(&self.connection).await
+            .map_err(|e| FepError::from_call("Controller proxy", e))?;
+        controller_proxy.set_current_im(im).await
+            .map_err(|e| FepError::from_call("SetCurrentIM", e))
+    }
+
+    /// Whether Fcitx has an input method configured besides keyboard layouts, as of connecting
+    /// or the last `recheck_input_methods`; `None` if unknown.
+    pub fn has_input_methods(&self) -> Option<bool> {
+        self.has_input_methods
+    }
+
+    /// Fcitx's global candidates-per-page setting, as of connecting or the last
+    /// `recheck_input_methods`; `None` if unknown.
+    pub fn page_size(&self) -> Option<usize> {
+        self.page_size
+    }
+
+    /// Queries the input method configuration (and the page size, which Fcitx reloads with
+    /// it) again, e.g. after InputMethodGroupsChanged (async).
+    pub async fn recheck_input_methods(&mut self) -> Option<bool> {
+        match FcitxControllerProxy::new(&self.connection).await {
+            Ok(controller_proxy) => {
+                self.has_input_methods = query_has_input_methods(&controller_proxy).await;
+                self.page_size = query_page_size(&controller_proxy).await;
+            }
+            Err(e) => {
+                eprintln!("[debug] could not create the controller proxy: {}", e);
+                self.has_input_methods = None;
+            }
+        }
+        self.has_input_methods
+    }
+
+    /// Returns the object path of this client's input context, unless disconnected.
+    pub fn ic_path(&self) -> Option<&OwnedObjectPath> {
+        self.ic_path.as_ref()
+    }
+
+    /// Returns the input method queried at connect time, if the query succeeded.
+    pub fn initial_im(&self) -> Option<&ImStatus> {
+        self.initial_im.as_ref()
+    }
+
+    /// Whether this Fcitx supports explicit candidate paging (`prev_page`/`next_page`).
+    pub fn supports_paging(&self) -> bool {
+        self.features.paging
+    }
+
+    /// Shows the previous candidate page (async). Check `supports_paging` first;
+    /// older Fcitx versions need the Page_Up keysym forwarded instead.
+    pub async fn prev_page(&mut self) -> Result<(), FepError> {
+        if let Some(proxy) = self.ic_proxy.as_mut() {
+            proxy.prev_page().await.map_err(|e| FepError::from_call("PrevPage", e))?;
+        }
+        Ok(())
+    }
+
+    /// Shows the next candidate page (async). Check `supports_paging` first;
+    /// older Fcitx versions need the Page_Down keysym forwarded instead.
+    pub async fn next_page(&mut self) -> Result<(), FepError> {
+        if let Some(proxy) = self.ic_proxy.as_mut() {
+            proxy.next_page().await.map_err(|e| FepError::from_call("NextPage", e))?;
+        }
+        Ok(())
+    }
+
+    /// Ends the current composition without further conversion (async).
+    /// InputContext1 has no call that commits the preedit, so the context is reset;
+    /// the caller commits the preedit text it last received (see `AppState::commit_preedit`).
+    pub async fn commit_preedit(&mut self) -> Result<(), FepError> {
+        if let Some(proxy) = self.ic_proxy.as_mut() {
+            proxy.reset().await.map_err(|e| FepError::from_call("Reset (commit preedit)", e))?;
         }
         Ok(())
     }
@@ -199,12 +1046,16 @@ impl<'a> FcitxClient<'a> {
         state: u32,
         is_release: bool,
     ) -> Result<bool, FepError> {
+        // An unfocused context must not receive keys; they are reported as not handled
+        if !self.focused {
+            return Ok(false);
+        }
+        let time = self.key_time();
         let proxy = self.ic_proxy.as_mut().ok_or_else(|| FepError::FcitxConnection("Input context proxy not available".to_string()))?;
-        let time = 0;
 
         println!(
-            "Forwarding key to Fcitx5 (async): keysym=0x{:x}, keycode={}, state={}, release={}",
-            keysym, keycode, state, is_release
+            "Forwarding key to Fcitx5 (async): keysym=0x{:x}, keycode={}, state={}, release={}, time={}",
+            keysym, keycode, state, is_release, time
         );
 
         match proxy.process_key_event(keysym, keycode, state, is_release, time).await { // await the async call
@@ -214,31 +1065,573 @@ impl<'a> FcitxClient<'a> {
             },
             Err(e) => {
                  eprintln!("Error forwarding key event: {}", e);
-                 Err(FepError::FcitxConnection(format!("ProcessKeyEvent failed: {}", e)))
+                 Err(FepError::from_call("ProcessKeyEvent", e))
             }
         }
     }
 
-    /// Disconnects (async cleanup if needed).
+    /// Sends FocusOut (if focused) and releases the input context. The D-Bus connection
+    /// itself closes when the client is dropped.
     pub async fn disconnect(&mut self) {
         println!("Disconnecting from Fcitx5 (async)...");
-        if let Some(proxy) = self.ic_proxy.as_mut() {
+        if let Some(proxy) = self.ic_proxy.as_mut().filter(|_| self.focused) {
             if let Err(e) = proxy.focus_out().await { // await focus_out
                 eprintln!("Error sending FocusOut on disconnect: {}", e);
             }
         }
         self.ic_proxy = None;
         self.ic_path = None;
+        self.focused = false;
         println!("Fcitx5 disconnected (connection will close on drop).");
     }
+
+    /// Like `disconnect`, but gives up after `deadline` (async).
+    /// A wedged bus must not keep the FEP from exiting and restoring the terminal.
+    pub async fn disconnect_with_timeout(&mut self, deadline: Duration) {
+        if tokio::time::timeout(deadline, self.disconnect()).await.is_err() {
+            eprintln!("Disconnecting from Fcitx timed out after {:?}; exiting anyway.", deadline);
+            self.ic_proxy = None;
+            self.ic_path = None;
+            self.focused = false;
+        }
+    }
 }
 
-// Implement Drop for async cleanup if necessary, though connection drop might suffice
 impl<'a> Drop for FcitxClient<'a> {
     fn drop(&mut self) {
-        // Note: Drop cannot be async. If async cleanup is strictly required,
-        // it must be called explicitly before dropping (e.g., client.disconnect().await).
-        // For simple cases, dropping the connection might be enough.
+        // Drop can't await FocusOut: on exit, `event_loop::shutdown` disconnects first.
+        // After a lost connection there is nobody left to tell.
         println!("FcitxClient dropped.");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::UnixStream;
+    use zbus::connection::Builder;
+    use zbus::object_server::SignalContext;
+    use zbus::Guid;
+    use zbus_macros::interface;
+
+    const IC_PATH: &str = "/org/freedesktop/portal/inputcontext/1";
+    /// Keysym the fake engine commits "あ" for; every other key is declined.
+    const COMMIT_KEY: u32 = 0x61; // XK_a
+
+    /// InputMethod1 of the fake Fcitx's D-Bus frontend: hands out the one input context.
+    struct FakeInputMethod;
+
+    #[interface(name = "org.fcitx.Fcitx.InputMethod1")]
+    impl FakeInputMethod {
+        async fn create_input_context(&self, args: Vec<(String, String)>) -> (OwnedObjectPath, Vec<u8>) {
+            assert_eq!(args, [("program".to_string(), "test".to_string())]);
+            (ObjectPath::from_static_str_unchecked(IC_PATH).into(), vec![0; 16])
+        }
+    }
+
+    /// Controller1 of the fake Fcitx.
+    struct FakeController;
+
+    #[interface(name = "org.fcitx.Fcitx.Controller1")]
+    impl FakeController {
+        async fn current_input_method(&self) -> String {
+            "mozc".to_string()
+        }
+
+        async fn state(&self) -> i32 {
+            2
+        }
+    }
+
+    /// Calls the fake input context received, in order.
+    type CallLog = Arc<Mutex<Vec<String>>>;
+
+    /// InputContext1 of the fake Fcitx: logs calls and commits "あ" for `COMMIT_KEY`.
+    struct FakeInputContext {
+        calls: CallLog,
+        wedged: bool, // FocusOut never returns, like on a wedged bus
+    }
+
+    #[interface(name = "org.fcitx.Fcitx.InputContext1")]
+    impl FakeInputContext {
+        async fn process_key_event(
+            &self,
+            keysym: u32,
+            _keycode: u32,
+            state: u32,
+            is_release: bool,
+            _time: u32,
+            #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        ) -> bool {
+            self.calls.lock().unwrap().push(format!("ProcessKeyEvent {:#x} {} {}", keysym, state, is_release));
+            if keysym != COMMIT_KEY || is_release {
+                return false;
+            }
+            // Like Fcitx, the signal goes out before the method returns
+            Self::commit_string(&ctxt, "あ").await.unwrap();
+            true
+        }
+
+        async fn focus_in(&self) {
+            self.calls.lock().unwrap().push("FocusIn".to_string());
+        }
+
+        async fn focus_out(&self) {
+            self.calls.lock().unwrap().push("FocusOut".to_string());
+            if self.wedged {
+                std::future::pending::<()>().await;
+            }
+        }
+
+        async fn reset(&self) {
+            self.calls.lock().unwrap().push("Reset".to_string());
+        }
+
+        async fn set_cursor_rect(&self, x: i32, y: i32, _w: i32, _h: i32) {
+            self.calls.lock().unwrap().push(format!("SetCursorRect {} {}", x, y));
+        }
+
+        async fn prev_page(&self) {
+            self.calls.lock().unwrap().push("PrevPage".to_string());
+        }
+
+        async fn next_page(&self) {
+            self.calls.lock().unwrap().push("NextPage".to_string());
+        }
+
+        async fn set_surrounding_text(&self, text: &str, cursor: u32, _anchor: u32) {
+            self.calls.lock().unwrap().push(format!("SetSurroundingText {} {}", text, cursor));
+        }
+
+        async fn set_capability(&self, caps: u64) {
+            self.calls.lock().unwrap().push(format!("SetCapability {:#x}", caps));
+        }
+
+        #[zbus(signal)]
+        async fn commit_string(ctxt: &SignalContext<'_>, str: &str) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        async fn update_formatted_preedit(ctxt: &SignalContext<'_>, text: Vec<(String, i32)>, cursor_pos: i32) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        async fn commit_string_with_cursor(ctxt: &SignalContext<'_>, text: &str, cursor: u32) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        async fn update_preedit(ctxt: &SignalContext<'_>, str: &str, cursor_pos: i32) -> zbus::Result<()>;
+    }
+
+    /// Serves the fake Fcitx on one end of a socket pair and returns a client connection to
+    /// it, plus the server connection for emitting signals and the input context's call log.
+    async fn fake_fcitx() -> (Connection, Connection, CallLog) {
+        fake_fcitx_with(true, false).await
+    }
+
+    /// Like `fake_fcitx`, with or without the D-Bus frontend's InputMethod1, and optionally
+    /// with a FocusOut that never returns.
+    async fn fake_fcitx_with(frontend: bool, wedged: bool) -> (Connection, Connection, CallLog) {
+        let (server_socket, client_socket) = UnixStream::pair().unwrap();
+        let calls = CallLog::default();
+        let mut server = Builder::unix_stream(server_socket)
+            .server(Guid::generate())
+            .unwrap()
+            .p2p()
+            .serve_at("/org/fcitx/Fcitx5", FakeController)
+            .unwrap()
+            .serve_at(IC_PATH, FakeInputContext { calls: Arc::clone(&calls), wedged })
+            .unwrap();
+        if frontend {
+            server = server.serve_at(FCITX5_INPUT_METHOD_PATH, FakeInputMethod).unwrap();
+        }
+        let server = server.build();
+        let client = Builder::unix_stream(client_socket).p2p().build();
+        let (server, client) = tokio::try_join!(server, client).unwrap();
+        (server, client, calls)
+    }
+
+    /// Waits for the next update, failing the test instead of hanging.
+    async fn next_update<S: Stream<Item = Result<FcitxUpdate, FepError>> + Unpin>(updates: &mut S) -> FcitxUpdate {
+        tokio::time::timeout(Duration::from_secs(5), updates.next())
+            .await
+            .expect("timed out waiting for an update")
+            .expect("update stream ended")
+            .expect("update stream failed")
+    }
+
+    #[tokio::test]
+    async fn connects_and_focuses_the_input_context() {
+        let (_server, connection, calls) = fake_fcitx().await;
+        let client = FcitxClient::connect_on(connection, "test").await.unwrap();
+
+        assert_eq!(client.ic_path().map(|path| path.as_str()), Some(IC_PATH));
+        assert!(client.is_focused());
+        assert_eq!(client.initial_im().map(|im| (im.name.as_str(), im.active)), Some(("mozc", Some(true))));
+        assert_eq!(*calls.lock().unwrap(), ["FocusIn"]);
+    }
+
+    #[tokio::test]
+    async fn forwards_keys_and_receives_the_commit() {
+        let (_server, connection, calls) = fake_fcitx().await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut updates = Box::pin(client.receive_updates().await.unwrap());
+
+        assert!(!client.forward_key_event(0x62, 0, 0, false).await.unwrap());
+        assert!(client.forward_key_event(COMMIT_KEY, 0, 4, false).await.unwrap());
+        assert!(matches!(next_update(&mut updates).await, FcitxUpdate::CommitString(text) if text == "あ"));
+        assert_eq!(
+            calls.lock().unwrap()[1..],
+            ["ProcessKeyEvent 0x62 0 false", "ProcessKeyEvent 0x61 4 false"]
+        );
+    }
+
+    #[tokio::test]
+    async fn unfocused_context_gets_no_keys() {
+        let (_server, connection, calls) = fake_fcitx().await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+
+        client.focus_out().await.unwrap();
+        assert!(!client.forward_key_event(COMMIT_KEY, 0, 0, false).await.unwrap());
+        assert_eq!(*calls.lock().unwrap(), ["FocusIn", "FocusOut"]);
+    }
+
+    #[tokio::test]
+    async fn pages_candidates_with_the_paging_methods_when_fcitx_has_them() {
+        let (_server, connection, calls) = fake_fcitx().await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+
+        assert!(client.supports_paging());
+        client.next_page().await.unwrap();
+        client.prev_page().await.unwrap();
+        assert_eq!(calls.lock().unwrap()[1..], ["NextPage", "PrevPage"]);
+    }
+
+    #[test]
+    fn detects_paging_only_with_both_methods() {
+        let iface = r#"<interface name="org.fcitx.Fcitx.InputContext1"><method name="PrevPage"></method></interface>"#;
+        assert!(!ic_features(iface).paging);
+        let iface = r#"<method name="PrevPage"></method><method name="NextPage"></method>"#;
+        assert!(ic_features(iface).paging);
+    }
+
+    #[tokio::test]
+    async fn disconnect_gives_up_on_a_focus_out_that_never_returns() {
+        let (_server, connection, calls) = fake_fcitx_with(true, true).await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+
+        let timeout = Duration::from_millis(100);
+        let started = tokio::time::Instant::now();
+        tokio::time::timeout(Duration::from_secs(5), client.disconnect_with_timeout(timeout))
+            .await
+            .expect("disconnect hung");
+        assert!(started.elapsed() >= timeout);
+        assert!(!client.is_focused());
+        assert!(client.ic_path().is_none());
+        assert_eq!(*calls.lock().unwrap(), ["FocusIn", "FocusOut"]);
+    }
+
+    #[tokio::test]
+    async fn reconnected_client_receives_updates_on_the_same_context_path() {
+        // The lost connection: its client is torn down before the new one connects
+        let (old_server, connection, _calls) = fake_fcitx().await;
+        let old_client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let old_path = old_client.ic_path().cloned();
+        let mut old_updates = old_client.receive_updates().await.unwrap();
+        old_server.close().await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_secs(5), old_updates.next()).await.unwrap().is_none());
+        drop(old_client);
+
+        // Fcitx hands out the same path again; the new subscription is still the new context's
+        let (server, connection, calls) = fake_fcitx().await;
+        let client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        assert_eq!(client.ic_path().cloned(), old_path);
+        let mut updates = client.receive_updates().await.unwrap();
+        FakeInputContext::commit_string(&SignalContext::new(&server, IC_PATH).unwrap(), "new").await.unwrap();
+        assert!(matches!(next_update(&mut updates).await, FcitxUpdate::CommitString(text) if text == "new"));
+        assert_eq!(*calls.lock().unwrap(), ["FocusIn"]);
+    }
+
+    #[tokio::test]
+    async fn key_timestamps_are_nonzero_and_increasing() {
+        let (_server, connection, _calls) = fake_fcitx().await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+
+        let first = client.key_time();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let second = client.key_time();
+        assert!(first > 0);
+        assert!(second > first, "{} then {}", first, second);
+        client.set_key_timestamps(false);
+        assert_eq!(client.key_time(), 0);
+    }
+
+    #[tokio::test]
+    async fn tracks_focus_and_skips_redundant_calls() {
+        let (_server, connection, calls) = fake_fcitx().await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+
+        assert!(client.is_focused());
+        client.focus_in().await.unwrap(); // Already focused: no call
+        client.focus_out().await.unwrap();
+        assert!(!client.is_focused());
+        client.focus_out().await.unwrap();
+        client.focus_in().await.unwrap();
+        assert!(client.is_focused());
+        assert_eq!(*calls.lock().unwrap(), ["FocusIn", "FocusOut", "FocusIn"]);
+    }
+
+    #[tokio::test]
+    async fn clean_shutdown_sends_focus_out_exactly_once() {
+        let (_server, connection, calls) = fake_fcitx().await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut state = AppState::new();
+
+        // The exit sequence of `event_loop::shutdown`, then a stray second disconnect and the drop
+        client.settle_preedit(FocusLossPolicy::Commit, &mut state).await.unwrap();
+        client.disconnect_with_timeout(Duration::from_secs(5)).await;
+        client.disconnect().await;
+        drop(client);
+        assert_eq!(*calls.lock().unwrap(), ["FocusIn", "FocusOut"]);
+    }
+
+    #[tokio::test]
+    async fn focus_toggles_coalesce_to_the_final_state() {
+        let (_server, connection, calls) = fake_fcitx().await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut state = AppState::new();
+
+        // Out and straight back in: nothing pending, nothing sent
+        client.request_focus(false);
+        client.request_focus(true);
+        assert_eq!(client.focus_request_deadline(), None);
+        client.apply_focus_request(FocusLossPolicy::Engine, &mut state).await.unwrap();
+        assert!(client.is_focused());
+        assert_eq!(*calls.lock().unwrap(), ["FocusIn"]);
+
+        // An odd number of toggles ends unfocused: exactly one FocusOut
+        for focused in [false, true, false, true, false] {
+            client.request_focus(focused);
+        }
+        assert!(client.focus_request_deadline().is_some());
+        client.apply_focus_request(FocusLossPolicy::Engine, &mut state).await.unwrap();
+        client.apply_focus_request(FocusLossPolicy::Engine, &mut state).await.unwrap();
+        assert!(!client.is_focused());
+        assert_eq!(*calls.lock().unwrap(), ["FocusIn", "FocusOut"]);
+    }
+
+    #[tokio::test]
+    async fn injected_updates_reach_every_subscription() {
+        let (_server, connection, _calls) = fake_fcitx().await;
+        let client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut first = client.receive_updates().await.unwrap();
+        let mut second = client.receive_updates().await.unwrap();
+
+        client.update_injector().send(FcitxUpdate::CommitString("x".to_string())).unwrap();
+        for updates in [&mut first, &mut second] {
+            assert!(matches!(next_update(updates).await, FcitxUpdate::CommitString(text) if text == "x"));
+        }
+    }
+
+    #[tokio::test]
+    async fn update_stream_ends_when_the_connection_closes() {
+        let (server, connection, _calls) = fake_fcitx().await;
+        let client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut updates = client.receive_updates().await.unwrap();
+        let _injector = client.update_injector(); // Must not keep the stream alive
+
+        server.close().await.unwrap();
+        let end = tokio::time::timeout(Duration::from_secs(5), updates.next()).await;
+        assert!(matches!(end, Ok(None)), "expected the stream to end, got {:?}", end);
+    }
+
+    /// Places the popup the way the event loop does after each update: only while the state
+    /// wants it, at the preedit cursor's cell (8x16 pixel cells, one row).
+    async fn place_popup(client: &mut FcitxClient<'_>, state: &AppState) {
+        if state.wants_cursor_rect() {
+            let before_cursor: String = state.preedit_string.chars().take(state.preedit_cursor_pos).collect();
+            let x = crate::width::display_width(&before_cursor) as i32 * 8;
+            client.set_cursor_rect(x, 0, 0, 16).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn cursor_rect_is_sent_once_per_cursor_move_while_composing() {
+        let (server, connection, calls) = fake_fcitx().await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut updates = Box::pin(client.receive_updates().await.unwrap());
+        let ctxt = SignalContext::new(&server, IC_PATH).unwrap();
+        let mut state = AppState::new();
+
+        // Plain typing the engine commits directly: no preedit, so no popup to place
+        for _ in 0..10 {
+            client.forward_key_event(COMMIT_KEY, 0, 0, false).await.unwrap();
+            state.apply_update(next_update(&mut updates).await);
+            place_popup(&mut client, &state).await;
+        }
+        assert_eq!(state.take_commit(), "あ".repeat(10));
+
+        // Composing "かん": the preedit after each key, with its cursor in bytes. Re-sending an
+        // unchanged preedit (e.g. while candidates change) doesn't move the cursor.
+        let preedits = [("k", 1), ("か", 3), ("かn", 4), ("かん", 6), ("かん", 6), ("かん", 3), ("かん", 3)];
+        for (text, cursor) in preedits {
+            FakeInputContext::update_preedit(&ctxt, text, cursor).await.unwrap();
+            state.apply_update(next_update(&mut updates).await);
+            place_popup(&mut client, &state).await;
+        }
+        FakeInputContext::commit_string(&ctxt, "かん").await.unwrap();
+        FakeInputContext::update_preedit(&ctxt, "", 0).await.unwrap();
+        for _ in 0..2 {
+            state.apply_update(next_update(&mut updates).await);
+            place_popup(&mut client, &state).await;
+        }
+
+        // Cursor cells 1, 2, 3, 4, 2: five moves, five calls
+        let rects: Vec<String> = calls.lock().unwrap().iter().filter(|call| call.starts_with("SetCursorRect")).cloned().collect();
+        assert_eq!(rects, ["SetCursorRect 8 0", "SetCursorRect 16 0", "SetCursorRect 24 0", "SetCursorRect 32 0", "SetCursorRect 16 0"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_a_commit_in_order_among_many_preedits() {
+        let (server, connection, _calls) = fake_fcitx().await;
+        let client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut updates = client.receive_updates().await.unwrap();
+
+        // 50 preedits with the commit after the 25th, then the empty preedit that ends it all
+        let ctxt = SignalContext::new(&server, IC_PATH).unwrap();
+        for i in 0..50 {
+            FakeInputContext::update_formatted_preedit(&ctxt, vec![(format!("p{}", i), 0)], 2).await.unwrap();
+            if i == 24 {
+                FakeInputContext::commit_string(&ctxt, "確定").await.unwrap();
+            }
+        }
+        FakeInputContext::update_formatted_preedit(&ctxt, vec![], 0).await.unwrap();
+
+        let mut state = AppState::new();
+        for i in 0..52 {
+            let update = next_update(&mut updates).await;
+            assert_eq!(matches!(update, FcitxUpdate::CommitString(_)), i == 25, "update {}: {:?}", i, update);
+            state.apply_update(update);
+        }
+        assert_eq!(state.take_commit(), "確定");
+        assert_eq!(state.preedit_string, "");
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_update_preedit_until_a_formatted_one_arrives() {
+        let (server, connection, _calls) = fake_fcitx().await;
+        let client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut updates = client.receive_updates().await.unwrap();
+
+        let ctxt = SignalContext::new(&server, IC_PATH).unwrap();
+        FakeInputContext::update_preedit(&ctxt, "plain", 3).await.unwrap();
+        FakeInputContext::update_formatted_preedit(&ctxt, vec![("form".to_string(), 0), ("atted".to_string(), 8)], 4).await.unwrap();
+        // From now on UpdatePreedit only repeats the formatted preedit and is dropped
+        FakeInputContext::update_preedit(&ctxt, "formatted", 4).await.unwrap();
+        FakeInputContext::commit_string(&ctxt, "end").await.unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(match next_update(&mut updates).await {
+                FcitxUpdate::UpdatePreedit { text, cursor_pos } => format!("preedit {} {}", text, cursor_pos),
+                FcitxUpdate::CommitString(text) => format!("commit {}", text),
+                update => panic!("unexpected update {:?}", update),
+            });
+        }
+        assert_eq!(received, ["preedit plain 3", "preedit formatted 4", "commit end"]);
+    }
+
+    #[tokio::test]
+    async fn resubscribes_on_the_same_connection() {
+        let (server, connection, calls) = fake_fcitx().await;
+        let client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        drop(client.receive_updates().await.unwrap()); // The first stream is gone
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+        let mut updates = client.resubscribe_updates(deadline, Duration::from_millis(10)).await.unwrap();
+        FakeInputContext::commit_string(&SignalContext::new(&server, IC_PATH).unwrap(), "again").await.unwrap();
+        assert!(matches!(next_update(&mut updates).await, FcitxUpdate::CommitString(text) if text == "again"));
+        // Same input context: nothing was created or focused again
+        assert_eq!(*calls.lock().unwrap(), ["FocusIn"]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_resubscribing_after_the_grace_period() {
+        let (server, connection, _calls) = fake_fcitx().await;
+        let client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut updates = client.receive_updates().await.unwrap();
+
+        server.close().await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_secs(5), updates.next()).await.unwrap().is_none());
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(50);
+        let error = client.resubscribe_updates(deadline, Duration::from_millis(10)).await.err().unwrap();
+        assert!(tokio::time::Instant::now() >= deadline);
+        // A full reconnect, not the wait for the session bus
+        assert!(matches!(error, FepError::UpdatesEnded(_)), "{:?}", error);
+        assert!(error.is_connection_error());
+    }
+
+    #[tokio::test]
+    async fn zero_grace_gives_up_at_once() {
+        let (_server, connection, _calls) = fake_fcitx().await;
+        let client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let result = client.resubscribe_updates(tokio::time::Instant::now(), Duration::from_millis(10)).await;
+        assert!(matches!(result, Err(FepError::UpdatesEnded(_))));
+    }
+
+    #[tokio::test]
+    async fn advertises_commit_string_with_cursor_when_fcitx_has_it() {
+        let (server, connection, calls) = fake_fcitx().await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut updates = client.receive_updates().await.unwrap();
+
+        client.enable_client_side_ui().await.unwrap();
+        assert_eq!(calls.lock().unwrap()[1], format!("SetCapability {:#x}", (1u64 << 1) | (1 << 4) | (1 << 6) | (1 << 39) | (1 << 41)));
+        FakeInputContext::commit_string_with_cursor(&SignalContext::new(&server, IC_PATH).unwrap(), "()", 1).await.unwrap();
+        assert!(matches!(
+            next_update(&mut updates).await,
+            FcitxUpdate::CommitStringWithCursor { text, cursor: 1 } if text == "()"
+        ));
+    }
+
+    #[tokio::test]
+    async fn fep_commits_reach_fcitx_as_surrounding_text() {
+        let (_server, connection, calls) = fake_fcitx().await;
+        let mut client = FcitxClient::connect_on(connection, "test").await.unwrap();
+        let mut state = AppState::new();
+
+        client.enable_client_side_ui().await.unwrap();
+        client.commit_text("履歴", &mut state).await.unwrap();
+        let calls = calls.lock().unwrap();
+        let caps = calls[1].strip_prefix("SetCapability 0x").map(|hex| u64::from_str_radix(hex, 16).unwrap()).unwrap();
+        assert_ne!(caps & CAPABILITY_SURROUNDING_TEXT, 0, "{:#x}", caps);
+        assert_eq!(calls[2], "SetSurroundingText 履歴 2");
+        assert_eq!(state.take_commit(), "履歴");
+    }
+
+    #[tokio::test]
+    async fn reports_a_disabled_frontend() {
+        let (_server, connection, calls) = fake_fcitx_with(false, false).await;
+        let error = FcitxClient::connect_on(connection, "test").await.err().unwrap();
+        assert!(matches!(&error, FepError::FrontendUnavailable(msg) if msg.contains(FCITX5_IFACE_INPUT_METHOD)), "{:?}", error);
+        assert!(!error.is_connection_error());
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn self_test_passes_against_a_complete_fcitx() {
+        let (_server, connection, calls) = fake_fcitx().await;
+        let checks = self_test_on(connection, "test").await;
+        let names: Vec<_> = checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, ["D-Bus frontend", "input context", FCITX5_IFACE_IC, "input methods"]);
+        assert!(checks.iter().all(|check| check.outcome.is_ok()), "{:?}", checks);
+        // The context is released again
+        assert_eq!(*calls.lock().unwrap(), ["FocusIn", "FocusOut"]);
+    }
+
+    #[tokio::test]
+    async fn self_test_stops_at_a_disabled_frontend() {
+        let (_server, connection, _calls) = fake_fcitx_with(false, false).await;
+        let checks = self_test_on(connection, "test").await;
+        assert_eq!(checks.len(), 1);
+        assert!(matches!(&checks[0].outcome, Err(problem) if problem.contains("D-Bus frontend addon")), "{:?}", checks);
+    }
+}
@@ -0,0 +1,195 @@
+// src/history.rs
+// Persistent history of committed strings, with recall (enabled with --history).
+
+use crate::error::FepError;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// Default maximum number of history entries kept.
+pub const DEFAULT_HISTORY_SIZE: usize = 500;
+
+/// Committed strings, newest last, mirrored to a file under the XDG data directory.
+///
+/// Each entry is one line, with `\` and newlines escaped. New entries are appended with a
+/// single `write` on a file opened in append mode. The file is compacted to `max_entries`
+/// on load once it has grown to twice that size, rewriting it in place. Loading (with the
+/// compaction) and each append hold an exclusive `flock` on the file, so concurrent FEP
+/// instances sharing it neither interleave partial lines nor lose entries appended while
+/// another instance compacts.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    entries: VecDeque<String>,
+    max_entries: usize,
+    recall_index: Option<usize>, // Entry currently shown for recall, counted from the newest
+}
+
+impl History {
+    /// Loads the history from the default location.
+    pub fn load(max_entries: usize) -> Result<Self, FepError> {
+        Self::load_from(default_path()?, max_entries)
+    }
+
+    /// Loads the history from `path`, creating its directory if needed.
+    pub fn load_from(path: PathBuf, max_entries: usize) -> Result<Self, FepError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        file.lock()?; // Released when `file` is dropped
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let lines: Vec<&str> = contents.lines().filter(|line| !line.is_empty()).collect();
+        let entries: VecDeque<String> = lines
+            .iter()
+            .skip(lines.len().saturating_sub(max_entries))
+            .map(|line| unescape_line(line))
+            .collect();
+
+        let history = History { path, entries, max_entries, recall_index: None };
+        if lines.len() >= max_entries.saturating_mul(2).max(1) {
+            history.compact(&mut file)?;
+        }
+        Ok(history)
+    }
+
+    /// Records a committed string, in memory and in the history file.
+    pub fn record(&mut self, text: &str) -> Result<(), FepError> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.entries.push_back(text.to_string());
+        while self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+        self.recall_index = None;
+
+        let line = format!("{}\n", escape_line(text));
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.lock()?; // Waits for a compaction by another instance; released on drop
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Cycles to the next older entry (wrapping to the newest) and returns it.
+    pub fn recall_next(&mut self) -> Option<&str> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let index = match self.recall_index {
+            Some(index) if index + 1 < self.entries.len() => index + 1,
+            _ => 0,
+        };
+        self.recall_index = Some(index);
+        self.entries.get(self.entries.len() - 1 - index).map(String::as_str)
+    }
+
+    /// Ends a recall, so the next `recall_next` starts from the newest entry again.
+    pub fn reset_recall(&mut self) {
+        self.recall_index = None;
+    }
+
+    /// Rewrites `file` (the locked history file) with only the entries kept in memory.
+    /// Done in place: replacing the file by renaming would leave other instances appending
+    /// to (and locking) the old one.
+    fn compact(&self, file: &mut File) -> Result<(), FepError> {
+        let contents: String = self.entries.iter().map(|entry| format!("{}\n", escape_line(entry))).collect();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Returns `$XDG_DATA_HOME/fcitx5-fep-rust/history`, falling back to `~/.local/share`.
+fn default_path() -> Result<PathBuf, FepError> {
+    let data_home = match std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let home = std::env::var_os("HOME")
+                .ok_or_else(|| FepError::Config("Neither XDG_DATA_HOME nor HOME is set".to_string()))?;
+            PathBuf::from(home).join(".local/share")
+        }
+    };
+    Ok(data_home.join("fcitx5-fep-rust").join("history"))
+}
+
+fn escape_line(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape_line(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some('n') => '\n',
+            Some(other) => other,
+            None => '\\',
+        });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// A fresh history file path under the temporary directory.
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fcitx5-fep-rust-history-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("history")
+    }
+
+    #[test]
+    fn records_and_reloads_escaped_entries() {
+        let path = temp_path("reload");
+        let mut history = History::load_from(path.clone(), 10).unwrap();
+        history.record("one").unwrap();
+        history.record("two\\lines\nhere").unwrap();
+        history.record("").unwrap(); // Ignored
+
+        let mut history = History::load_from(path, 10).unwrap();
+        assert_eq!(history.recall_next(), Some("two\\lines\nhere"));
+        assert_eq!(history.recall_next(), Some("one"));
+        assert_eq!(history.recall_next(), Some("two\\lines\nhere"));
+    }
+
+    #[test]
+    fn compacts_to_the_newest_entries_on_load() {
+        let path = temp_path("compact");
+        let mut history = History::load_from(path.clone(), 2).unwrap();
+        for entry in ["a", "b", "c", "d"] {
+            history.record(entry).unwrap();
+        }
+        History::load_from(path.clone(), 2).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "c\nd\n");
+    }
+
+    #[test]
+    fn append_waits_for_the_lock() {
+        let path = temp_path("lock");
+        let mut history = History::load_from(path.clone(), 10).unwrap();
+
+        // Another instance holds the lock (e.g. while compacting) and writes its own entry
+        let other = OpenOptions::new().append(true).open(&path).unwrap();
+        other.lock().unwrap();
+        let appender = std::thread::spawn(move || history.record("mine").unwrap());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!appender.is_finished(), "appended while the file was locked");
+        (&other).write_all(b"theirs\n").unwrap();
+        other.unlock().unwrap();
+
+        appender.join().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "theirs\nmine\n");
+    }
+}
+
@@ -0,0 +1,67 @@
+// src/im_memory.rs
+// Persisted last-used input method per program (enabled with --remember-im).
+
+use crate::error::FepError;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Maps program identifiers (see `--program`) to the unique name of the input method last
+/// used with them, stored as `program<TAB>im` lines under the XDG config directory.
+#[derive(Debug)]
+pub struct ImMemory {
+    path: PathBuf,
+    map: BTreeMap<String, String>,
+}
+
+impl ImMemory {
+    /// Loads the map from the default location; a missing file is an empty map.
+    pub fn load() -> Result<Self, FepError> {
+        let path = default_path()?;
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let map = contents
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(program, im)| (program.to_string(), im.to_string()))
+            .collect();
+        Ok(ImMemory { path, map })
+    }
+
+    /// Returns the input method last used with `program`.
+    pub fn get(&self, program: &str) -> Option<&str> {
+        self.map.get(program).map(String::as_str)
+    }
+
+    /// Remembers `im` for `program`, rewriting the file if the entry changed.
+    pub fn set(&mut self, program: &str, im: &str) -> Result<(), FepError> {
+        if self.get(program) == Some(im) || program.contains(['\t', '\n']) {
+            return Ok(());
+        }
+        self.map.insert(program.to_string(), im.to_string());
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension(format!("tmp.{}", std::process::id()));
+        let contents: String = self.map.iter().map(|(program, im)| format!("{}\t{}\n", program, im)).collect();
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Returns `$XDG_CONFIG_HOME/fcitx5-fep-rust/im-memory`, falling back to `~/.config`.
+fn default_path() -> Result<PathBuf, FepError> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let home = std::env::var_os("HOME")
+                .ok_or_else(|| FepError::Config("Neither XDG_CONFIG_HOME nor HOME is set".to_string()))?;
+            PathBuf::from(home).join(".config")
+        }
+    };
+    Ok(config_home.join("fcitx5-fep-rust").join("im-memory"))
+}
@@ -0,0 +1,460 @@
+// src/keymap.rs
+// X11 keysym and modifier tables and the mapping from crossterm key events to Fcitx keys.
+// Runtime-agnostic: usable without tokio or zbus (the `core` feature).
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers, MediaKeyCode, ModifierKeyCode};
+
+// --- X11 Keysym Definitions ---
+// Provides constants for common key symbols used by Fcitx.
+#[allow(non_upper_case_globals)] // Keep the X11 names
+pub mod keysyms {
+    pub const XK_BackSpace: u32 = 0xff08;
+    pub const XK_Tab: u32 = 0xff09;
+    pub const XK_Return: u32 = 0xff0d; // Enter key
+    pub const XK_Escape: u32 = 0xff1b;
+    pub const XK_Left: u32 = 0xff51;
+    pub const XK_Up: u32 = 0xff52;
+    pub const XK_Right: u32 = 0xff53;
+    pub const XK_Down: u32 = 0xff54;
+    pub const XK_Page_Up: u32 = 0xff55; // Prior
+    pub const XK_Page_Down: u32 = 0xff56; // Next
+    pub const XK_Delete: u32 = 0xffff;
+    pub const XK_Home: u32 = 0xff50;
+    pub const XK_End: u32 = 0xff57;
+    pub const XK_Insert: u32 = 0xff63;
+    pub const XK_ISO_Left_Tab: u32 = 0xfe20; // Shift+Tab
+    pub const XK_Pause: u32 = 0xff13;
+    pub const XK_Scroll_Lock: u32 = 0xff14;
+    pub const XK_Print: u32 = 0xff61;
+    pub const XK_Menu: u32 = 0xff67;
+    pub const XK_Num_Lock: u32 = 0xff7f;
+    pub const XK_KP_Begin: u32 = 0xff9d;
+    pub const XK_F1: u32 = 0xffbe; // F1..F35 are consecutive
+    pub const XK_F35: u32 = 0xffe0;
+    pub const XK_Shift_L: u32 = 0xffe1;
+    pub const XK_Shift_R: u32 = 0xffe2;
+    pub const XK_Control_L: u32 = 0xffe3;
+    pub const XK_Control_R: u32 = 0xffe4;
+    pub const XK_Caps_Lock: u32 = 0xffe5;
+    pub const XK_Meta_L: u32 = 0xffe7;
+    pub const XK_Meta_R: u32 = 0xffe8;
+    pub const XK_Alt_L: u32 = 0xffe9;
+    pub const XK_Alt_R: u32 = 0xffea;
+    pub const XK_Super_L: u32 = 0xffeb;
+    pub const XK_Super_R: u32 = 0xffec;
+    pub const XK_Hyper_L: u32 = 0xffed;
+    pub const XK_Hyper_R: u32 = 0xffee;
+    pub const XK_ISO_Level3_Shift: u32 = 0xfe03;
+    pub const XK_ISO_Level5_Shift: u32 = 0xfe11;
+    pub const XF86XK_AudioLowerVolume: u32 = 0x1008ff11;
+    pub const XF86XK_AudioMute: u32 = 0x1008ff12;
+    pub const XF86XK_AudioRaiseVolume: u32 = 0x1008ff13;
+    pub const XF86XK_AudioPlay: u32 = 0x1008ff14;
+    pub const XF86XK_AudioStop: u32 = 0x1008ff15;
+    pub const XF86XK_AudioPrev: u32 = 0x1008ff16;
+    pub const XF86XK_AudioNext: u32 = 0x1008ff17;
+    pub const XF86XK_AudioRecord: u32 = 0x1008ff1c;
+    pub const XF86XK_AudioPause: u32 = 0x1008ff31;
+    pub const XF86XK_AudioRewind: u32 = 0x1008ff3e;
+    pub const XF86XK_AudioForward: u32 = 0x1008ff97;
+    pub const XK_space: u32 = 0x0020;
+    pub const XK_exclam: u32 = 0x0021; // !
+    pub const XK_quotedbl: u32 = 0x0022; // "
+    pub const XK_numbersign: u32 = 0x0023; // #
+    pub const XK_dollar: u32 = 0x0024; // $
+    pub const XK_percent: u32 = 0x0025; // %
+    pub const XK_ampersand: u32 = 0x0026; // &
+    pub const XK_apostrophe: u32 = 0x0027; // '
+    pub const XK_parenleft: u32 = 0x0028; // (
+    pub const XK_parenright: u32 = 0x0029; // )
+    pub const XK_asterisk: u32 = 0x002a; // *
+    pub const XK_plus: u32 = 0x002b; // +
+    pub const XK_comma: u32 = 0x002c; // ,
+    pub const XK_minus: u32 = 0x002d; // -
+    pub const XK_period: u32 = 0x002e; // .
+    pub const XK_slash: u32 = 0x002f; // /
+    pub const XK_0: u32 = 0x0030;
+    pub const XK_1: u32 = 0x0031;
+    pub const XK_2: u32 = 0x0032;
+    pub const XK_3: u32 = 0x0033;
+    pub const XK_4: u32 = 0x0034;
+    pub const XK_5: u32 = 0x0035;
+    pub const XK_6: u32 = 0x0036;
+    pub const XK_7: u32 = 0x0037;
+    pub const XK_8: u32 = 0x0038;
+    pub const XK_9: u32 = 0x0039;
+    pub const XK_colon: u32 = 0x003a; // :
+    pub const XK_semicolon: u32 = 0x003b; // ;
+    pub const XK_less: u32 = 0x003c; // <
+    pub const XK_equal: u32 = 0x003d; // =
+    pub const XK_greater: u32 = 0x003e; // >
+    pub const XK_question: u32 = 0x003f; // ?
+    pub const XK_at: u32 = 0x0040; // @
+    pub const XK_A: u32 = 0x0041;
+    pub const XK_B: u32 = 0x0042;
+    pub const XK_C: u32 = 0x0043;
+    pub const XK_D: u32 = 0x0044;
+    pub const XK_E: u32 = 0x0045;
+    pub const XK_F: u32 = 0x0046;
+    pub const XK_G: u32 = 0x0047;
+    pub const XK_H: u32 = 0x0048;
+    pub const XK_I: u32 = 0x0049;
+    pub const XK_J: u32 = 0x004a;
+    pub const XK_K: u32 = 0x004b;
+    pub const XK_L: u32 = 0x004c;
+    pub const XK_M: u32 = 0x004d;
+    pub const XK_N: u32 = 0x004e;
+    pub const XK_O: u32 = 0x004f;
+    pub const XK_P: u32 = 0x0050;
+    pub const XK_Q: u32 = 0x0051;
+    pub const XK_R: u32 = 0x0052;
+    pub const XK_S: u32 = 0x0053;
+    pub const XK_T: u32 = 0x0054;
+    pub const XK_U: u32 = 0x0055;
+    pub const XK_V: u32 = 0x0056;
+    pub const XK_W: u32 = 0x0057;
+    pub const XK_X: u32 = 0x0058;
+    pub const XK_Y: u32 = 0x0059;
+    pub const XK_Z: u32 = 0x005a;
+    pub const XK_bracketleft: u32 = 0x005b; // [
+    pub const XK_backslash: u32 = 0x005c; // \ -> Note: Often requires Shift on some layouts
+    pub const XK_bracketright: u32 = 0x005d; // ]
+    pub const XK_asciicircum: u32 = 0x005e; // ^
+    pub const XK_underscore: u32 = 0x005f; // _
+    pub const XK_grave: u32 = 0x0060; // `
+    pub const XK_a: u32 = 0x0061;
+    pub const XK_b: u32 = 0x0062;
+    pub const XK_c: u32 = 0x0063;
+    pub const XK_d: u32 = 0x0064;
+    pub const XK_e: u32 = 0x0065;
+    pub const XK_f: u32 = 0x0066;
+    pub const XK_g: u32 = 0x0067;
+    pub const XK_h: u32 = 0x0068;
+    pub const XK_i: u32 = 0x0069;
+    pub const XK_j: u32 = 0x006a;
+    pub const XK_k: u32 = 0x006b;
+    pub const XK_l: u32 = 0x006c;
+    pub const XK_m: u32 = 0x006d;
+    pub const XK_n: u32 = 0x006e;
+    pub const XK_o: u32 = 0x006f;
+    pub const XK_p: u32 = 0x0070;
+    pub const XK_q: u32 = 0x0071;
+    pub const XK_r: u32 = 0x0072;
+    pub const XK_s: u32 = 0x0073;
+    pub const XK_t: u32 = 0x0074;
+    pub const XK_u: u32 = 0x0075;
+    pub const XK_v: u32 = 0x0076;
+    pub const XK_w: u32 = 0x0077;
+    pub const XK_x: u32 = 0x0078;
+    pub const XK_y: u32 = 0x0079;
+    pub const XK_z: u32 = 0x007a;
+    pub const XK_braceleft: u32 = 0x007b; // {
+    pub const XK_bar: u32 = 0x007c; // |
+    pub const XK_braceright: u32 = 0x007d; // }
+    pub const XK_asciitilde: u32 = 0x007e; // ~
+    pub const XK_dead_grave: u32 = 0xfe50;
+    pub const XK_dead_acute: u32 = 0xfe51;
+    pub const XK_dead_circumflex: u32 = 0xfe52;
+    pub const XK_dead_tilde: u32 = 0xfe53;
+    pub const XK_dead_diaeresis: u32 = 0xfe57;
+    pub const XK_dead_abovering: u32 = 0xfe58;
+    pub const XK_dead_cedilla: u32 = 0xfe5b;
+
+    /// Symbolic names (without the `XK_` prefix) for the keysyms above.
+    pub const NAMES: &[(&str, u32)] = &[
+        ("BackSpace", XK_BackSpace), ("Tab", XK_Tab), ("Return", XK_Return), ("Escape", XK_Escape),
+        ("Left", XK_Left), ("Up", XK_Up), ("Right", XK_Right), ("Down", XK_Down), ("Delete", XK_Delete),
+        ("Page_Up", XK_Page_Up), ("Prior", XK_Page_Up), ("Page_Down", XK_Page_Down), ("Next", XK_Page_Down),
+        ("Home", XK_Home), ("End", XK_End), ("Insert", XK_Insert), ("ISO_Left_Tab", XK_ISO_Left_Tab),
+        ("Pause", XK_Pause), ("Scroll_Lock", XK_Scroll_Lock), ("Print", XK_Print), ("Menu", XK_Menu),
+        ("Num_Lock", XK_Num_Lock), ("Caps_Lock", XK_Caps_Lock),
+        ("space", XK_space), ("exclam", XK_exclam), ("quotedbl", XK_quotedbl),
+        ("numbersign", XK_numbersign), ("dollar", XK_dollar), ("percent", XK_percent),
+        ("ampersand", XK_ampersand), ("apostrophe", XK_apostrophe), ("parenleft", XK_parenleft),
+        ("parenright", XK_parenright), ("asterisk", XK_asterisk), ("plus", XK_plus),
+        ("comma", XK_comma), ("minus", XK_minus), ("period", XK_period), ("slash", XK_slash),
+        ("colon", XK_colon), ("semicolon", XK_semicolon), ("less", XK_less), ("equal", XK_equal),
+        ("greater", XK_greater), ("question", XK_question), ("at", XK_at),
+        ("bracketleft", XK_bracketleft), ("backslash", XK_backslash),
+        ("bracketright", XK_bracketright), ("asciicircum", XK_asciicircum),
+        ("underscore", XK_underscore), ("grave", XK_grave), ("braceleft", XK_braceleft),
+        ("bar", XK_bar), ("braceright", XK_braceright), ("asciitilde", XK_asciitilde),
+        ("dead_grave", XK_dead_grave), ("dead_acute", XK_dead_acute),
+        ("dead_circumflex", XK_dead_circumflex), ("dead_tilde", XK_dead_tilde),
+        ("dead_diaeresis", XK_dead_diaeresis), ("dead_abovering", XK_dead_abovering),
+        ("dead_cedilla", XK_dead_cedilla),
+    ];
+
+    /// Looks up a keysym by its symbolic name (e.g. "Return", "a", "7").
+    /// Single ASCII letters and digits map to their own codepoint, like their XK_ constants.
+    pub fn from_name(name: &str) -> Option<u32> {
+        let name = name.strip_prefix("XK_").unwrap_or(name);
+        if let Some(&(_, keysym)) = NAMES.iter().find(|(n, _)| *n == name) {
+            return Some(keysym);
+        }
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphanumeric() => Some(c as u32),
+            _ => None,
+        }
+    }
+
+    /// Returns the character typed by a Latin-1 or Unicode (0x01000000 + codepoint) keysym.
+    /// Keysyms encoding a surrogate or a value beyond U+10FFFF, and all other keysyms, give `None`.
+    pub fn to_char(keysym: u32) -> Option<char> {
+        match keysym {
+            0x20..=0x7e | 0xa0..=0xff => char::from_u32(keysym),
+            0x0100_0000..=0x01ff_ffff => char::from_u32(keysym - 0x0100_0000),
+            _ => None,
+        }
+    }
+}
+
+// --- X11 Modifier Masks ---
+// Provides constants for modifier key states.
+#[allow(non_upper_case_globals)]
+pub mod masks {
+    pub const ShiftMask: u32 = 1 << 0;
+    pub const LockMask: u32 = 1 << 1; // Caps Lock
+    pub const ControlMask: u32 = 1 << 2;
+    pub const Mod1Mask: u32 = 1 << 3; // Alt key (usually)
+    pub const Mod2Mask: u32 = 1 << 4; // Num Lock key (usually)
+    pub const Mod3Mask: u32 = 1 << 5; // Often unused
+    pub const Mod4Mask: u32 = 1 << 6; // Super/Win key (usually)
+    pub const Mod5Mask: u32 = 1 << 7; // Often ISO_Level3_Shift (AltGr)
+}
+
+/// Maps a non-ASCII character to its X11 keysym.
+///
+/// Terminals with dead-key support (xterm, VTE-based terminals, kitty, alacritty, wezterm)
+/// compose the sequence themselves and deliver the finished character (e.g. 'é'), which is
+/// sent with the standard encoding: Latin-1 characters map to the keysym of the same value
+/// (so 'é' is XK_eacute and a standalone '´' is XK_acute), everything else uses the Unicode
+/// keysym range (0x01000000 + codepoint).
+/// Terminals that pass a combining mark through on its own get it mapped to the matching
+/// X11 dead key, so Fcitx's compose support can combine it with the following key.
+/// Returns `None` (the key is ignored) if the result doesn't round-trip to a valid character.
+fn unicode_keysym(c: char) -> Option<u32> {
+    let keysym = match c {
+        '\u{0300}' => return Some(keysyms::XK_dead_grave),
+        '\u{0301}' => return Some(keysyms::XK_dead_acute),
+        '\u{0302}' => return Some(keysyms::XK_dead_circumflex),
+        '\u{0303}' => return Some(keysyms::XK_dead_tilde),
+        '\u{0308}' => return Some(keysyms::XK_dead_diaeresis),
+        '\u{030A}' => return Some(keysyms::XK_dead_abovering),
+        '\u{0327}' => return Some(keysyms::XK_dead_cedilla),
+        _ if (c as u32) < 0x100 => c as u32,
+        _ => 0x0100_0000 | c as u32,
+    };
+    // Control characters below 0x100 have no character keysym; pass them through unchanged.
+    if keysym < 0x100 || keysyms::to_char(keysym) == Some(c) {
+        Some(keysym)
+    } else {
+        None
+    }
+}
+
+/// Maps crossterm modifiers to an X11 state mask.
+fn modifier_state(modifiers: KeyModifiers) -> u32 {
+    let mut state = 0u32;
+    if modifiers.contains(KeyModifiers::SHIFT) {
+        state |= masks::ShiftMask;
+    }
+    if modifiers.contains(KeyModifiers::CONTROL) {
+        state |= masks::ControlMask;
+    }
+    if modifiers.contains(KeyModifiers::ALT) {
+        state |= masks::Mod1Mask; // Assuming Alt is Mod1
+    }
+    // Note: Handling SUPER (Mod4Mask), AltGr (Mod5Mask), CapsLock, NumLock
+    // would require more complex state tracking or platform APIs.
+    state
+}
+
+/// Maps a crossterm KeyEvent to Fcitx compatible (keysym, keycode, state).
+/// Returns None if the key event should not be forwarded to Fcitx; see
+/// `map_unmapped_key` for the keys this leaves out.
+pub fn map_key_event_to_fcitx(key_event: &KeyEvent) -> Option<(u32, u32, u32)> {
+    let state = modifier_state(key_event.modifiers);
+
+    // Map crossterm KeyCode to X11 Keysym
+    let keysym = match key_event.code {
+        // --- Character Keys ---
+        // crossterm provides the character considering Shift state. Printable ASCII keysyms
+        // (XK_space..XK_asciitilde) equal their code points, so these need no table lookup.
+        KeyCode::Char(c) => match c {
+            ' '..='~' => c as u32,
+            // For other characters (e.g., non-ASCII, dead-key results), use the X11 Unicode encoding.
+            _ => unicode_keysym(c)?,
+        },
+
+        // --- Special Keys ---
+        KeyCode::Backspace => keysyms::XK_BackSpace,
+        KeyCode::Enter => keysyms::XK_Return,
+        KeyCode::Left => keysyms::XK_Left,
+        KeyCode::Right => keysyms::XK_Right,
+        KeyCode::Up => keysyms::XK_Up,
+        KeyCode::Down => keysyms::XK_Down,
+        KeyCode::Tab => keysyms::XK_Tab,
+        KeyCode::Delete => keysyms::XK_Delete,
+        KeyCode::Esc => keysyms::XK_Escape,
+        KeyCode::PageUp => keysyms::XK_Page_Up,
+        KeyCode::PageDown => keysyms::XK_Page_Down,
+        KeyCode::Home => keysyms::XK_Home,
+        KeyCode::End => keysyms::XK_End,
+        KeyCode::Insert => keysyms::XK_Insert,
+        KeyCode::BackTab => keysyms::XK_ISO_Left_Tab,
+
+        // Function, lock, media and modifier keys only go to Fcitx on request (map_unmapped_key)
+        _ => return None,
+    };
+
+    // Use 0 as a placeholder keycode. Fcitx generally works well with keysym + state.
+    let keycode = 0;
+
+    Some((keysym, keycode, state))
+}
+
+/// Maps the keys `map_key_event_to_fcitx` leaves out (function, lock, media and modifier
+/// keys), which are forwarded only with `--unmapped-keys forward`. Returns None for keys
+/// without any X11 keysym (e.g. `Null`, media Reverse).
+pub fn map_unmapped_key(key_event: &KeyEvent) -> Option<(u32, u32, u32)> {
+    let keysym = match key_event.code {
+        KeyCode::F(n @ 1..=35) => keysyms::XK_F1 + (n as u32 - 1),
+        KeyCode::Menu => keysyms::XK_Menu,
+        KeyCode::PrintScreen => keysyms::XK_Print,
+        KeyCode::ScrollLock => keysyms::XK_Scroll_Lock,
+        KeyCode::Pause => keysyms::XK_Pause,
+        KeyCode::CapsLock => keysyms::XK_Caps_Lock,
+        KeyCode::NumLock => keysyms::XK_Num_Lock,
+        KeyCode::KeypadBegin => keysyms::XK_KP_Begin,
+        KeyCode::Media(media) => match media {
+            MediaKeyCode::Play | MediaKeyCode::PlayPause => keysyms::XF86XK_AudioPlay,
+            MediaKeyCode::Pause => keysyms::XF86XK_AudioPause,
+            MediaKeyCode::Stop => keysyms::XF86XK_AudioStop,
+            MediaKeyCode::FastForward => keysyms::XF86XK_AudioForward,
+            MediaKeyCode::Rewind => keysyms::XF86XK_AudioRewind,
+            MediaKeyCode::TrackNext => keysyms::XF86XK_AudioNext,
+            MediaKeyCode::TrackPrevious => keysyms::XF86XK_AudioPrev,
+            MediaKeyCode::Record => keysyms::XF86XK_AudioRecord,
+            MediaKeyCode::LowerVolume => keysyms::XF86XK_AudioLowerVolume,
+            MediaKeyCode::RaiseVolume => keysyms::XF86XK_AudioRaiseVolume,
+            MediaKeyCode::MuteVolume => keysyms::XF86XK_AudioMute,
+            MediaKeyCode::Reverse => return None,
+        },
+        KeyCode::Modifier(modifier) => match modifier {
+            ModifierKeyCode::LeftShift => keysyms::XK_Shift_L,
+            ModifierKeyCode::RightShift => keysyms::XK_Shift_R,
+            ModifierKeyCode::LeftControl => keysyms::XK_Control_L,
+            ModifierKeyCode::RightControl => keysyms::XK_Control_R,
+            ModifierKeyCode::LeftAlt => keysyms::XK_Alt_L,
+            ModifierKeyCode::RightAlt => keysyms::XK_Alt_R,
+            ModifierKeyCode::LeftSuper => keysyms::XK_Super_L,
+            ModifierKeyCode::RightSuper => keysyms::XK_Super_R,
+            ModifierKeyCode::LeftHyper => keysyms::XK_Hyper_L,
+            ModifierKeyCode::RightHyper => keysyms::XK_Hyper_R,
+            ModifierKeyCode::LeftMeta => keysyms::XK_Meta_L,
+            ModifierKeyCode::RightMeta => keysyms::XK_Meta_R,
+            ModifierKeyCode::IsoLevel3Shift => keysyms::XK_ISO_Level3_Shift,
+            ModifierKeyCode::IsoLevel5Shift => keysyms::XK_ISO_Level5_Shift,
+        },
+        _ => return None,
+    };
+    Some((keysym, 0, modifier_state(key_event.modifiers)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    fn keysym_of(c: char) -> Option<u32> {
+        map_key_event_to_fcitx(&key(KeyCode::Char(c), KeyModifiers::NONE)).map(|(keysym, _, _)| keysym)
+    }
+
+    #[test]
+    fn composed_latin1_character_uses_its_own_keysym() {
+        // What terminals with dead-key support deliver for ´ then e
+        assert_eq!(map_key_event_to_fcitx(&key(KeyCode::Char('é'), KeyModifiers::NONE)), Some((0xe9, 0, 0)));
+        assert_eq!(keysyms::to_char(0xe9), Some('é'));
+    }
+
+    #[test]
+    fn standalone_acute_accent_is_xk_acute() {
+        assert_eq!(keysym_of('´'), Some(0xb4));
+    }
+
+    #[test]
+    fn combining_marks_become_dead_keys() {
+        assert_eq!(keysym_of('\u{301}'), Some(keysyms::XK_dead_acute));
+        assert_eq!(keysym_of('\u{308}'), Some(keysyms::XK_dead_diaeresis));
+        assert_eq!(keysym_of('\u{327}'), Some(keysyms::XK_dead_cedilla));
+    }
+
+    #[test]
+    fn characters_beyond_latin1_use_unicode_keysyms() {
+        assert_eq!(keysym_of('あ'), Some(0x0100_3042));
+        assert_eq!(keysym_of('😀'), Some(0x0101_f600));
+        assert_eq!(keysyms::to_char(0x0100_3042), Some('あ'));
+    }
+
+    #[test]
+    fn surrogate_and_out_of_range_keysyms_give_no_character() {
+        // A Unicode keysym for the lone surrogate U+D800, the last surrogate and one past U+10FFFF
+        for keysym in [0x0100_d800, 0x0100_dfff, 0x0111_0000] {
+            assert_eq!(keysyms::to_char(keysym), None, "{:#x}", keysym);
+        }
+        assert_eq!(keysyms::to_char(0x0100_d7ff), Some('\u{d7ff}'));
+        assert_eq!(keysyms::to_char(0x0110_ffff), Some('\u{10ffff}'));
+        // Keysyms outside the Latin-1 and Unicode ranges don't type a character either
+        assert_eq!(keysyms::to_char(keysyms::XK_Return), None);
+        assert_eq!(keysyms::to_char(0x80), None);
+    }
+
+    #[test]
+    fn maps_special_keys_and_modifiers() {
+        let table = [
+            (KeyCode::Enter, keysyms::XK_Return),
+            (KeyCode::Backspace, keysyms::XK_BackSpace),
+            (KeyCode::Delete, keysyms::XK_Delete),
+            (KeyCode::Esc, keysyms::XK_Escape),
+            (KeyCode::Tab, keysyms::XK_Tab),
+            (KeyCode::BackTab, keysyms::XK_ISO_Left_Tab),
+            (KeyCode::Left, keysyms::XK_Left),
+            (KeyCode::Right, keysyms::XK_Right),
+            (KeyCode::Up, keysyms::XK_Up),
+            (KeyCode::Down, keysyms::XK_Down),
+            (KeyCode::Home, keysyms::XK_Home),
+            (KeyCode::End, keysyms::XK_End),
+            (KeyCode::PageUp, keysyms::XK_Page_Up),
+            (KeyCode::PageDown, keysyms::XK_Page_Down),
+            (KeyCode::Insert, keysyms::XK_Insert),
+        ];
+        for (code, keysym) in table {
+            assert_eq!(map_key_event_to_fcitx(&key(code, KeyModifiers::NONE)), Some((keysym, 0, 0)), "{:?}", code);
+        }
+        let ctrl_alt_shift = KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT;
+        assert_eq!(
+            map_key_event_to_fcitx(&key(KeyCode::Char('A'), ctrl_alt_shift)),
+            Some(('A' as u32, 0, masks::ShiftMask | masks::ControlMask | masks::Mod1Mask))
+        );
+    }
+
+    #[test]
+    fn function_media_and_modifier_keys_are_left_to_map_unmapped_key() {
+        for code in [KeyCode::F(5), KeyCode::Media(MediaKeyCode::Play), KeyCode::Modifier(ModifierKeyCode::LeftShift)] {
+            assert_eq!(map_key_event_to_fcitx(&key(code, KeyModifiers::NONE)), None, "{:?}", code);
+        }
+        assert_eq!(map_unmapped_key(&key(KeyCode::F(1), KeyModifiers::NONE)), Some((keysyms::XK_F1, 0, 0)));
+        assert_eq!(map_unmapped_key(&key(KeyCode::F(35), KeyModifiers::NONE)), Some((keysyms::XK_F35, 0, 0)));
+        assert_eq!(
+            map_unmapped_key(&key(KeyCode::Media(MediaKeyCode::PlayPause), KeyModifiers::NONE)),
+            Some((keysyms::XF86XK_AudioPlay, 0, 0))
+        );
+        assert_eq!(map_unmapped_key(&key(KeyCode::Media(MediaKeyCode::Reverse), KeyModifiers::NONE)), None);
+        assert_eq!(map_unmapped_key(&key(KeyCode::Char('a'), KeyModifiers::NONE)), None);
+    }
+}
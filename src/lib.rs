@@ -0,0 +1,29 @@
+// src/lib.rs
+// Reusable pieces of the FEP for other frontends. The `core` feature has the keysym tables
+// and key mapping, the line editor, the preedit/commit state and display width measurement,
+// with no async runtime or D-Bus dependencies. The `runtime` feature adds the async Fcitx
+// client, its errors and rendering into a caller-supplied target. The event loop and the
+// terminal frontend live in the binary.
+
+#[cfg(feature = "core")]
+pub mod keymap;
+#[cfg(feature = "core")]
+pub mod line_editor;
+#[cfg(feature = "core")]
+pub mod state;
+#[cfg(feature = "core")]
+pub mod width;
+
+#[cfg(feature = "runtime")]
+pub mod error;
+#[cfg(feature = "runtime")]
+pub mod fcitx;
+#[cfg(feature = "runtime")]
+pub mod render;
+
+#[cfg(feature = "runtime")]
+pub use error::FepError;
+#[cfg(feature = "runtime")]
+pub use fcitx::FcitxClient;
+#[cfg(feature = "runtime")]
+pub use render::{render_into, Area, CellStyle, RenderTarget};
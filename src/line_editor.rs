@@ -0,0 +1,162 @@
+// src/line_editor.rs
+// Optional local line editor over committed text (enabled with --line-edit).
+
+use crossterm::event::{KeyCode, KeyEvent};
+
+/// A line of committed text with a cursor, edited locally until it is submitted with Enter.
+/// Commits from Fcitx are inserted at the cursor; the editing keys only act while no
+/// preedit is active, so they still reach the engine during a composition.
+#[derive(Debug, Default, Clone)]
+pub struct LineEditor {
+    text: String,
+    cursor: usize, // Character index into `text`
+}
+
+/// What a key did to the line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LineEdit {
+    Edited,         // The line or its cursor changed
+    Submit(String), // Enter: the finished line, which is now cleared
+    NotHandled,     // Not a line-editing key
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Cursor position as a character index.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Moves the cursor to `cursor` (character index), clamped to the end of the line.
+    pub fn set_cursor(&mut self, cursor: usize) {
+        self.cursor = cursor.min(self.text.chars().count());
+    }
+
+    /// Inserts committed text at the cursor and moves the cursor past it.
+    pub fn insert(&mut self, text: &str) {
+        let byte_pos = self.byte_pos(self.cursor);
+        self.text.insert_str(byte_pos, text);
+        self.cursor += text.chars().count();
+    }
+
+    /// Applies an editing key (Home/End/Left/Right/Backspace/Delete/Enter) without modifiers.
+    pub fn handle_key(&mut self, key_event: &KeyEvent) -> LineEdit {
+        if !key_event.modifiers.is_empty() {
+            return LineEdit::NotHandled;
+        }
+        let len = self.text.chars().count();
+        match key_event.code {
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = len,
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(len),
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.text.remove(self.byte_pos(self.cursor));
+            }
+            KeyCode::Delete if self.cursor < len => {
+                self.text.remove(self.byte_pos(self.cursor));
+            }
+            KeyCode::Backspace | KeyCode::Delete => {} // Nothing to delete at this end
+            KeyCode::Enter => {
+                self.cursor = 0;
+                return LineEdit::Submit(std::mem::take(&mut self.text));
+            }
+            _ => return LineEdit::NotHandled,
+        }
+        LineEdit::Edited
+    }
+
+    /// Returns the text split at the cursor.
+    pub fn split_at_cursor(&self) -> (&str, &str) {
+        self.text.split_at(self.byte_pos(self.cursor))
+    }
+
+    fn byte_pos(&self, char_index: usize) -> usize {
+        self.text.char_indices().nth(char_index).map_or(self.text.len(), |(i, _)| i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyModifiers;
+
+    fn press(line: &mut LineEditor, code: KeyCode) -> LineEdit {
+        line.handle_key(&KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn line(text: &str, cursor: usize) -> LineEditor {
+        let mut line = LineEditor::new();
+        line.insert(text);
+        line.set_cursor(cursor);
+        line
+    }
+
+    #[test]
+    fn inserts_multibyte_text_at_the_cursor() {
+        let mut line = line("ab", 1);
+        line.insert("日本");
+        assert_eq!((line.text(), line.cursor()), ("a日本b", 3));
+        assert_eq!(line.split_at_cursor(), ("a日本", "b"));
+    }
+
+    #[test]
+    fn backspace_at_both_ends() {
+        let mut start = line("あい", 0);
+        assert_eq!(press(&mut start, KeyCode::Backspace), LineEdit::Edited);
+        assert_eq!((start.text(), start.cursor()), ("あい", 0));
+
+        let mut end = line("あい", 2);
+        assert_eq!(press(&mut end, KeyCode::Backspace), LineEdit::Edited);
+        assert_eq!((end.text(), end.cursor()), ("あ", 1));
+    }
+
+    #[test]
+    fn delete_at_both_ends() {
+        let mut start = line("あい", 0);
+        assert_eq!(press(&mut start, KeyCode::Delete), LineEdit::Edited);
+        assert_eq!((start.text(), start.cursor()), ("い", 0));
+
+        let mut end = line("あい", 2);
+        assert_eq!(press(&mut end, KeyCode::Delete), LineEdit::Edited);
+        assert_eq!((end.text(), end.cursor()), ("あい", 2));
+    }
+
+    #[test]
+    fn cursor_movement_stays_inside_the_line() {
+        let mut line = line("aあ", 0);
+        press(&mut line, KeyCode::Left);
+        assert_eq!(line.cursor(), 0);
+        press(&mut line, KeyCode::End);
+        press(&mut line, KeyCode::Right);
+        assert_eq!(line.cursor(), 2);
+        press(&mut line, KeyCode::Home);
+        assert_eq!(line.cursor(), 0);
+        line.set_cursor(10);
+        assert_eq!(line.cursor(), 2);
+    }
+
+    #[test]
+    fn enter_submits_and_clears_the_line() {
+        let mut line = line("日本語", 1);
+        assert_eq!(press(&mut line, KeyCode::Enter), LineEdit::Submit("日本語".to_string()));
+        assert_eq!((line.text(), line.cursor()), ("", 0));
+    }
+
+    #[test]
+    fn other_keys_and_modified_keys_are_not_handled() {
+        let mut line = line("ab", 1);
+        assert_eq!(press(&mut line, KeyCode::Char('x')), LineEdit::NotHandled);
+        let ctrl_left = KeyEvent::new(KeyCode::Left, KeyModifiers::CONTROL);
+        assert_eq!(line.handle_key(&ctrl_left), LineEdit::NotHandled);
+        assert_eq!((line.text(), line.cursor()), ("ab", 1));
+    }
+}
@@ -2,69 +2,122 @@
 // Main entry point for the async Fcitx5 FEP application.
 // Handles initialization, argument parsing (if any), and starts the main event loop.
 
-mod error;
+mod config;
+mod event_log;
 mod event_loop;
-mod fcitx;
-mod state;
+mod history;
+mod im_memory;
+mod metrics;
+mod overlay;
+mod reconnect;
+mod sanitize;
+mod sink;
 mod terminal;
+mod utf8;
+mod xkb;
 
-use error::FepError;
-use event_loop::run_event_loop;
-use tokio::select; // Import tokio::select
+// The Fcitx client, rendering and the runtime-agnostic modules come from the library crate
+use fcitx5_fep_rust::{error, fcitx, keymap, line_editor, render, state, width};
+
+use event_loop::{run_send_key, run_session, shutdown, SHUTDOWN_TIMEOUT};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Starting Fcitx5 FEP (Async)...");
 
-    // Initialize terminal (synchronous setup)
-    let mut terminal = match terminal::Terminal::new() {
-        Ok(term) => term,
+    // Parse command-line configuration before touching the terminal
+    let config = match config::Config::from_args() {
+        Ok(config) => config,
         Err(e) => {
-            eprintln!("Failed to initialize terminal: {}", e);
-            // Attempt to disable raw mode if it was partially enabled, though unlikely here
-            let _ = crossterm::terminal::disable_raw_mode();
+            eprintln!("{}", e);
             return Err(e.into());
         }
     };
 
-    // Connect to Fcitx (asynchronous)
-    // Use a block to ensure client is dropped before terminal cleanup if connect fails
-    let mut fcitx_client = match fcitx::FcitxClient::connect().await {
-         Ok(client) => client,
-         Err(e) => {
-             eprintln!("Failed to connect to Fcitx: {}", e);
-             // Terminal cleanup will happen automatically via Drop
-             return Err(e.into());
-         }
-    };
+    // One-shot `--list-ims` mode: print the configured input methods and exit.
+    if config.list_ims {
+        let ims = match fcitx::list_input_methods().await {
+            Ok(ims) => ims,
+            Err(e) => {
+                eprintln!("Failed to list input methods: {}", e);
+                return Err(e.into());
+            }
+        };
+        if ims.is_empty() {
+            println!("No input methods are configured. Add some with fcitx5-configtool.");
+        }
+        for im in ims {
+            println!("{}\t{} ({}) [{}] {}", im.unique_name, im.name, im.native_name, im.label, im.language_code);
+        }
+        return Ok(());
+    }
 
-    let mut app_state = state::AppState::new();
+    // One-shot `--self-test` mode: check the Fcitx setup, report and exit.
+    if config.self_test {
+        let checks = fcitx::self_test(&config.program).await;
+        for check in &checks {
+            match &check.outcome {
+                Ok(detail) => println!("[ ok ] {}: {}", check.name, detail),
+                Err(problem) => println!("[FAIL] {}: {}", check.name, problem),
+            }
+        }
+        if checks.iter().any(|check| check.outcome.is_err()) {
+            return Err("self-test failed".into()); // Nonzero exit status
+        }
+        return Ok(());
+    }
 
-    // Run the main event loop, handling Ctrl+C for graceful shutdown
-    println!("FEP started. Press Ctrl+C to exit.");
-    select! {
-        result = run_event_loop(&mut terminal, &mut fcitx_client, &mut app_state) => {
-            if let Err(e) = result {
-                eprintln!("\nEvent loop terminated with error: {}", e);
-                // Error occurred, return it (cleanup via Drop)
-                // Ensure newline after potential raw mode output mess
-                println!();
+    // One-shot `--send-key` mode: no terminal setup, just forward the key and report.
+    if let Some((keysym, state)) = config.send_key {
+        let mut fcitx_client = match fcitx::FcitxClient::connect(&config.program).await {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Failed to connect to Fcitx: {}", e);
                 return Err(e.into());
-            } else {
-                 // Event loop exited normally (e.g., stream ended)
-                 println!("\nEvent loop finished normally.");
             }
+        };
+        fcitx_client.set_key_timestamps(config.key_timestamps);
+        let result = run_send_key(&mut fcitx_client, keysym, state).await;
+        fcitx_client.disconnect_with_timeout(SHUTDOWN_TIMEOUT).await;
+        if let Err(e) = result {
+            eprintln!("Failed to send key: {}", e);
+            return Err(e.into()); // Nonzero exit status for scripts
         }
-        _ = tokio::signal::ctrl_c() => {
-            println!("\nCtrl+C received, shutting down gracefully...");
-            // Signal received, cleanup will happen via Drop
+        return Ok(());
+    }
+
+    // Initialize terminal (synchronous setup)
+    let mut terminal = match terminal::Terminal::new(&config) {
+        Ok(term) => term,
+        Err(e) => {
+            eprintln!("Failed to initialize terminal: {}", e);
+            // Attempt to disable raw mode if it was partially enabled, though unlikely here
+            let _ = crossterm::terminal::disable_raw_mode();
+            return Err(e.into());
         }
+    };
+
+    let mut app_state = state::AppState::new();
+    if config.line_edit {
+        app_state.line = Some(line_editor::LineEditor::new());
     }
 
-    // Explicit disconnect might be needed if Drop doesn't handle async cleanup well
-    // fcitx_client.disconnect().await; // Call if needed
+    // Connect to Fcitx and run the main event loop (reconnecting if the connection is lost).
+    // Ctrl+C (a key event in raw mode, or SIGINT) is handled inside the loop, so there is
+    // exactly one graceful shutdown path.
+    println!("FEP started. Press Ctrl+C to exit.");
+    let mut fcitx_client = None;
+    let result = run_session(&mut terminal, &mut app_state, &mut fcitx_client, &config).await;
 
+    // One exit sequence for every way the session ends: FocusOut is sent (bounded by a
+    // timeout) while the terminal is still set up, then the client and the terminal go.
+    shutdown(&mut terminal, fcitx_client.take(), &mut app_state, &config).await;
+    drop(terminal); // Restores the terminal (see Terminal's Drop)
+    if let Err(e) = result {
+        eprintln!("\nEvent loop terminated with error: {}", e);
+        return Err(e.into());
+    }
+    println!("\nShutting down gracefully...");
     println!("Exiting Fcitx5 FEP application.");
-    // Terminal and FcitxClient cleanup happens via their Drop implementations here
     Ok(())
 }
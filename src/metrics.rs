@@ -0,0 +1,79 @@
+// src/metrics.rs
+// Optional key-to-update latency instrumentation (enabled with --metrics).
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Tracks the latency between forwarding a key to Fcitx and receiving the resulting update.
+///
+/// D-Bus signals are not tied to the method call that caused them, so updates are matched
+/// to key presses in FIFO order. This is approximate: a key that produces no update (or
+/// several updates) shifts the pairing until the queue drains.
+#[derive(Debug, Default)]
+pub struct LatencyMetrics {
+    pending: VecDeque<Instant>, // Forward times of keys still waiting for an update
+    count: u32,
+    total: Duration,
+    min: Option<Duration>,
+    max: Duration,
+}
+
+impl LatencyMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that a key event was forwarded to Fcitx.
+    pub fn key_forwarded(&mut self) {
+        self.pending.push_back(Instant::now());
+    }
+
+    /// Records that an update arrived from Fcitx and returns its latency, if it was matched
+    /// to a forwarded key.
+    pub fn update_received(&mut self) -> Option<Duration> {
+        // None: update not caused by a key we forwarded (or already matched)
+        let sent = self.pending.pop_front()?;
+        let latency = sent.elapsed();
+        self.count += 1;
+        self.total += latency;
+        self.min = Some(self.min.map_or(latency, |min| min.min(latency)));
+        self.max = self.max.max(latency);
+        Some(latency)
+    }
+
+    /// Discards keys that will never see an update (e.g. keys not handled by Fcitx).
+    pub fn key_unhandled(&mut self) {
+        self.pending.pop_back();
+    }
+
+    /// Returns the running min/avg/max as a human-readable string.
+    pub fn summary(&self) -> String {
+        if self.count == 0 {
+            return "no samples".to_string();
+        }
+        format!(
+            "min {:?}, avg {:?}, max {:?} over {} samples",
+            self.min.unwrap_or_default(),
+            self.total / self.count,
+            self.max,
+            self.count
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_updates_to_forwarded_keys_in_order() {
+        let mut metrics = LatencyMetrics::new();
+        assert_eq!(metrics.update_received(), None);
+        metrics.key_forwarded();
+        metrics.key_forwarded();
+        metrics.key_unhandled();
+        assert!(metrics.update_received().is_some());
+        assert_eq!(metrics.update_received(), None);
+        assert!(metrics.summary().ends_with("over 1 samples"), "{}", metrics.summary());
+    }
+}
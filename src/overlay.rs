@@ -0,0 +1,46 @@
+// src/overlay.rs
+// Keybinding debug overlay: shows the raw key events crossterm delivered and what they
+// were mapped to, in place of the preedit (toggled with --debug-overlay-key).
+
+use crossterm::event::KeyEvent;
+use std::collections::VecDeque;
+
+/// Number of key events the overlay shows.
+const OVERLAY_LINES: usize = 8;
+
+/// The most recent key events, newest last, as display lines.
+/// Holds no reference to `AppState`: while the overlay is shown, keys are only recorded
+/// here and never forwarded, so the composition is untouched when it is toggled off.
+#[derive(Debug, Default)]
+pub struct KeyDebugOverlay {
+    lines: VecDeque<String>,
+}
+
+impl KeyDebugOverlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a key event together with the (keysym, keycode, state) it maps to, if any.
+    pub fn record(&mut self, key_event: &KeyEvent, mapping: Option<(u32, u32, u32)>) {
+        let mapped = match mapping {
+            Some((keysym, keycode, state)) => format!("keysym=0x{:x} keycode={} state=0x{:x}", keysym, keycode, state),
+            None => "not mapped".to_string(),
+        };
+        let line = format!(
+            "code={:?} modifiers={:?} kind={:?} -> {}",
+            key_event.code, key_event.modifiers, key_event.kind, mapped
+        );
+        if self.lines.len() == OVERLAY_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    /// Returns the lines to draw, starting with a header.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec!["[key debug] press the overlay hotkey again to resume".to_string()];
+        lines.extend(self.lines.iter().cloned());
+        lines
+    }
+}
@@ -0,0 +1,89 @@
+// src/reconnect.rs
+// Throttled reconnection to Fcitx after the connection is lost.
+
+use crate::fcitx::FcitxClient;
+use std::time::{Duration, Instant};
+use zbus::Connection;
+
+/// What to do once reconnecting has been given up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    Exit,        // Exit with an error
+    Passthrough, // Keep running without Fcitx, writing typed keys through as-is
+}
+
+/// Reconnection settings.
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// Delay before the first attempt; doubled after each failure.
+    pub initial_delay: Duration,
+    /// Hard cap on the delay, i.e. at most one attempt per this interval once the backoff plateaus.
+    pub max_delay: Duration,
+    /// Total time after which reconnecting is given up. Zero disables reconnecting.
+    pub max_total: Duration,
+    /// Behavior after giving up.
+    pub on_failure: FailurePolicy,
+    /// How long to try resubscribing to Fcitx's signals on the existing connection after the
+    /// update stream ends, before treating the connection as lost. Zero reconnects at once.
+    pub stream_grace: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(5),
+            max_total: Duration::from_secs(60),
+            on_failure: FailurePolicy::Exit,
+            stream_grace: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Tries to reconnect to Fcitx (as `program`) with exponential backoff (async).
+/// Returns `None` once `max_total` has elapsed without success. Individual failures
+/// are not logged; a single summary is printed when the attempts end.
+pub async fn connect_with_backoff<'a>(config: &ReconnectConfig, program: &str) -> Option<FcitxClient<'a>> {
+    let started = Instant::now();
+    let mut delay = config.initial_delay;
+    let mut attempts = 0u32;
+    let mut last_error = None;
+
+    while started.elapsed() + delay <= config.max_total {
+        tokio::time::sleep(delay).await;
+        attempts += 1;
+        match FcitxClient::connect(program).await {
+            Ok(client) => {
+                eprintln!("Reconnected to Fcitx after {} attempt(s) in {:?}.", attempts, started.elapsed());
+                return Some(client);
+            }
+            Err(e) => last_error = Some(e),
+        }
+        delay = (delay * 2).min(config.max_delay);
+    }
+
+    eprintln!(
+        "Gave up reconnecting to Fcitx after {} attempt(s) in {:?}{}",
+        attempts,
+        started.elapsed(),
+        last_error.map_or(String::from("."), |e| format!(": {}", e))
+    );
+    None
+}
+
+/// Waits for the D-Bus session bus to accept connections again, with the same backoff and
+/// overall limit as `connect_with_backoff` (async). Returns `false` once `max_total` has
+/// elapsed. Only the bus is probed; connecting to Fcitx is left to the caller.
+pub async fn wait_for_session_bus(config: &ReconnectConfig) -> bool {
+    let started = Instant::now();
+    let mut delay = config.initial_delay;
+    while started.elapsed() + delay <= config.max_total {
+        tokio::time::sleep(delay).await;
+        if Connection::session().await.is_ok() {
+            return true;
+        }
+        delay = (delay * 2).min(config.max_delay);
+    }
+    eprintln!("Gave up waiting for the D-Bus session bus after {:?}.", started.elapsed());
+    false
+}
@@ -0,0 +1,255 @@
+// src/render.rs
+// Renders the application state into a caller-supplied target (for embedding in a TUI).
+// The standalone binary uses `Terminal::render`, which writes ANSI directly to stdout.
+
+use crate::state::AppState;
+use crate::width::grapheme_width;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A rectangular region of the target, in cells.
+///
+/// Coordinate conventions: `(x, y)` is the top-left cell of the region, with `x` counting
+/// columns and `y` counting rows, both zero-based from the target's top-left corner
+/// (the same convention as `ratatui::layout::Rect`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Area {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// Style of a drawn cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellStyle {
+    Plain,
+    Preedit, // Preedit text (the stdout renderer underlines it)
+}
+
+/// A drawing surface provided by the embedder, e.g. an adapter around a `ratatui::buffer::Buffer`.
+pub trait RenderTarget {
+    /// Draws a grapheme cluster (one or more characters, e.g. a base letter and its combining
+    /// marks) at absolute target coordinates. A wide grapheme is drawn at its first cell and
+    /// also covers the next one, which is left alone (as in `ratatui::buffer::Buffer`).
+    fn set_cell(&mut self, x: u16, y: u16, grapheme: &str, style: CellStyle);
+
+    /// Places the cursor at absolute target coordinates. Called at most once per render,
+    /// and only if the preedit cursor falls inside the area.
+    fn set_cursor(&mut self, _x: u16, _y: u16) {}
+}
+
+/// Draws the current preedit of `state` into `area` of `target`.
+///
+/// The preedit is laid out left to right starting at the area's top-left corner and wraps
+/// at the area's width, measured in terminal cells like the stdout renderer: wide characters
+/// and emoji take two cells and are never split across rows. Anything that does not fit in
+/// the area's height is clipped, and nothing is ever drawn outside the area. Preedits longer
+/// than `max_preedit_len` characters are elided like in the stdout renderer (0 = no limit).
+/// Committed text is not drawn: embedders take it from `AppState::take_commit` and insert
+/// it into their own widgets.
+pub fn render_into<T: RenderTarget>(state: &AppState, area: Area, target: &mut T, max_preedit_len: usize) {
+    if area.width == 0 || area.height == 0 || state.preedit_string.is_empty() {
+        return;
+    }
+    let (preedit, cursor) = elide_preedit(&state.preedit_string, state.preedit_cursor_pos, max_preedit_len);
+    let (width, height) = (area.width as usize, area.height as usize);
+
+    let (mut col, mut row) = (0, 0);
+    let mut chars_before = 0; // Characters of the preedit laid out so far
+    let mut cursor_cell = None;
+    for grapheme in preedit.graphemes(true) {
+        let cells = grapheme_width(grapheme);
+        // A grapheme that doesn't fit on the rest of the row starts the next one
+        if col > 0 && col + cells > width {
+            (col, row) = (0, row + 1);
+        }
+        // The cursor goes before the grapheme it points into
+        let chars = grapheme.chars().count();
+        if cursor_cell.is_none() && cursor < chars_before + chars {
+            cursor_cell = Some((col, row));
+        }
+        if row >= height {
+            break;
+        }
+        // Zero-width graphemes take no cell, and one wider than the area can't be drawn in it
+        if cells > 0 && cells <= width {
+            target.set_cell(area.x + col as u16, area.y + row as u16, grapheme, CellStyle::Preedit);
+        }
+        col += cells.min(width);
+        chars_before += chars;
+    }
+
+    // A cursor at the end of a full row goes to the start of the next one
+    let (col, row) = cursor_cell.unwrap_or(if col >= width { (0, row + 1) } else { (col, row) });
+    if row < height {
+        target.set_cursor(area.x + col as u16, area.y + row as u16);
+    }
+}
+
+/// Bounds a preedit string to at most `max_len` characters for display.
+/// The middle is elided with "…" while the region around the cursor stays visible.
+/// Returns the display string and the cursor position (character index) within it.
+/// A `max_len` of 0 disables the limit.
+pub fn elide_preedit(text: &str, cursor: usize, max_len: usize) -> (String, usize) {
+    let chars: Vec<char> = text.chars().collect();
+    let len = chars.len();
+    if max_len == 0 || len <= max_len {
+        return (text.to_string(), cursor);
+    }
+    let max_len = max_len.max(3); // Room for at least one character and two ellipses
+    let cursor = cursor.min(len);
+
+    // Try to keep both ends and elide the middle.
+    let budget = max_len - 1;
+    let head_len = budget / 2;
+    let tail_len = budget - head_len;
+    let tail_start = len - tail_len;
+    let head: String = chars[..head_len].iter().collect();
+    let tail: String = chars[tail_start..].iter().collect();
+    if cursor <= head_len {
+        return (format!("{}…{}", head, tail), cursor);
+    }
+    if cursor >= tail_start {
+        return (format!("{}…{}", head, tail), head_len + 1 + (cursor - tail_start));
+    }
+
+    // The cursor is inside the elided middle: show a window around it instead.
+    let window = max_len - 2;
+    let start = cursor.saturating_sub(window / 2).min(len - window);
+    let middle: String = chars[start..start + window].iter().collect();
+    (format!("…{}…", middle), 1 + cursor - start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A target that records drawn cells row by row, like a terminal buffer. Cells covered by
+    /// a wide grapheme stay empty.
+    struct BufferTarget {
+        cells: Vec<Vec<String>>,
+        cursor: Option<(u16, u16)>,
+    }
+
+    impl BufferTarget {
+        fn new(width: usize, height: usize) -> Self {
+            BufferTarget { cells: vec![vec![String::new(); width]; height], cursor: None }
+        }
+
+        /// The rows as strings, with "." for cells nothing was drawn into.
+        fn rows(&self) -> Vec<String> {
+            self.cells
+                .iter()
+                .map(|row| row.iter().map(|cell| if cell.is_empty() { "." } else { cell.as_str() }).collect())
+                .collect()
+        }
+    }
+
+    impl RenderTarget for BufferTarget {
+        fn set_cell(&mut self, x: u16, y: u16, grapheme: &str, style: CellStyle) {
+            assert_eq!(style, CellStyle::Preedit);
+            let cell = &mut self.cells[y as usize][x as usize]; // Panics if drawn outside the buffer
+            assert!(cell.is_empty(), "cell ({}, {}) drawn twice", x, y);
+            *cell = grapheme.to_string();
+        }
+
+        fn set_cursor(&mut self, x: u16, y: u16) {
+            assert!(self.cursor.replace((x, y)).is_none(), "cursor placed twice");
+        }
+    }
+
+    fn state(preedit: &str, cursor: usize) -> AppState {
+        AppState { preedit_string: preedit.to_string(), preedit_cursor_pos: cursor, ..AppState::new() }
+    }
+
+    fn render(state: &AppState, area: Area, buffer_size: (usize, usize), max_len: usize) -> BufferTarget {
+        let mut target = BufferTarget::new(buffer_size.0, buffer_size.1);
+        render_into(state, area, &mut target, max_len);
+        target
+    }
+
+    const AREA_4X2: Area = Area { x: 0, y: 0, width: 4, height: 2 };
+
+    #[test]
+    fn wraps_ascii_at_the_area_width() {
+        let target = render(&state("abcdef", 6), AREA_4X2, (4, 2), 0);
+        assert_eq!(target.rows(), ["abcd", "ef.."]);
+        assert_eq!(target.cursor, Some((2, 1)));
+    }
+
+    #[test]
+    fn wide_characters_take_two_cells_and_are_not_split() {
+        // "あいう" is six cells: two fit on the first row, the third wraps whole
+        let target = render(&state("aあいう", 2), AREA_4X2, (4, 2), 0);
+        assert_eq!(target.rows(), ["aあ..", "い.う."]);
+        assert_eq!(target.cursor, Some((0, 1)));
+    }
+
+    #[test]
+    fn cursor_after_wide_characters_counts_cells() {
+        let target = render(&state("日本", 1), AREA_4X2, (4, 2), 0);
+        assert_eq!(target.rows(), ["日.本.", "...."]);
+        assert_eq!(target.cursor, Some((2, 0)));
+    }
+
+    #[test]
+    fn grapheme_clusters_fill_one_cell() {
+        // "e" + combining acute, then a family emoji (ZWJ sequence), then "x"
+        let target = render(&state("e\u{301}👨\u{200d}👩\u{200d}👧x", 7), AREA_4X2, (4, 2), 0);
+        assert_eq!(target.cells[0], ["e\u{301}", "👨\u{200d}👩\u{200d}👧", "", "x"]);
+        assert_eq!(target.cursor, Some((3, 0)));
+    }
+
+    #[test]
+    fn cursor_at_the_start_middle_and_end_of_a_multibyte_preedit() {
+        let area = Area { x: 0, y: 0, width: 8, height: 1 };
+        for (cursor, cell) in [(0, 0), (1, 2), (3, 6)] {
+            let target = render(&state("にほん", cursor), area, (8, 1), 0);
+            assert_eq!(target.rows(), ["に.ほ.ん..."]);
+            assert_eq!(target.cursor, Some((cell, 0)), "cursor at character {}", cursor);
+        }
+    }
+
+    #[test]
+    fn cursor_at_the_end_of_a_full_row_goes_to_the_next_row() {
+        let target = render(&state("abcd", 4), AREA_4X2, (4, 2), 0);
+        assert_eq!(target.cursor, Some((0, 1)));
+        // ... unless there is none
+        let target = render(&state("abcdefgh", 8), AREA_4X2, (4, 2), 0);
+        assert_eq!(target.rows(), ["abcd", "efgh"]);
+        assert_eq!(target.cursor, None);
+    }
+
+    #[test]
+    fn clips_to_the_area_and_draws_at_its_offset() {
+        let area = Area { x: 1, y: 1, width: 3, height: 1 };
+        let target = render(&state("あいう", 0), area, (5, 3), 0);
+        assert_eq!(target.rows(), [".....", ".あ...", "....."]);
+        assert_eq!(target.cursor, Some((1, 1)));
+    }
+
+    #[test]
+    fn wide_grapheme_wider_than_the_area_is_skipped() {
+        let area = Area { x: 0, y: 0, width: 1, height: 3 };
+        let target = render(&state("aあb", 3), area, (1, 3), 0);
+        assert_eq!(target.rows(), ["a", ".", "b"]);
+        assert_eq!(target.cursor, None);
+    }
+
+    #[test]
+    fn draws_nothing_for_an_empty_area_or_preedit() {
+        let empty = Area { width: 0, ..AREA_4X2 };
+        assert_eq!(render(&state("abc", 0), empty, (4, 2), 0).cursor, None);
+        let target = render(&state("", 0), AREA_4X2, (4, 2), 0);
+        assert_eq!((target.rows(), target.cursor), (vec!["....".to_string(); 2], None));
+    }
+
+    #[test]
+    fn elides_long_preedits() {
+        let area = Area { x: 0, y: 0, width: 8, height: 1 };
+        let target = render(&state("abcdefghij", 0), area, (8, 1), 5);
+        assert_eq!(target.rows(), ["ab…ij..."]);
+        assert_eq!(target.cursor, Some((0, 0)));
+    }
+}
+
@@ -0,0 +1,161 @@
+// src/sanitize.rs
+// Neutralizes control characters and escape sequences in committed text before it
+// reaches the terminal or any other sink.
+
+use crate::state::FcitxUpdate;
+
+/// How committed text is cleaned up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizePolicy {
+    /// Drop every control character, together with the escape sequence it introduces.
+    Strict,
+    /// Keep tab and carriage return, and show other control characters visibly
+    /// (e.g. `^[` for ESC) so nothing is silently lost but nothing is interpreted either.
+    Permissive,
+}
+
+/// Sanitization settings for committed text.
+#[derive(Debug, Clone, Copy)]
+pub struct CommitFilter {
+    pub policy: SanitizePolicy,
+    /// Whether newlines in commits are passed through (under either policy).
+    pub allow_newline: bool,
+}
+
+impl Default for CommitFilter {
+    fn default() -> Self {
+        CommitFilter { policy: SanitizePolicy::Strict, allow_newline: true }
+    }
+}
+
+impl CommitFilter {
+    /// Sanitizes the text of a commit update from Fcitx; other updates pass through.
+    /// Only engine commits are filtered: bytes the FEP emits itself (fallback keys,
+    /// literal tabs) are intentional and go into the commit buffer untouched.
+    pub fn apply_to_update(&self, update: FcitxUpdate) -> FcitxUpdate {
+        match update {
+            FcitxUpdate::CommitString(text) => FcitxUpdate::CommitString(self.apply(&text)),
+            FcitxUpdate::CommitStringWithCursor { text, cursor } => {
+                // Filter each side of the cursor separately so it stays between the same characters
+                let split = text.char_indices().nth(cursor).map_or(text.len(), |(i, _)| i);
+                let (before, after) = (self.apply(&text[..split]), self.apply(&text[split..]));
+                let cursor = before.chars().count();
+                FcitxUpdate::CommitStringWithCursor { text: before + &after, cursor }
+            }
+            other => other,
+        }
+    }
+
+    /// Returns `text` with dangerous control characters stripped or escaped.
+    /// Text without control characters is returned unchanged.
+    pub fn apply(&self, text: &str) -> String {
+        if !text.chars().any(char::is_control) {
+            return text.to_string();
+        }
+        let mut out = String::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if !c.is_control() || (c == '\n' && self.allow_newline) {
+                out.push(c);
+                continue;
+            }
+            match self.policy {
+                SanitizePolicy::Permissive if c == '\t' || c == '\r' => out.push(c),
+                SanitizePolicy::Permissive => out.push_str(&visible_control(c)),
+                SanitizePolicy::Strict => skip_escape_sequence(c, &mut chars),
+            }
+        }
+        out
+    }
+}
+
+/// Caret notation for C0 controls and DEL (`^[`, `^?`), `\xNN` for C1 controls.
+fn visible_control(c: char) -> String {
+    match c as u32 {
+        code @ 0x00..=0x1f => format!("^{}", char::from(code as u8 + 0x40)),
+        0x7f => "^?".to_string(),
+        code => format!("\\x{:02x}", code),
+    }
+}
+
+/// Consumes the rest of the escape sequence introduced by `c`, if any:
+/// CSI (`ESC [` or U+009B) up to its final byte, OSC/DCS/APC/PM/SOS (`ESC ]`, `ESC P`, ...)
+/// up to BEL or ST, and a single character after any other ESC.
+fn skip_escape_sequence(c: char, chars: &mut std::iter::Peekable<std::str::Chars<'_>>) {
+    let introducer = match c {
+        '\x1b' => match chars.next() {
+            Some(next) => next,
+            None => return,
+        },
+        '\u{9b}' => '[',
+        '\u{90}' | '\u{98}' | '\u{9d}' | '\u{9e}' | '\u{9f}' => ']',
+        _ => return, // Lone control character
+    };
+    match introducer {
+        '[' => {
+            // Parameter and intermediate bytes, then one final byte in 0x40..=0x7e
+            for next in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&next) {
+                    break;
+                }
+            }
+        }
+        ']' | 'P' | 'X' | '^' | '_' => {
+            // String sequences end with BEL or ST (`ESC \` or U+009C)
+            while let Some(next) = chars.next() {
+                match next {
+                    '\x07' | '\u{9c}' => break,
+                    '\x1b' if chars.peek() == Some(&'\\') => {
+                        chars.next();
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        _ => {} // Two-character sequence, already consumed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRICT: CommitFilter = CommitFilter { policy: SanitizePolicy::Strict, allow_newline: true };
+    const PERMISSIVE: CommitFilter = CommitFilter { policy: SanitizePolicy::Permissive, allow_newline: true };
+
+    #[test]
+    fn strict_drops_ansi_escape_sequences() {
+        assert_eq!(STRICT.apply("a\x1b[31mred\x1b[0m"), "ared");
+        assert_eq!(STRICT.apply("\x1b]2;title\x07ok"), "ok");
+        assert_eq!(STRICT.apply("\x1b]52;c;ZXZpbA==\x1b\\ok"), "ok");
+        assert_eq!(STRICT.apply("\u{9b}2Jok"), "ok");
+        assert_eq!(STRICT.apply("tab\tbell\x07"), "tabbell");
+    }
+
+    #[test]
+    fn permissive_shows_control_characters() {
+        assert_eq!(PERMISSIVE.apply("a\x1b[31m"), "a^[[31m");
+        assert_eq!(PERMISSIVE.apply("\t\r\x7f\u{9b}"), "\t\r^?\\x9b");
+    }
+
+    #[test]
+    fn newlines_pass_only_when_allowed() {
+        assert_eq!(STRICT.apply("a\nb"), "a\nb");
+        let no_newline = CommitFilter { allow_newline: false, ..STRICT };
+        assert_eq!(no_newline.apply("a\nb"), "ab");
+        assert_eq!(CommitFilter { allow_newline: false, ..PERMISSIVE }.apply("a\nb"), "a^Jb");
+    }
+
+    #[test]
+    fn neutralizes_commit_updates_and_keeps_the_cursor_in_place() {
+        let update = STRICT.apply_to_update(FcitxUpdate::CommitString("日本\x1b[2J語".to_string()));
+        assert!(matches!(update, FcitxUpdate::CommitString(text) if text == "日本語"));
+
+        let update = FcitxUpdate::CommitStringWithCursor { text: "\x1b[1m(\x1b[0m)".to_string(), cursor: 5 };
+        assert!(matches!(
+            STRICT.apply_to_update(update),
+            FcitxUpdate::CommitStringWithCursor { text, cursor: 1 } if text == "()"
+        ));
+    }
+}
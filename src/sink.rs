@@ -0,0 +1,324 @@
+// src/sink.rs
+// Destinations for committed text besides the terminal itself (files, sockets, ...).
+
+use crate::error::FepError;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Where committed text should be delivered. Parsed from `--commit-sink`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkSpec {
+    Terminal,          // Print commits in the terminal (the default)
+    File(PathBuf),     // Append commits to a file
+    Socket(PathBuf, Framing), // Write commits to a Unix domain socket
+    Command(Vec<String>), // Run a command for every commit (--on-commit), as argv
+}
+
+impl SinkSpec {
+    /// Parses `terminal`, `file:<path>`, or `socket:<path>` with an optional framing
+    /// (`socket+newline:<path>`, `socket+nul:<path>`, `socket+length:<path>`).
+    pub fn parse(spec: &str) -> Result<Self, FepError> {
+        let invalid = || FepError::Config(format!("Invalid commit sink: '{}'", spec));
+        match spec.split_once(':') {
+            None if spec == "terminal" => Ok(SinkSpec::Terminal),
+            Some(("file", path)) if !path.is_empty() => Ok(SinkSpec::File(path.into())),
+            Some((kind, path)) if !path.is_empty() && kind.starts_with("socket") => {
+                let framing = match kind.strip_prefix("socket") {
+                    Some("") => Framing::Raw,
+                    Some("+newline") => Framing::Newline,
+                    Some("+nul") => Framing::Nul,
+                    Some("+length") => Framing::LengthPrefixed,
+                    _ => return Err(invalid()),
+                };
+                Ok(SinkSpec::Socket(path.into(), framing))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How a socket sink marks where one commit ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// The text as is, with no boundaries (`socket:`).
+    Raw,
+    /// Each commit followed by `\n` (`socket+newline:`).
+    Newline,
+    /// Each commit followed by a NUL byte (`socket+nul:`).
+    Nul,
+    /// Each commit preceded by its length in bytes as a little-endian u32 (`socket+length:`).
+    LengthPrefixed,
+}
+
+impl Framing {
+    /// Returns `text` framed for the wire. In the delimiter modes a backslash is sent as `\\`
+    /// and the delimiter inside a commit as `\n` or `\0`, so a frame never ends early;
+    /// the length prefix needs no escaping.
+    pub fn frame(self, text: &str) -> Vec<u8> {
+        let delimiter = match self {
+            Framing::Raw => return text.as_bytes().to_vec(),
+            Framing::LengthPrefixed => {
+                let mut frame = Vec::with_capacity(4 + text.len());
+                frame.extend_from_slice(&(text.len() as u32).to_le_bytes());
+                frame.extend_from_slice(text.as_bytes());
+                return frame;
+            }
+            Framing::Newline => '\n',
+            Framing::Nul => '\0',
+        };
+        let mut frame = String::with_capacity(text.len() + 1);
+        for c in text.chars() {
+            match c {
+                '\\' => frame.push_str("\\\\"),
+                c if c == delimiter => frame.push_str(if c == '\n' { "\\n" } else { "\\0" }),
+                c => frame.push(c),
+            }
+        }
+        frame.push(delimiter);
+        frame.into_bytes()
+    }
+}
+
+/// A destination that receives every committed string.
+pub trait CommitSink: Send {
+    /// Human-readable name used in error messages.
+    fn name(&self) -> String;
+
+    /// Delivers one committed string.
+    fn write(&mut self, text: &str) -> io::Result<()>;
+}
+
+/// Appends committed text to a file.
+pub struct FileSink {
+    path: PathBuf,
+    file: File,
+}
+
+impl CommitSink for FileSink {
+    fn name(&self) -> String {
+        format!("file:{}", self.path.display())
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        self.file.write_all(text.as_bytes())?;
+        self.file.flush()
+    }
+}
+
+/// Sends committed text to a listening Unix domain socket, one frame per commit.
+pub struct SocketSink {
+    path: PathBuf,
+    stream: UnixStream,
+    framing: Framing,
+}
+
+impl CommitSink for SocketSink {
+    fn name(&self) -> String {
+        format!("socket:{}", self.path.display())
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        self.stream.write_all(&self.framing.frame(text))
+    }
+}
+
+/// Placeholder in `--on-commit` arguments that is replaced by the committed text.
+pub const COMMIT_PLACEHOLDER: &str = "{}";
+
+/// Commands from `--on-commit` that may run at the same time; further commits are skipped.
+const MAX_RUNNING_COMMANDS: usize = 4;
+
+/// Runs a command for every committed string, without a shell: the text replaces every `{}`
+/// in the arguments (also inside one, as in `"got: {}"`), and is written to the command's
+/// stdin if there is none.
+/// Commands run in the background (a thread waits for each one), so a slow command never
+/// blocks the event loop; their failures are logged rather than reported as sink errors.
+pub struct CommandSink {
+    argv: Vec<String>,
+    running: Arc<AtomicUsize>,
+}
+
+impl CommitSink for CommandSink {
+    fn name(&self) -> String {
+        format!("command:{}", self.argv.join(" "))
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        if self.running.fetch_add(1, Ordering::SeqCst) >= MAX_RUNNING_COMMANDS {
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            eprintln!("{}: {} commands still running, skipping this commit.", self.name(), MAX_RUNNING_COMMANDS);
+            return Ok(());
+        }
+        let args = substitute_commit(&self.argv[1..], text);
+        let use_stdin = args.is_none();
+        let spawned = Command::new(&self.argv[0])
+            .args(args.as_deref().unwrap_or(&self.argv[1..]))
+            .stdin(if use_stdin { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::null()) // Output would garble the terminal in raw mode
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                eprintln!("{}: failed to run: {}", self.name(), e);
+                return Ok(());
+            }
+        };
+        let (name, running, input) = (self.name(), Arc::clone(&self.running), text.to_string());
+        std::thread::spawn(move || {
+            if let Some(mut stdin) = child.stdin.take() {
+                // A command that ignores its input may close stdin early; that's not a failure
+                let _ = stdin.write_all(input.as_bytes());
+            }
+            match child.wait() {
+                Ok(status) if !status.success() => eprintln!("{}: exited with {}", name, status),
+                Ok(_) => {}
+                Err(e) => eprintln!("{}: failed to wait for the command: {}", name, e),
+            }
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+}
+
+/// The arguments with every `{}` replaced by `text`, or `None` if there is no `{}` (the text
+/// then goes to stdin).
+fn substitute_commit(args: &[String], text: &str) -> Option<Vec<String>> {
+    args.iter()
+        .any(|arg| arg.contains(COMMIT_PLACEHOLDER))
+        .then(|| args.iter().map(|arg| arg.replace(COMMIT_PLACEHOLDER, text)).collect())
+}
+
+/// Opens the sink described by `spec`.
+/// Returns `None` for `SinkSpec::Terminal`, which is handled by the `Terminal` itself.
+pub fn open_sink(spec: &SinkSpec) -> Result<Option<Box<dyn CommitSink>>, FepError> {
+    let open_error = |e: io::Error| FepError::Config(format!("Failed to open commit sink {:?}: {}", spec, e));
+    match spec {
+        SinkSpec::Terminal => Ok(None),
+        SinkSpec::File(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path).map_err(open_error)?;
+            Ok(Some(Box::new(FileSink { path: path.clone(), file })))
+        }
+        SinkSpec::Socket(path, framing) => {
+            let stream = UnixStream::connect(path).map_err(open_error)?;
+            Ok(Some(Box::new(SocketSink { path: path.clone(), stream, framing: *framing })))
+        }
+        SinkSpec::Command(argv) => Ok(Some(Box::new(CommandSink {
+            argv: argv.clone(),
+            running: Arc::new(AtomicUsize::new(0)),
+        }))),
+    }
+}
+
+/// Delivers `text` to every sink. A failing sink does not prevent delivery to the
+/// others; all failures are collected into a single `FepError::CommitSink`.
+pub fn write_all_sinks(sinks: &mut [Box<dyn CommitSink>], text: &str) -> Result<(), FepError> {
+    let failures: Vec<(String, io::Error)> = sinks
+        .iter_mut()
+        .filter_map(|sink| sink.write(text).err().map(|e| (sink.name(), e)))
+        .collect();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(FepError::CommitSink(failures))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+
+    const COMMITS: [&str; 7] = ["plain", "", "日本語", "line\nbreak", "nul\0byte", "back\\slash", "\\n\\0\n\0"];
+
+    /// What a consumer does with the stream: splits it into frames and undoes the escaping.
+    fn unframe(framing: Framing, mut bytes: &[u8]) -> Vec<String> {
+        let mut commits = Vec::new();
+        while !bytes.is_empty() {
+            if framing == Framing::LengthPrefixed {
+                let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+                commits.push(String::from_utf8(bytes[4..4 + len].to_vec()).unwrap());
+                bytes = &bytes[4 + len..];
+                continue;
+            }
+            let delimiter = if framing == Framing::Newline { b'\n' } else { b'\0' };
+            let end = bytes.iter().position(|&b| b == delimiter).expect("unterminated frame");
+            let mut text = Vec::new();
+            let mut escaped = false;
+            for &b in &bytes[..end] {
+                if !escaped && b == b'\\' {
+                    escaped = true;
+                    continue;
+                }
+                text.push(match (escaped, b) {
+                    (false, _) | (true, b'\\') => b,
+                    (true, b'n') => b'\n',
+                    (true, b'0') => b'\0',
+                    (true, b) => panic!("bad escape '\\{}'", b as char),
+                });
+                escaped = false;
+            }
+            assert!(!escaped, "frame ends inside an escape");
+            commits.push(String::from_utf8(text).unwrap());
+            bytes = &bytes[end + 1..];
+        }
+        commits
+    }
+
+    #[test]
+    fn commits_round_trip_through_every_framing() {
+        for framing in [Framing::Newline, Framing::Nul, Framing::LengthPrefixed] {
+            let stream: Vec<u8> = COMMITS.iter().flat_map(|text| framing.frame(text)).collect();
+            assert_eq!(unframe(framing, &stream), COMMITS, "{:?}", framing);
+        }
+    }
+
+    #[test]
+    fn delimiters_inside_a_commit_are_escaped() {
+        assert_eq!(Framing::Newline.frame("a\nb\\"), b"a\\nb\\\\\n");
+        assert_eq!(Framing::Nul.frame("a\0b\n"), b"a\\0b\n\0");
+        assert_eq!(Framing::LengthPrefixed.frame("a\nb"), b"\x03\0\0\0a\nb");
+        assert_eq!(Framing::Raw.frame("a\nb\\"), b"a\nb\\");
+    }
+
+    #[test]
+    fn placeholder_is_replaced_inside_arguments_too() {
+        let args = |argv: &[&str]| argv.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(substitute_commit(&args(&["IME", "{}"]), "変換"), Some(args(&["IME", "変換"])));
+        assert_eq!(substitute_commit(&args(&["got: {}", "-t", "{}x"]), "a b"), Some(args(&["got: a b", "-t", "a bx"])));
+        // The text is never re-parsed, so quotes and `{}` in it stay literal
+        assert_eq!(substitute_commit(&args(&["{}"]), "'{}'"), Some(args(&["'{}'"])));
+        // Without a placeholder the text goes to stdin
+        assert_eq!(substitute_commit(&args(&["-a", "commits.txt"]), "x"), None);
+        assert_eq!(substitute_commit(&[], "x"), None);
+    }
+
+    #[test]
+    fn socket_sink_sends_one_frame_per_commit() {
+        for (kind, framing) in [("newline", Framing::Newline), ("nul", Framing::Nul), ("length", Framing::LengthPrefixed)] {
+            let path = std::env::temp_dir().join(format!("fcitx5-fep-rust-sink-{}-{}", std::process::id(), kind));
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).unwrap();
+            let spec = SinkSpec::parse(&format!("socket+{}:{}", kind, path.display())).unwrap();
+            assert_eq!(spec, SinkSpec::Socket(path.clone(), framing));
+
+            let mut sink = open_sink(&spec).unwrap().unwrap();
+            let (mut consumer, _) = listener.accept().unwrap();
+            for text in COMMITS {
+                sink.write(text).unwrap();
+            }
+            drop(sink);
+            let mut received = Vec::new();
+            consumer.read_to_end(&mut received).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(unframe(framing, &received), COMMITS, "{:?}", framing);
+        }
+    }
+}
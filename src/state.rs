@@ -1,241 +1,400 @@
-// src/fcitx.rs
-// Handles asynchronous communication with the Fcitx5 daemon via D-Bus using zbus.
-
-use crate::error::FepError;
-use crate::state::FcitxUpdate;
-use std::collections::HashMap;
-use zbus::{Connection, Proxy};
-use zbus::zvariant::{ObjectPath, OwnedObjectPath, Type, Value}; // Use OwnedObjectPath
-use zbus_macros::{proxy, DeserializeInto, Serialize};
-use futures_util::{Stream, StreamExt}; // Stream and StreamExt for handling signals
-use tokio_stream::wrappers::SignalStream; // Wrapper for zbus signals
-
-// --- D-Bus Constants ---
-const FCITX5_SERVICE: &str = "org.fcitx.Fcitx5";
-const FCITX5_IFACE_CONTROLLER: &str = "org.fcitx.Fcitx.Controller1";
-const FCITX5_IFACE_IC: &str = "org.fcitx.Fcitx.InputContext1";
-const FCITX5_PATH: &str = "/org/fcitx/Fcitx5";
-
-// --- D-Bus Proxy Definitions ---
-
-#[proxy(
-    interface = "org.fcitx.Fcitx.Controller1",
-    default_service = "org.fcitx.Fcitx5",
-    default_path = "/org/fcitx/Fcitx5"
-)]
-trait FcitxController {
-    /// Creates an input context for an application.
-    #[zbus(name = "CreateInputContext")]
-    async fn create_input_context(
-        &self,
-        args: &HashMap<&str, zbus::zvariant::Value<'_>>, // e.g., {"program": "my_app"}
-    ) -> zbus::Result<(OwnedObjectPath, u32)>; // Returns IC path and capabilities
+// src/state.rs
+// Holds the application state (preedit, pending commits) shared by the event loop and renderer.
+
+use crate::line_editor::LineEditor;
+use crate::width::{display_width, grapheme_width, last_grapheme};
+
+/// Candidates per page assumed until Fcitx's setting is known.
+pub const DEFAULT_PAGE_SIZE: usize = 10;
+
+/// Updates received from Fcitx via D-Bus signals.
+#[derive(Debug, Clone)]
+pub enum FcitxUpdate {
+    /// Text that should be committed (inserted) as-is.
+    CommitString(String),
+    /// Text to commit, with the cursor left `cursor` characters into it (editing-capable engines).
+    CommitStringWithCursor { text: String, cursor: usize },
+    /// The current preedit (composition) text and the cursor position within it.
+    UpdatePreedit { text: String, cursor_pos: i32 },
+    /// Fcitx's candidate list and whether it shows any aux text (from UpdateClientSideUI),
+    /// with whether there are pages before and after this one.
+    ClientSideUi { candidates: Vec<String>, highlighted: Option<usize>, aux_visible: bool, has_prev: bool, has_next: bool },
+    /// The input method of the input context changed (from CurrentIM); carries its unique name.
+    CurrentIm(String),
+    /// Fcitx's input method configuration changed (from InputMethodGroupsChanged).
+    InputMethodsChanged,
 }
 
-#[proxy(interface = "org.fcitx.Fcitx.InputContext1")]
-trait FcitxInputContext {
-    /// Processes a key event. Returns true if handled by Fcitx.
-    #[zbus(name = "ProcessKeyEvent")]
-    async fn process_key_event(
-        &self,
-        keysym: u32,
-        keycode: u32,
-        state: u32,
-        is_release: bool,
-        time: u32,
-    ) -> zbus::Result<bool>;
-
-    /// Notifies Fcitx that the input context gained focus.
-    #[zbus(name = "FocusIn")]
-    async fn focus_in(&self) -> zbus::Result<()>;
-
-    /// Notifies Fcitx that the input context lost focus.
-    #[zbus(name = "FocusOut")]
-    async fn focus_out(&self) -> zbus::Result<()>;
-
-    /// Resets the input context state.
-    #[zbus(name = "Reset")]
-    async fn reset(&self) -> zbus::Result<()>;
-
-    /// Sets the position of the cursor rectangle (for candidate window placement).
-    #[zbus(name = "SetCursorRect")]
-    async fn set_cursor_rect(&self, x: i32, y: i32, w: i32, h: i32) -> zbus::Result<()>;
-
-    // --- Signals ---
-
-    /// Signal emitted when text should be committed.
-    #[zbus(signal)]
-    async fn commit_string(&self, str: String) -> zbus::Result<()>;
-
-    /// Signal emitted when the preedit text changes (with formatting).
-    #[zbus(signal)]
-    async fn update_formatted_preedit(&self, text: Vec<FormattedText>, cursor_pos: i32) -> zbus::Result<()>;
-
-    /// Signal emitted when surrounding text should be deleted.
-    // #[zbus(signal)]
-    // async fn delete_surrounding_text(&self, offset: i32, n_chars: u32) -> zbus::Result<()>;
+/// The input method Fcitx uses for the FEP's input context.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImStatus {
+    /// Unique name of the input method (e.g. "mozc", "keyboard-us").
+    pub name: String,
+    /// Whether the input method is active (not the plain keyboard layout), if known.
+    pub active: Option<bool>,
 }
 
-/// Represents a segment of formatted preedit text.
-#[derive(DeserializeInto, Type, Debug, Clone)]
-pub struct FormattedText {
-    text: String,
-    format: i32, // Corresponds to FcitxFormattedPreeditFormat enum (e.g., 0=None, 1=Underline)
+/// Current state of the FEP as seen by the renderer.
+#[derive(Debug, Default)]
+pub struct AppState {
+    /// Current preedit (composition) string.
+    pub preedit_string: String,
+    /// Cursor position inside the preedit string (character index).
+    pub preedit_cursor_pos: usize,
+    /// Display width of `preedit_string` in terminal cells, kept up to date with it: a
+    /// preedit that extends the previous one (plain typing) only has the new text measured.
+    pub preedit_width: usize,
+    /// Display width of the preedit before its cursor.
+    pub preedit_cursor_width: usize,
+    /// Committed text that has not been written to the terminal yet.
+    /// Consecutive commits are appended here and flushed together by the next render.
+    pub commit_string: String,
+    /// Where the cursor should end up inside `commit_string` (character index), if the last
+    /// commit placed it somewhere other than the end.
+    pub commit_cursor: Option<usize>,
+    /// Keys echoed locally before Fcitx responded (optimistic echo mode).
+    /// Replaced by the real state on the next update from Fcitx.
+    pub optimistic_echo: String,
+    /// History entry currently shown for recall; committed with Enter, dropped by other keys.
+    pub recall: Option<String>,
+    /// Whether Fcitx shows a candidate/aux UI; `None` until Fcitx has reported it
+    /// (older versions never do), in which case the UI is assumed to be visible.
+    pub ui_visible: Option<bool>,
+    /// Candidates on the current page, as reported by UpdateClientSideUI.
+    pub candidates: Vec<String>,
+    /// Index of the highlighted candidate in `candidates`.
+    pub highlighted_candidate: Option<usize>,
+    /// Whether Fcitx has candidate pages before/after the current one.
+    pub has_prev_page: bool,
+    pub has_next_page: bool,
+    /// Fcitx's candidates per page, once known (see `page_size`).
+    pub known_page_size: Option<usize>,
+    /// Current input method; `None` until known (queried at connect, then kept up to date).
+    pub im: Option<ImStatus>,
+    /// Local line editor (line-edit mode); commits go into it instead of `commit_string`.
+    pub line: Option<LineEditor>,
+    /// Short status shown after the preedit, e.g. while a literal key is armed.
+    pub status_hint: Option<String>,
+    /// Whether our input context has focus in Fcitx, i.e. keys currently go to an input method.
+    pub focused: bool,
 }
 
-// --- Fcitx Client Implementation (Async) ---
+impl AppState {
+    /// Creates an empty application state.
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-pub struct FcitxClient<'a> {
-    connection: Connection, // Async Connection
-    ic_proxy: Option<FcitxInputContextProxy<'a>>, // Async Proxy for the Input Context
-    ic_path: Option<OwnedObjectPath>, // Store the path for signal matching if needed (proxy handles it)
-}
+    /// Applies an update received from Fcitx to the state.
+    pub fn apply_update(&mut self, update: FcitxUpdate) {
+        // UI visibility says nothing about the text, so it doesn't touch the echo.
+        if let FcitxUpdate::ClientSideUi { candidates, highlighted, aux_visible, has_prev, has_next } = update {
+            self.ui_visible = Some(aux_visible || !candidates.is_empty());
+            self.highlighted_candidate = highlighted.filter(|&index| index < candidates.len());
+            // A page followed by another one is full, so its length is the engine's actual
+            // page size; this also picks up the setting changing while the FEP runs.
+            if has_next && !candidates.is_empty() {
+                self.known_page_size = Some(candidates.len());
+            }
+            self.has_prev_page = has_prev;
+            self.has_next_page = has_next;
+            self.candidates = candidates;
+            return;
+        }
+        // Neither does an input method change. Fcitx counts the keyboard layouts as inactive.
+        if let FcitxUpdate::CurrentIm(name) = update {
+            let active = Some(!name.starts_with("keyboard-"));
+            self.im = Some(ImStatus { name, active });
+            return;
+        }
+        if let FcitxUpdate::InputMethodsChanged = update {
+            return; // Checked by the event loop, which has the D-Bus connection
+        }
+        // Any real update supersedes the optimistic echo.
+        self.optimistic_echo.clear();
+        match update {
+            FcitxUpdate::CommitString(text) if text.is_empty() => {
+                // Fcitx sends an empty commit to reset the composition; nothing to write.
+                self.clear_preedit();
+            }
+            FcitxUpdate::UpdatePreedit { text, .. } if text.is_empty() => {
+                // An empty preedit (no segments) means "clear preedit".
+                self.clear_preedit();
+            }
+            FcitxUpdate::CommitStringWithCursor { text, cursor } => {
+                // Like a plain commit, but remember where in the buffer the cursor goes.
+                let cursor = cursor.min(text.chars().count());
+                if let Some(line) = self.line.as_mut() {
+                    let start = line.cursor();
+                    line.insert(&text);
+                    line.set_cursor(start + cursor);
+                } else {
+                    let start = self.commit_cursor.unwrap_or_else(|| self.commit_string.chars().count());
+                    self.push_commit(&text);
+                    self.commit_cursor = Some(start + cursor);
+                }
+                self.clear_preedit();
+            }
+            FcitxUpdate::CommitString(text) => {
+                // Buffer the commit instead of replacing it, so that several commits
+                // arriving in the same event-loop turn end up in a single write.
+                self.push_commit(&text);
+                // A commit finishes the current composition.
+                self.clear_preedit();
+            }
+            FcitxUpdate::UpdatePreedit { text, cursor_pos } => {
+                // Fcitx reports the cursor as a byte offset; convert it to a character index.
+                let byte_pos = (cursor_pos.max(0) as usize).min(text.len());
+                let cursor = text
+                    .char_indices()
+                    .take_while(|(i, _)| *i < byte_pos)
+                    .count();
+                self.set_preedit(text, cursor);
+            }
+            FcitxUpdate::ClientSideUi { .. } | FcitxUpdate::CurrentIm(_) | FcitxUpdate::InputMethodsChanged => {} // Handled above
+        }
+    }
 
-impl<'a> FcitxClient<'a> {
-    /// Establishes an async connection to Fcitx5 and creates an input context.
-    pub async fn connect() -> Result<Self, FepError> {
-        println!("Connecting to Fcitx5 via D-Bus (async)...");
-        let connection = Connection::session().await?; // Use ? for From<zbus::Error>
-        println!("D-Bus session connection established.");
-
-        let controller_proxy = FcitxControllerProxy::new(&connection).await?;
-        println!("Fcitx controller proxy created.");
-
-        // Prepare arguments for CreateInputContext
-        let mut args = HashMap::new();
-        // Use a unique name for the application if possible
-        args.insert("program", Value::from("fcitx5-fep-rust").into());
-        // Optionally add display, capabilities etc.
-        // args.insert("display", Value::from(std::env::var("DISPLAY").unwrap_or(":0".to_string())));
-
-        println!("Calling CreateInputContext (async)...");
-        let (ic_path, _ic_caps) = controller_proxy.create_input_context(&args).await?;
-        println!("Input Context created at path: {}", ic_path);
-
-        // Create the async proxy for the newly created Input Context
-        let ic_proxy = FcitxInputContextProxy::builder(&connection)
-            .path(ic_path.clone())? // Build proxy for the specific path
-            .build().await?;
-        println!("Input context proxy created.");
-
-        let mut client = FcitxClient {
-            connection,
-            ic_proxy: Some(ic_proxy),
-            ic_path: Some(ic_path), // Store path if needed elsewhere, though proxy knows its path
-        };
+    /// Candidates per page: as configured in Fcitx or seen on a full page, else
+    /// `DEFAULT_PAGE_SIZE`.
+    pub fn page_size(&self) -> usize {
+        self.known_page_size.unwrap_or(DEFAULT_PAGE_SIZE)
+    }
 
-        // Activate the input context by sending FocusIn
-        client.focus_in().await?;
-        println!("Input context focused.");
+    /// Whether Fcitx may be showing a candidate window: a composition is active and Fcitx
+    /// hasn't reported its UI as hidden.
+    pub fn candidates_may_be_open(&self) -> bool {
+        !self.preedit_string.is_empty() && self.ui_visible != Some(false)
+    }
 
-        Ok(client)
+    /// Whether SetCursorRect updates are worth sending: only while there is a preedit and
+    /// Fcitx may be showing a popup next to it.
+    pub fn wants_cursor_rect(&self) -> bool {
+        self.candidates_may_be_open()
     }
 
-    /// Returns a combined stream of relevant Fcitx updates (CommitString, UpdateFormattedPreedit).
-    /// The stream yields Result<FcitxUpdate, FepError>.
-    pub async fn receive_updates(&self) -> Result<impl Stream<Item = Result<FcitxUpdate, FepError>> + '_, FepError> {
-        let proxy = self.ic_proxy.as_ref().ok_or_else(|| FepError::FcitxConnection("Input context proxy not available for signals".to_string()))?;
+    /// Echoes a key locally before Fcitx confirms it (optimistic echo mode).
+    pub fn echo_optimistically(&mut self, c: char) {
+        self.optimistic_echo.push(c);
+    }
 
-        // Create streams for individual signals using the proxy methods
-        let commit_signal_stream = proxy.receive_commit_string().await?;
-        let preedit_signal_stream = proxy.receive_update_formatted_preedit().await?;
+    /// Rolls back the most recent optimistic echo (e.g. the engine didn't take the key).
+    pub fn rollback_optimistic(&mut self) {
+        self.optimistic_echo.pop();
+    }
 
-        // Map the signal arguments (contained in Result<SignalArgsType, zbus::Error>) to our FcitxUpdate enum
-        let commit_stream = commit_signal_stream.map(|args_result| {
-             args_result
-                 .map(|args| FcitxUpdate::CommitString(args.str)) // Access args by name defined in signal method
-                 .map_err(FepError::from) // Convert zbus::Error to FepError
-        });
+    /// Moves the current preedit into the commit buffer as-is and clears the preedit.
+    pub fn commit_preedit(&mut self) {
+        let preedit = std::mem::take(&mut self.preedit_string);
+        self.push_commit(&preedit);
+        self.clear_preedit();
+    }
 
-        let preedit_stream = preedit_signal_stream.map(|args_result| {
-             args_result.map(|args| {
-                 // args is (Vec<FormattedText>, i32)
-                 let text = args.text.into_iter().map(|s| s.text).collect::<String>();
-                 let cursor_pos = args.cursor_pos; // Cursor position in bytes
-                 println!("Raw Preedit Signal: text='{}', cursor_pos={}", text, cursor_pos);
-                 FcitxUpdate::UpdatePreedit { text, cursor_pos }
-             })
-             .map_err(FepError::from) // Convert zbus::Error to FepError
-        });
+    /// Replaces the preedit, with the cursor at character `cursor`, and updates its widths.
+    fn set_preedit(&mut self, text: String, cursor: usize) {
+        self.preedit_width = match text.strip_prefix(self.preedit_string.as_str()) {
+            // Only the last grapheme cluster can change by appending to it
+            Some(added) if !self.preedit_string.is_empty() => {
+                let tail = last_grapheme(&self.preedit_string);
+                self.preedit_width - grapheme_width(tail) + display_width(&format!("{}{}", tail, added))
+            }
+            _ => display_width(&text),
+        };
+        self.preedit_cursor_width = match text.char_indices().nth(cursor) {
+            Some((byte_pos, _)) => display_width(&text[..byte_pos]),
+            None => self.preedit_width,
+        };
+        self.preedit_string = text;
+        self.preedit_cursor_pos = cursor;
+    }
 
-        // Merge the two streams into a single stream using tokio_stream::StreamExt::merge
-        Ok(tokio_stream::StreamExt::merge(commit_stream, preedit_stream))
+    /// Clears the preedit (the composition ended).
+    fn clear_preedit(&mut self) {
+        self.preedit_string.clear();
+        self.preedit_cursor_pos = 0;
+        self.preedit_width = 0;
+        self.preedit_cursor_width = 0;
     }
 
-    /// Sends FocusIn signal to the input context (async).
-    pub async fn focus_in(&mut self) -> Result<(), FepError> {
-        if let Some(proxy) = self.ic_proxy.as_mut() {
-            proxy.focus_in().await?;
+    /// Adds committed text: to the line editor at its cursor in line-edit mode, otherwise to
+    /// the commit buffer at its cursor (the end, unless a commit placed it inside; see
+    /// `commit_cursor`), so coalesced commits give the same line as separately drawn ones.
+    /// The text is taken as final: for engine commits Fcitx already knows about it, and for
+    /// FEP-originated text see `FcitxClient::commit_text`.
+    pub fn push_commit(&mut self, text: &str) {
+        match self.line.as_mut() {
+            Some(line) => line.insert(text),
+            None => match self.commit_cursor {
+                // After a commit that placed the cursor inside it, text goes in at the cursor
+                Some(cursor) => {
+                    let byte_pos = self.commit_string.char_indices().nth(cursor).map_or(self.commit_string.len(), |(i, _)| i);
+                    self.commit_string.insert_str(byte_pos, text);
+                    self.commit_cursor = Some(cursor + text.chars().count());
+                }
+                None => self.commit_string.push_str(text),
+            },
         }
-        Ok(())
     }
 
-     /// Sends FocusOut signal to the input context (async).
-    pub async fn focus_out(&mut self) -> Result<(), FepError> {
-        if let Some(proxy) = self.ic_proxy.as_mut() {
-            proxy.focus_out().await?;
-        }
-        Ok(())
+    /// The edited line and its cursor (characters) in line-edit mode, i.e. what Fcitx should
+    /// see as surrounding text. Outside line-edit mode committed text has left the FEP.
+    pub fn line_surrounding_text(&self) -> Option<(String, u32)> {
+        self.line.as_ref().map(|line| (line.text().to_string(), line.cursor() as u32))
     }
 
-    /// Sends Reset signal to the input context (async).
-     pub async fn reset(&mut self) -> Result<(), FepError> {
-        if let Some(proxy) = self.ic_proxy.as_mut() {
-            proxy.reset().await?;
-        }
-        Ok(())
-    }
-
-    /// Sends a key event to Fcitx5 using provided keysym, keycode, and state (async).
-    pub async fn forward_key_event(
-        &mut self,
-        keysym: u32,
-        keycode: u32, // Placeholder (0) is often acceptable
-        state: u32,   // Modifier state mask
-        is_release: bool, // Currently assuming false (press only)
-    ) -> Result<bool, FepError> {
-        let proxy = self.ic_proxy.as_mut().ok_or_else(|| FepError::FcitxConnection("Input context proxy not available".to_string()))?;
-        let time = 0; // Event timestamp, 0 is usually fine
-
-        println!(
-            "Forwarding key to Fcitx5 (async): keysym=0x{:x}, keycode={}, state={}, release={}",
-            keysym, keycode, state, is_release
-        );
-
-        // Call the D-Bus method asynchronously
-        match proxy.process_key_event(keysym, keycode, state, is_release, time).await {
-            Ok(handled) => {
-                println!("Fcitx handled key event: {}", handled);
-                Ok(handled)
+    /// Returns true if there is committed text waiting to be written.
+    pub fn has_pending_commit(&self) -> bool {
+        !self.commit_string.is_empty()
+    }
+
+    /// Takes the buffered commit text, leaving the buffer empty.
+    pub fn take_commit(&mut self) -> String {
+        self.commit_cursor = None;
+        std::mem::take(&mut self.commit_string)
+    }
+
+    /// Takes the buffered commit text split at the cursor position (see `commit_cursor`):
+    /// the text before the cursor and the text after it (empty if the cursor is at the end).
+    pub fn take_commit_split(&mut self) -> (String, String) {
+        let cursor = self.commit_cursor.take();
+        let mut before = self.take_commit();
+        let after = match cursor {
+            Some(cursor) => match before.char_indices().nth(cursor) {
+                Some((byte_pos, _)) => before.split_off(byte_pos),
+                None => String::new(),
             },
-            Err(e) => {
-                 eprintln!("Error forwarding key event: {}", e);
-                 Err(FepError::from(e)) // Convert zbus::Error
-            }
+            None => String::new(),
+        };
+        (before, after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit_with_cursor(text: &str, cursor: usize) -> FcitxUpdate {
+        FcitxUpdate::CommitStringWithCursor { text: text.to_string(), cursor }
+    }
+
+    fn preedit(text: &str, cursor_pos: i32) -> FcitxUpdate {
+        FcitxUpdate::UpdatePreedit { text: text.to_string(), cursor_pos }
+    }
+
+    #[test]
+    fn consecutive_commits_are_buffered_until_taken() {
+        let mut state = AppState::new();
+        state.apply_update(FcitxUpdate::CommitString("日本".to_string()));
+        state.apply_update(FcitxUpdate::CommitString("語".to_string()));
+        assert!(state.has_pending_commit());
+        assert_eq!(state.take_commit_split(), ("日本語".to_string(), String::new()));
+        assert!(!state.has_pending_commit());
+        assert_eq!(state.take_commit(), "");
+    }
+
+    #[test]
+    fn empty_preedit_update_clears_the_preedit() {
+        let mut state = AppState::new();
+        state.apply_update(preedit("にほん", 9));
+        state.apply_update(preedit("", 0));
+        assert_eq!((state.preedit_string.as_str(), state.preedit_cursor_pos), ("", 0));
+        assert!(!state.has_pending_commit());
+    }
+
+    #[test]
+    fn empty_commit_clears_the_preedit_without_committing() {
+        let mut state = AppState::new();
+        state.apply_update(preedit("にほん", 9));
+        state.apply_update(FcitxUpdate::CommitString(String::new()));
+        assert_eq!((state.preedit_string.as_str(), state.preedit_cursor_pos), ("", 0));
+        assert!(!state.has_pending_commit());
+    }
+
+    #[test]
+    fn preedit_cursor_is_converted_from_bytes_to_characters() {
+        let mut state = AppState::new();
+        // "aあb": 'a' is one byte, 'あ' three
+        for (byte_pos, char_pos) in [(0, 0), (1, 1), (4, 2), (5, 3)] {
+            state.apply_update(preedit("aあb", byte_pos));
+            assert_eq!(state.preedit_cursor_pos, char_pos, "byte offset {}", byte_pos);
         }
+        // An offset inside a character counts that character as before the cursor
+        state.apply_update(preedit("aあb", 2));
+        assert_eq!(state.preedit_cursor_pos, 2);
+        // Negative and out-of-range offsets are clamped
+        state.apply_update(preedit("aあb", -1));
+        assert_eq!(state.preedit_cursor_pos, 0);
+        state.apply_update(preedit("aあb", 100));
+        assert_eq!(state.preedit_cursor_pos, 3);
     }
 
-    /// Performs asynchronous cleanup before dropping if necessary.
-    /// Currently only sends FocusOut.
-    pub async fn disconnect(&mut self) {
-        println!("Disconnecting from Fcitx5 (async)...");
-        if let Some(proxy) = self.ic_proxy.as_mut() {
-            // Try to send FocusOut, ignore error if it fails during shutdown
-            let _ = proxy.focus_out().await;
+    #[test]
+    fn preedit_widths_follow_every_update() {
+        let mut state = AppState::new();
+        // Typing: each preedit extends the previous one
+        let mut typed = String::new();
+        for c in "かんじ変換abc".chars() {
+            typed.push(c);
+            state.apply_update(preedit(&typed, typed.len() as i32));
+            assert_eq!(state.preedit_width, display_width(&typed), "{}", typed);
+            assert_eq!(state.preedit_cursor_width, state.preedit_width);
         }
-        // Clear the proxy and path
-        self.ic_proxy = None;
-        self.ic_path = None;
-        println!("Fcitx5 client disconnected.");
+        // A replaced preedit, with the cursor in the middle
+        state.apply_update(preedit("漢字x", 3));
+        assert_eq!((state.preedit_width, state.preedit_cursor_width), (5, 2));
+        // Appending can extend the last grapheme cluster instead of adding one
+        state.apply_update(preedit("a👨", 1));
+        state.apply_update(preedit("a👨\u{200d}👩", 1));
+        assert_eq!((state.preedit_width, state.preedit_cursor_width), (3, 1));
+        state.apply_update(FcitxUpdate::CommitString("a".to_string()));
+        assert_eq!((state.preedit_width, state.preedit_cursor_width), (0, 0));
+    }
+
+    #[test]
+    fn commit_with_cursor_places_the_cursor_after_buffered_text() {
+        let mut state = AppState::new();
+        state.apply_update(FcitxUpdate::CommitString("ab".to_string()));
+        state.apply_update(commit_with_cursor("「」", 1));
+        assert_eq!(state.commit_cursor, Some(3));
+        assert_eq!(state.take_commit_split(), ("ab「".to_string(), "」".to_string()));
+        assert_eq!(state.commit_cursor, None);
+    }
+
+    #[test]
+    fn commit_with_cursor_clamps_the_cursor_to_the_text() {
+        let mut state = AppState::new();
+        state.apply_update(commit_with_cursor("あい", 5));
+        assert_eq!(state.commit_cursor, Some(2));
+        assert_eq!(state.take_commit_split(), ("あい".to_string(), String::new()));
+    }
+
+    #[test]
+    fn plain_commit_after_a_cursor_commit_goes_in_at_the_cursor() {
+        let mut state = AppState::new();
+        state.apply_update(commit_with_cursor("()", 1));
+        state.apply_update(FcitxUpdate::CommitString("x".to_string()));
+        assert_eq!(state.commit_cursor, Some(2));
+        assert_eq!(state.take_commit_split(), ("(x".to_string(), ")".to_string()));
+    }
+
+    #[test]
+    fn commit_with_cursor_in_line_edit_mode_moves_the_line_cursor() {
+        let mut state = AppState::new();
+        let mut line = LineEditor::new();
+        line.insert("ab");
+        line.set_cursor(1);
+        state.line = Some(line);
+        state.apply_update(commit_with_cursor("「」", 1));
+        let line = state.line.as_ref().unwrap();
+        assert_eq!((line.text(), line.cursor()), ("a「」b", 2));
+        assert!(!state.has_pending_commit());
     }
-}
 
-// Drop implementation for automatic cleanup (cannot be async)
-impl<'a> Drop for FcitxClient<'a> {
-    fn drop(&mut self) {
-        // If async cleanup (like FocusOut) is critical, it should be called explicitly
-        // via `disconnect().await` before dropping the client.
-        // Dropping the `zbus::Connection` handles closing the D-Bus connection.
-        println!("FcitxClient dropped, D-Bus connection will be closed.");
+    #[test]
+    fn commit_with_cursor_ends_the_composition() {
+        let mut state = AppState::new();
+        state.apply_update(FcitxUpdate::UpdatePreedit { text: "かっこ".to_string(), cursor_pos: 9 });
+        state.apply_update(commit_with_cursor("()", 1));
+        assert_eq!((state.preedit_string.as_str(), state.preedit_cursor_pos), ("", 0));
     }
 }
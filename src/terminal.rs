@@ -1,138 +1,949 @@
 // src/terminal.rs
 // Handles terminal setup, raw mode, rendering, and provides an async event stream.
 
+use crate::config::{ColorMode, CommitDisplay, Config};
 use crate::error::FepError;
-use crate::state::AppState;
+use crate::event_log::EventLog;
+use crate::render::elide_preedit;
+use crate::sink::{self, CommitSink, SinkSpec};
+use crate::state::{AppState, ImStatus};
+use crate::width::{display_width, grapheme_width, last_grapheme};
 use crossterm::{
-    cursor::{self, MoveLeft, MoveToColumn}, // Import cursor commands
-    event::{self, Event, KeyCode, KeyEvent, KeyModifiers, EventStream}, // Use EventStream
+    cursor::{self, MoveToColumn, MoveUp}, // Import cursor commands
+    event::{
+        DisableFocusChange, EnableFocusChange, Event, KeyEvent, EventStream, // Use EventStream
+        KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
     execute, // For executing terminal commands
     style::{Attribute, Print, SetAttribute}, // For styling output
     terminal::{self, Clear, ClearType}, // For terminal control (raw mode, clear)
+    tty::IsTty, // For detecting non-interactive stdin
 };
-use std::io::{self, Stdout, Write};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use futures_util::{Stream, StreamExt}; // Stream and StreamExt for async stream handling
+use unicode_segmentation::UnicodeSegmentation;
 
-// Optional: For accurate character width calculation (not used here to minimize deps)
-// use unicode_width::UnicodeWidthStr;
+/// Input read from the terminal.
+#[derive(Debug)]
+pub enum TerminalInput {
+    Key(KeyEvent),
+    /// The terminal gained (`true`) or lost focus; only reported with --follow-terminal-focus.
+    Focus(bool),
+}
 
 /// Manages terminal state and interaction.
 pub struct Terminal {
-    stdout: Stdout, // Handle to standard output
+    stdout: Box<dyn Write + Send>, // Standard output (a buffer in tests)
+    max_preedit_len: usize, // Maximum number of preedit characters drawn
+    preedit_overflow_warned: bool, // Whether the "preedit too long" warning was already logged
+    origin_col: u16, // Column where the preedit starts (right after the last committed text)
+    commit_tail: String, // Last grapheme cluster printed before the origin, which a later commit may extend
+    commit_after: String, // Committed text right of the origin (a commit placed the cursor inside it), redrawn after the preedit
+    cursor_row_offset: u16, // Rows between the origin and the cursor (for wrapped preedits)
+    commit_to_terminal: bool, // Whether commits are printed in the terminal
+    scroll_commits: bool, // Whether each printed commit ends with a line break (--commit-display scroll)
+    commit_sinks: Vec<Box<dyn CommitSink>>, // Additional destinations for committed text
+    event_log: Option<EventLog>, // Machine-readable event log (--event-log)
+    styling: Styling, // Which kinds of styling the terminal may receive
+    candidate_rows: usize, // Height of the candidate window (0 = not drawn)
+    candidate_first: usize, // Index of the first candidate inside the scrolled window
+    max_candidates: usize, // Most candidates of a page shown in the window (0 = all)
+    placeholder: String, // Drawn at the cursor while focused with no preedit (--preedit-placeholder)
+    im_title: String, // Terminal title format for the input method (--im-title; empty = off)
+    title_im: Option<ImStatus>, // Input method the title was last set for
+    keyboard_enhanced: bool, // Whether the kitty keyboard protocol flags were pushed
+    focus_reporting: bool, // Whether focus change reporting was enabled (--follow-terminal-focus)
+    render_failures: u32, // Consecutive transient render failures
+    redraw_pending: bool, // Whether a failed render may have left the cursor somewhere unknown
+}
+
+/// Consecutive transient render failures after which rendering errors become fatal.
+const MAX_RENDER_FAILURES: u32 = 5;
+
+/// Saves and restores the window title (xterm's XTWINOPS title stack, also supported by
+/// VTE, kitty, foot and others); terminals without the stack ignore them.
+const PUSH_TITLE: &str = "\x1b[22;2t";
+const POP_TITLE: &str = "\x1b[23;2t";
+
+/// Styling capabilities of the output terminal.
+#[derive(Debug, Clone, Copy)]
+struct Styling {
+    attributes: bool, // Monochrome attributes (underline, reverse)
+}
+
+impl Styling {
+    /// Resolves the styling to use from the configured mode and the environment.
+    /// The renderer only emits monochrome attributes, which `NO_COLOR` permits, so in
+    /// `Auto` mode they are kept unless the terminal is `dumb`.
+    fn detect(mode: ColorMode) -> Self {
+        match mode {
+            ColorMode::Always => Styling { attributes: true },
+            ColorMode::Never => Styling { attributes: false },
+            ColorMode::Auto => {
+                let dumb = std::env::var("TERM").map_or(true, |term| term == "dumb");
+                Styling { attributes: !dumb }
+            }
+        }
+    }
 }
 
 impl Terminal {
-    /// Creates a new Terminal handler, enters raw mode, and hides the cursor.
+    /// Creates a new Terminal handler, enters raw mode, and hides the cursor (if configured).
     /// This setup is synchronous.
-    pub fn new() -> Result<Self, FepError> {
+    pub fn new(config: &Config) -> Result<Self, FepError> {
+        // Raw mode and the event stream need an interactive terminal; fail early with a
+        // clear message instead of a cryptic raw-mode error when stdin is piped.
+        if !io::stdin().is_tty() {
+            return Err(FepError::TerminalSetup(
+                "stdin is not a terminal. The FEP needs an interactive terminal; \
+                 use --send-key for scripted, non-interactive use."
+                    .to_string(),
+            ));
+        }
+        // Open the configured commit sinks before touching the terminal state
+        let mut commit_sinks = Vec::new();
+        for spec in &config.commit_sinks {
+            if let Some(sink) = sink::open_sink(spec)? {
+                commit_sinks.push(sink);
+            }
+        }
+        let keyboard_enhanced = config.keyboard_enhancement && detect_keyboard_enhancement();
         let mut stdout = io::stdout();
         // Enter raw mode to process key events directly
         terminal::enable_raw_mode()
             .map_err(|e| FepError::TerminalSetup(format!("Failed to enable raw mode: {}", e)))?;
-        // Hide the cursor for cleaner FEP display
-        execute!(stdout, cursor::Hide)
-            .map_err(|e| FepError::TerminalSetup(format!("Failed to hide cursor: {}", e)))?;
-        Ok(Terminal { stdout })
+        // Hide the cursor for cleaner FEP display, unless configured to keep it visible.
+        // When visible, `render` leaves it at the preedit cursor position.
+        if config.hide_cursor {
+            execute!(stdout, cursor::Hide)
+                .map_err(|e| FepError::TerminalSetup(format!("Failed to hide cursor: {}", e)))?;
+        }
+        if keyboard_enhanced {
+            execute!(
+                stdout,
+                PushKeyboardEnhancementFlags(
+                    KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+                        | KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                )
+            )
+            .map_err(|e| FepError::TerminalSetup(format!("Failed to enable keyboard enhancement: {}", e)))?;
+        }
+        if !config.im_title.is_empty() {
+            // Save the current title on the xterm title stack; terminals without one ignore this
+            execute!(stdout, Print(PUSH_TITLE))
+                .map_err(|e| FepError::TerminalSetup(format!("Failed to save the terminal title: {}", e)))?;
+        }
+        if config.follow_terminal_focus {
+            execute!(stdout, EnableFocusChange)
+                .map_err(|e| FepError::TerminalSetup(format!("Failed to enable focus reporting: {}", e)))?;
+        }
+        Terminal::with_output(config, Box::new(stdout), commit_sinks, keyboard_enhanced)
     }
 
-    /// Returns an asynchronous stream of terminal key events.
-    /// Filters out non-key events.
-    pub fn key_event_stream(&self) -> impl Stream<Item = Result<KeyEvent, FepError>> + Send + Unpin {
+    /// Builds the handler around an already set up output; `new` does the terminal setup.
+    fn with_output(
+        config: &Config,
+        stdout: Box<dyn Write + Send>,
+        commit_sinks: Vec<Box<dyn CommitSink>>,
+        keyboard_enhanced: bool,
+    ) -> Result<Self, FepError> {
+        Ok(Terminal {
+            stdout,
+            max_preedit_len: config.max_preedit_len,
+            preedit_overflow_warned: false,
+            origin_col: 0,
+            commit_tail: String::new(),
+            commit_after: String::new(),
+            cursor_row_offset: 0,
+            commit_to_terminal: config.commit_sinks.contains(&SinkSpec::Terminal)
+                && config.commit_display != CommitDisplay::Suppress,
+            scroll_commits: config.commit_display == CommitDisplay::Scroll,
+            commit_sinks,
+            event_log: config.event_log.as_deref().map(EventLog::open).transpose()?,
+            styling: Styling::detect(config.color),
+            candidate_rows: config.candidate_rows,
+            candidate_first: 0,
+            max_candidates: config.max_candidates,
+            placeholder: config.preedit_placeholder.clone(),
+            im_title: config.im_title.clone(),
+            title_im: None,
+            keyboard_enhanced,
+            focus_reporting: config.follow_terminal_focus,
+            render_failures: 0,
+            redraw_pending: false,
+        })
+    }
+
+    /// Returns an asynchronous stream of terminal key events and focus changes.
+    /// Filters out other events.
+    pub fn input_stream(&self) -> impl Stream<Item = Result<TerminalInput, FepError>> + Send + Unpin {
         EventStream::new() // Create a stream of terminal events
             .filter_map(|maybe_event| async { // Process each event asynchronously
                 match maybe_event {
-                    // If it's a key event, yield it as Ok(KeyEvent)
-                    Ok(Event::Key(key_event)) => Some(Ok(key_event)),
+                    // If it's a key event, yield it as Ok(TerminalInput::Key)
+                    Ok(Event::Key(key_event)) => Some(Ok(TerminalInput::Key(key_event))),
+                    Ok(Event::FocusGained) => Some(Ok(TerminalInput::Focus(true))),
+                    Ok(Event::FocusLost) => Some(Ok(TerminalInput::Focus(false))),
                     // Ignore other event types (Mouse, Resize, etc.)
                     Ok(_) => None,
                     // If there's an error reading the event, yield it as Err(FepError)
                     Err(e) => Some(Err(FepError::Io(e))),
                 }
             })
+            .boxed() // The async filter is not Unpin; boxing pins it
     }
 
 
-    /// Renders the current application state (preedit, commit) to the terminal.
-    /// Handles cursor positioning based on preedit state. This is synchronous.
-    pub fn render(&mut self, state: &AppState) -> Result<(), FepError> {
-        // --- Prepare Rendering Commands ---
+    /// Writes all buffered commit text to the terminal in a single write and clears the buffer.
+    /// Consecutive commits are coalesced by `AppState`, so a burst of commits costs one write.
+    /// The commit replaces any preedit currently drawn and moves the render origin past it.
+    /// The text is also fanned out to every configured commit sink; a failing sink doesn't
+    /// stop delivery to the others, and all failures are reported together.
+    pub fn flush_commits(&mut self, state: &mut AppState) -> Result<(), FepError> {
+        if !state.has_pending_commit() {
+            return Ok(());
+        }
+        // Engine commits were sanitized on arrival (see `CommitFilter::apply_to_update`);
+        // anything else in the buffer (fallback bytes, literal tabs) was put there by the FEP.
+        let (before, after) = state.take_commit_split();
+        let commit = format!("{}{}", before, after);
+        if commit.is_empty() {
+            return Ok(());
+        }
+        if let Some(log) = &self.event_log {
+            log.commit(&commit);
+        }
+        if self.commit_to_terminal {
+            self.clear_preedit_region()?;
+            if self.scroll_commits {
+                // Each commit gets its own line(s); raw mode needs "\r\n" for a line break
+                let text = commit.replace("\r\n", "\n").replace('\n', "\r\n");
+                execute!(self.stdout, Print(&text), Print("\r\n"))?;
+                self.origin_col = 0;
+                self.commit_tail.clear();
+                self.commit_after.clear();
+                self.stdout.flush().map_err(FepError::Io)?;
+                return sink::write_all_sinks(&mut self.commit_sinks, &commit);
+            }
+            // The engine may place the cursor inside the commit. The text after it stays on
+            // screen right of the new origin (clearing the preedit region erased any from an
+            // earlier commit): it is drawn behind every preedit until a commit ends the line.
+            self.commit_after.insert_str(0, &after);
+            if self.commit_after.is_empty() {
+                execute!(self.stdout, Print(&before))?;
+            } else {
+                execute!(self.stdout, Print(&before), cursor::SavePosition, Print(&self.commit_after), cursor::RestorePosition)?;
+            }
+            // A commit may continue the previous one's last grapheme cluster (e.g. an emoji ZWJ
+            // sequence split across commits), which then still occupies the same cells.
+            self.origin_col = advance_column(self.origin_col, &self.commit_tail, &before, terminal_width());
+            self.commit_tail = last_grapheme(&format!("{}{}", self.commit_tail, before)).to_string();
+            self.stdout.flush().map_err(FepError::Io)?;
+        }
+        sink::write_all_sinks(&mut self.commit_sinks, &commit)
+    }
 
-        // 1. Move cursor to the beginning of the line and clear it
+    /// Moves the cursor back to the render origin and clears everything the previous
+    /// preedit occupied, including rows it wrapped onto.
+    /// After a failed render the cursor may be anywhere inside that region, so the origin
+    /// saved by the last render is restored instead (best effort: if the preedit scrolled
+    /// the screen since, the saved row is off).
+    fn clear_preedit_region(&mut self) -> Result<(), FepError> {
+        if self.redraw_pending {
+            execute!(self.stdout, cursor::RestorePosition)?;
+            self.redraw_pending = false;
+            self.cursor_row_offset = 0;
+        } else if self.cursor_row_offset > 0 {
+            execute!(self.stdout, MoveUp(self.cursor_row_offset))?;
+            self.cursor_row_offset = 0;
+        }
         execute!(
             self.stdout,
-            cursor::MoveToColumn(0),
-            Clear(ClearType::FromCursorDown), // Clear from cursor to end of screen might be safer
-                                             // Clear(ClearType::CurrentLine), // Or just clear the current line
+            cursor::MoveToColumn(self.origin_col),
+            Clear(ClearType::FromCursorDown), // Also clears rows the preedit wrapped onto
         )?;
+        Ok(())
+    }
 
-        let mut current_cursor_col: u16 = 0; // Track estimated cursor column
+    /// Renders the current application state (commit, preedit) to the terminal.
+    /// Pending commits are flushed first so they always appear before the preedit.
+    /// Handles cursor positioning based on preedit state. This is synchronous.
+    ///
+    /// A transient write error (e.g. a momentarily full output) is logged and the next render
+    /// redraws the whole region from the saved origin; only `MAX_RENDER_FAILURES` of them in
+    /// a row, or any other error, are returned.
+    pub fn render(&mut self, state: &mut AppState) -> Result<(), FepError> {
+        match self.draw(state) {
+            Ok(()) => {
+                self.render_failures = 0;
+                Ok(())
+            }
+            Err(FepError::Io(e)) if is_transient(&e) && self.render_failures + 1 < MAX_RENDER_FAILURES => {
+                self.render_failures += 1;
+                self.redraw_pending = true;
+                eprintln!(
+                    "Render failed ({}); redrawing on the next update ({} of {} failures in a row).",
+                    e, self.render_failures, MAX_RENDER_FAILURES
+                );
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
 
-        // 2. Render Preedit String (if any)
-        if !state.preedit_string.is_empty() {
-            // Apply underline style and print the preedit text
-            execute!(
-                self.stdout,
-                SetAttribute(Attribute::Underlined),
-                Print(&state.preedit_string),
-                SetAttribute(Attribute::Reset) // Reset style immediately after
-            )?;
+    fn draw(&mut self, state: &mut AppState) -> Result<(), FepError> {
+        // --- Prepare Rendering Commands ---
+
+        // 0. Show a changed input method in the terminal title. It goes to the display only,
+        // never to the commit sinks.
+        self.update_title(state)?;
+
+        // 1. Move cursor back to the render origin and clear the previous preedit.
+        // This also runs for an empty preedit, so a cleared composition leaves no ghost text.
+        self.clear_preedit_region()?;
+
+        // 2. Flush buffered Commit String (if any) before drawing the preedit,
+        // so committed text and preedit keep their order on screen.
+        self.flush_commits(state)?;
+        // Remember the origin for a redraw, should this render fail halfway
+        execute!(self.stdout, cursor::SavePosition)?;
 
-            // Calculate the display width of the preedit string.
-            // WARNING: Using chars().count() is NOT accurate for CJK or wide characters.
-            // For accurate width, use a crate like `unicode_width`.
-            // let preedit_display_width = UnicodeWidthStr::width(state.preedit_string.as_str());
-            let preedit_display_width = state.preedit_string.chars().count(); // Simple char count approximation
+        // 3. Render Preedit String (if any), followed by optimistically echoed keys.
+        // In line-edit mode the preedit is drawn inside the editable line, at its cursor.
+        let (line_before, line_after) = state.line.as_ref().map_or(("", ""), |line| line.split_at_cursor());
+        let placeholder = self.placeholder_text(state);
+        if !state.preedit_string.is_empty()
+            || !state.optimistic_echo.is_empty()
+            || state.recall.is_some()
+            || !line_before.is_empty()
+            || !line_after.is_empty()
+            || !self.commit_after.is_empty()
+            || (self.candidate_rows > 0 && !state.candidates.is_empty())
+            || state.status_hint.is_some()
+            || !placeholder.is_empty()
+        {
+            // Bound the displayed preedit so a runaway engine can't slow down every frame.
+            // A recalled history entry is shown in place of the preedit, with the cursor at its end.
+            let (preedit, cursor_target_char_index) = match &state.recall {
+                Some(recall) => elide_preedit(recall, recall.chars().count(), self.max_preedit_len),
+                None => elide_preedit(&state.preedit_string, state.preedit_cursor_pos, self.max_preedit_len),
+            };
+            if state.recall.is_none() && preedit != state.preedit_string && !self.preedit_overflow_warned {
+                eprintln!(
+                    "Warning: preedit longer than {} characters, eliding display.",
+                    self.max_preedit_len
+                );
+                self.preedit_overflow_warned = true;
+            }
+
+            execute!(self.stdout, Print(line_before))?;
 
-            // Calculate the display width up to the cursor position (character-based).
-            let cursor_target_char_index = state.preedit_cursor_pos;
-            let width_to_cursor = state.preedit_string
-                .chars()
-                .take(cursor_target_char_index)
-                .count(); // Simple char count approximation
+            // Apply underline style (reverse for a recalled entry, if the terminal supports
+            // attributes) and print the preedit text
+            if self.styling.attributes {
+                let attribute = if state.recall.is_some() { Attribute::Reverse } else { Attribute::Underlined };
+                execute!(
+                    self.stdout,
+                    SetAttribute(attribute),
+                    Print(&preedit),
+                    SetAttribute(Attribute::Reset) // Reset style immediately after
+                )?;
+            } else {
+                execute!(self.stdout, Print(&preedit))?;
+            }
 
-            // Move the cursor back from the end of the printed string to the target position.
-            let chars_to_move_left = preedit_display_width.saturating_sub(width_to_cursor);
-            if chars_to_move_left > 0 {
-                execute!(self.stdout, MoveLeft(chars_to_move_left as u16))?;
+            // Keys echoed before Fcitx confirmed them are drawn in a distinct "pending" (dim) style.
+            // The placeholder only shows when there is no preedit or echo, so the cursor stays
+            // in front of it: it is drawn, but never counted as text before the cursor.
+            let pending = &state.optimistic_echo;
+            for dim in [pending.as_str(), placeholder.as_str()] {
+                if dim.is_empty() {
+                    continue;
+                }
+                if self.styling.attributes {
+                    execute!(self.stdout, SetAttribute(Attribute::Dim), Print(dim), SetAttribute(Attribute::Reset))?;
+                } else {
+                    execute!(self.stdout, Print(dim))?;
+                }
             }
-            current_cursor_col = width_to_cursor as u16; // Update estimated cursor column
+            execute!(self.stdout, Print(line_after), Print(&self.commit_after))?;
+            let hint = state.status_hint.as_deref().unwrap_or("");
+            if !hint.is_empty() {
+                if self.styling.attributes {
+                    execute!(self.stdout, SetAttribute(Attribute::Dim), Print(hint), SetAttribute(Attribute::Reset))?;
+                } else {
+                    execute!(self.stdout, Print(hint))?;
+                }
+            }
+
+            // Calculate the display width (in cells, per grapheme cluster) of what was drawn.
+            // NOTE: cursor_placement assumes no cells are skipped, so a wide character that
+            // doesn't fit at the end of a row (and wraps early) can still throw it off by one.
+            // The state keeps the widths of the whole preedit; an elided or recalled one is measured.
+            let (preedit_width, preedit_cursor_width) = if state.recall.is_none() && preedit == state.preedit_string {
+                (state.preedit_width, state.preedit_cursor_width)
+            } else {
+                let before_cursor: String = preedit.chars().take(cursor_target_char_index).collect();
+                (display_width(&preedit), display_width(&before_cursor))
+            };
+            let preedit_display_width = display_width(line_before)
+                + preedit_width
+                + display_width(pending)
+                + display_width(&placeholder)
+                + display_width(line_after)
+                + display_width(&self.commit_after)
+                + display_width(hint);
+
+            // Calculate the display width up to the cursor position.
+            // With pending echo, the cursor sits after the echoed keys.
+            let width_to_cursor = display_width(line_before)
+                + if pending.is_empty() { preedit_cursor_width } else { preedit_width + display_width(pending) };
+
+            // Vertical candidate window on the rows below the preedit. Rows left over from a
+            // longer list were already cleared with the previous preedit region.
+            let candidate_lines = self.candidate_window(state);
+            for (line, highlighted) in &candidate_lines {
+                execute!(self.stdout, Print("\r\n"))?;
+                if *highlighted && self.styling.attributes {
+                    execute!(self.stdout, SetAttribute(Attribute::Reverse), Print(line), SetAttribute(Attribute::Reset))?;
+                } else {
+                    execute!(self.stdout, Print(line))?;
+                }
+            }
+
+            // Move the cursor from the end of the printed string to the target position,
+            // taking rows the preedit wrapped onto (and the candidate window) into account.
+            // Row counts are clamped to u16 so a very long unlimited preedit can't wrap them.
+            let (text_end_row, cursor_row, cursor_col) = cursor_placement(
+                self.origin_col as usize,
+                preedit_display_width,
+                width_to_cursor,
+                terminal_width() as usize,
+            );
+            let end_row = text_end_row + candidate_lines.len();
+            if end_row > cursor_row {
+                execute!(self.stdout, MoveUp(u16::try_from(end_row - cursor_row).unwrap_or(u16::MAX)))?;
+            }
+            execute!(self.stdout, MoveToColumn(cursor_col as u16))?;
+            // Remember how far below the origin we are
+            self.cursor_row_offset = u16::try_from(cursor_row).unwrap_or(u16::MAX);
         }
 
-        // 3. Render Commit String (if any)
-        // This typically happens after preedit is cleared by AppState update.
-        if !state.commit_string.is_empty() {
-            // Print the commit string at the current cursor position (usually column 0 after preedit clear)
-            execute!(self.stdout, Print(&state.commit_string))?;
+        // 4. Flush stdout to make changes visible
+        self.stdout.flush().map_err(FepError::Io)?;
+
+        Ok(())
+    }
+
+    /// Returns the placeholder to draw: only while focused with nothing composed or recalled,
+    /// with `{im}` replaced by the current input method's name.
+    fn placeholder_text(&self, state: &AppState) -> String {
+        let composing = !state.preedit_string.is_empty() || !state.optimistic_echo.is_empty() || state.recall.is_some();
+        if self.placeholder.is_empty() || !state.focused || composing {
+            return String::new();
+        }
+        let im = state.im.as_ref().map_or("", |im| im.name.as_str());
+        self.placeholder.replace("{im}", im)
+    }
 
-            // Update estimated cursor column after printing commit string
-            // WARNING: Again, using chars().count() is not accurate for width.
-            let commit_display_width = state.commit_string.chars().count();
-            current_cursor_col += commit_display_width as u16;
+    /// Sets the terminal title (OSC 2) from the `--im-title` format if the input method or its
+    /// active state changed since the last render.
+    fn update_title(&mut self, state: &AppState) -> Result<(), FepError> {
+        if self.im_title.is_empty() || state.im == self.title_im {
+            return Ok(());
         }
+        let (name, on) = state.im.as_ref().map_or(("", None), |im| (im.name.as_str(), im.active));
+        let title = self
+            .im_title
+            .replace("{im}", name)
+            .replace("{state}", match on {
+                Some(true) => "on",
+                Some(false) => "off",
+                None => "",
+            })
+            .replace(char::is_control, ""); // The name comes from Fcitx; keep it from ending the sequence
+        execute!(self.stdout, Print(format!("\x1b]2;{}\x07", title)))?;
+        self.title_im = state.im.clone();
+        Ok(())
+    }
 
-        // 4. Ensure the cursor is positioned correctly (optional final adjustment)
-        // execute!(self.stdout, cursor::MoveToColumn(current_cursor_col))?;
+    /// Submits a finished line from the line editor: prints it followed by a line break and
+    /// sends it, newline-terminated, to the commit sinks.
+    pub fn submit_line(&mut self, line: &str) -> Result<(), FepError> {
+        if let Some(log) = &self.event_log {
+            log.commit(&format!("{}\n", line));
+        }
+        if self.commit_to_terminal {
+            self.clear_preedit_region()?;
+            execute!(self.stdout, Print(&line), Print("\r\n"))?;
+            self.origin_col = 0;
+            self.commit_tail.clear();
+            self.commit_after.clear();
+            self.stdout.flush().map_err(FepError::Io)?;
+        }
+        sink::write_all_sinks(&mut self.commit_sinks, &format!("{}\n", line))
+    }
 
-        // 5. Flush stdout to make changes visible
+    /// Draws `lines` (e.g. the keybinding debug overlay) in place of the preedit, one per row,
+    /// each truncated to the terminal width. Touches no application state; the next
+    /// `render` clears the overlay like any previous preedit.
+    pub fn render_overlay(&mut self, lines: &[String]) -> Result<(), FepError> {
+        self.clear_preedit_region()?;
+        let cols = terminal_width() as usize;
+        for (row, line) in lines.iter().enumerate() {
+            if row > 0 {
+                execute!(self.stdout, Print("\r\n"))?; // Scrolls if the overlay reaches the bottom
+            }
+            let width = if row == 0 { cols.saturating_sub(self.origin_col as usize) } else { cols };
+            let line: String = line.chars().take(width.saturating_sub(1)).collect();
+            execute!(self.stdout, Print(line))?;
+        }
+        self.cursor_row_offset = lines.len().saturating_sub(1) as u16;
         self.stdout.flush().map_err(FepError::Io)?;
-
         Ok(())
     }
 
+    /// Returns the rows of the candidate window (text, highlighted): at most `candidate_rows`
+    /// candidates, scrolled so the highlighted one stays visible, with ▲/▼ in the first column
+    /// when more candidates exist above/below the window.
+    /// With `--max-candidates`, only the first candidates of the page are considered and a
+    /// final row says how many were left out. Each row shows Fcitx's own label, so selecting
+    /// by label still picks the same candidate whether or not others are hidden.
+    /// When Fcitx has other pages, a final ◀/▶ row says so, and a short last page is padded
+    /// to the height of a full one (per Fcitx's page size) so paging doesn't move the window.
+    fn candidate_window(&mut self, state: &AppState) -> Vec<(String, bool)> {
+        let hidden = match self.max_candidates {
+            0 => 0,
+            max => state.candidates.len().saturating_sub(max),
+        };
+        let total = state.candidates.len() - hidden;
+        let rows = self.candidate_rows.min(total);
+        if rows == 0 {
+            self.candidate_first = 0;
+            return Vec::new();
+        }
+        let full_rows = match self.max_candidates {
+            0 => self.candidate_rows.min(state.page_size()),
+            max => self.candidate_rows.min(state.page_size()).min(max),
+        };
+        let padding = if state.has_prev_page { full_rows.saturating_sub(rows) } else { 0 };
+        let mut footer = String::new();
+        if hidden > 0 {
+            footer.push_str(&format!(" … +{} more", hidden));
+        }
+        if state.has_prev_page || state.has_next_page {
+            let prev = if state.has_prev_page { '◀' } else { ' ' };
+            let next = if state.has_next_page { '▶' } else { ' ' };
+            footer.push_str(&format!(" {} {}", prev, next));
+        }
+        // Follow the highlighted candidate as selection or paging moves it
+        if let Some(highlighted) = state.highlighted_candidate.filter(|&index| index < total) {
+            if highlighted < self.candidate_first {
+                self.candidate_first = highlighted;
+            } else if highlighted >= self.candidate_first + rows {
+                self.candidate_first = highlighted + 1 - rows;
+            }
+        }
+        self.candidate_first = self.candidate_first.min(total - rows);
+
+        let first = self.candidate_first;
+        let width = (terminal_width() as usize).saturating_sub(1);
+        (first..first + rows)
+            .map(|index| {
+                let marker = if index == first && first > 0 {
+                    '▲'
+                } else if index == first + rows - 1 && first + rows < total {
+                    '▼'
+                } else {
+                    ' '
+                };
+                let highlighted = state.highlighted_candidate == Some(index);
+                // Without attributes the highlighted candidate is marked with '*' instead
+                let star = if highlighted && !self.styling.attributes { "*" } else { "" };
+                let line = format!("{}{}{}", marker, star, state.candidates[index]);
+                (line.chars().take(width).collect(), highlighted)
+            })
+            .chain(std::iter::repeat_with(|| (String::new(), false)).take(padding))
+            .chain((!footer.is_empty()).then(|| (footer.chars().take(width).collect(), false)))
+            .collect()
+    }
+
+    /// Returns the event log, if `--event-log` is enabled.
+    pub fn event_log(&self) -> Option<&EventLog> {
+        self.event_log.as_ref()
+    }
+
+    /// Returns the on-screen cell (column, row) of the cursor after the last render,
+    /// i.e. where the preedit cursor actually is after wrapping.
+    /// Queries the terminal, so call it only when the position may have changed.
+    pub fn cursor_cell(&self) -> Result<(u16, u16), FepError> {
+        cursor::position().map_err(FepError::Io)
+    }
+
     /// Cleans up the terminal state (synchronous).
     /// Disables raw mode and shows the cursor. Called automatically on Drop.
     fn cleanup(&mut self) {
         // Ignore errors during cleanup, as we're likely exiting anyway.
-        let _ = execute!(self.stdout, cursor::Show); // Restore cursor visibility
+        if !self.im_title.is_empty() {
+            let _ = execute!(self.stdout, Print(POP_TITLE)); // Restore the title saved at startup
+        }
+        if self.focus_reporting {
+            let _ = execute!(self.stdout, DisableFocusChange);
+        }
+        if self.keyboard_enhanced {
+            let _ = execute!(self.stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.stdout, cursor::Show); // Restore cursor visibility (even if never hidden)
         let _ = terminal::disable_raw_mode(); // Exit raw mode
         // Printing here might interfere with final error messages from main
         // println!("\nTerminal cleanup completed.");
     }
 }
 
+/// Queries whether the terminal supports the kitty keyboard protocol and logs which key
+/// reporting capabilities are available either way.
+fn detect_keyboard_enhancement() -> bool {
+    match terminal::supports_keyboard_enhancement() {
+        Ok(true) => {
+            println!("Keyboard enhancement enabled: Ctrl+Shift combinations and key releases are reported.");
+            true
+        }
+        Ok(false) => {
+            eprintln!(
+                "Terminal doesn't support the kitty keyboard protocol: Ctrl+Shift combinations \
+                 can't be told apart from Ctrl ones and key releases are not forwarded to Fcitx."
+            );
+            false
+        }
+        Err(e) => {
+            eprintln!("Failed to query keyboard enhancement support, continuing without it: {}", e);
+            false
+        }
+    }
+}
+
+/// Whether a terminal write error is worth retrying rather than ending the session.
+/// A closed terminal (broken pipe, EOF) or anything unexpected is treated as fatal.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::TimedOut)
+}
+
+/// Returns the terminal width in columns, falling back to 80 if it can't be queried.
+/// Some environments report a 0x0 size; that is clamped to one column (with a warning, once),
+/// which every layout computation here handles, so rendering degrades instead of failing.
+fn terminal_width() -> u16 {
+    usable_width(terminal::size())
+}
+
+/// The width to lay out for, given the terminal's reported size (see `terminal_width`).
+fn usable_width(size: io::Result<(u16, u16)>) -> u16 {
+    static ZERO_SIZE_WARNED: AtomicBool = AtomicBool::new(false);
+    match size {
+        Ok((0, _)) => {
+            if !ZERO_SIZE_WARNED.swap(true, Ordering::Relaxed) {
+                eprintln!("Warning: the terminal reports a width of 0; laying out for 1 column.");
+            }
+            1
+        }
+        Ok((cols, _)) => cols,
+        Err(_) => 80,
+    }
+}
+
+/// Where to put the cursor after printing `width` cells starting at column `origin`, with
+/// the cursor `to_cursor` cells into them. Returns the row the printed text ended on, and the
+/// cursor's row and column; rows are counted from the origin's row.
+///
+/// Terminals defer the wrap after writing the last column: the cursor stays on that row
+/// until another character is printed. Text ending exactly at the right edge therefore ends
+/// on its last printed row, and a cursor at the end of such text is left on the last column
+/// instead of being moved to the start of a row that was never opened.
+fn cursor_placement(origin: usize, width: usize, to_cursor: usize, cols: usize) -> (usize, usize, usize) {
+    let cols = cols.max(1);
+    let end = origin + width;
+    let end_row = if width == 0 { origin / cols } else { (end - 1) / cols };
+    let target = origin + to_cursor.min(width);
+    if to_cursor >= width && width > 0 && end.is_multiple_of(cols) {
+        return (end_row, end_row, cols - 1);
+    }
+    (end_row, target / cols, target % cols)
+}
+
+/// Returns the column the cursor ends up in after printing `text` starting at `col`,
+/// wrapping at `cols` columns. `tail` is the grapheme cluster printed just before `col`:
+/// if `text` continues it, only the width the cluster gains is counted.
+/// A wide character that doesn't fit in the rest of the row wraps to the next one.
+fn advance_column(col: u16, tail: &str, text: &str, cols: u16) -> u16 {
+    let cols = cols.max(1) as usize;
+    let mut col = col as usize;
+    let joined = format!("{}{}", tail, text);
+    for (i, grapheme) in joined.graphemes(true).enumerate() {
+        let width = match i {
+            0 if !tail.is_empty() => grapheme_width(grapheme).saturating_sub(grapheme_width(tail)),
+            _ => grapheme_width(grapheme),
+        };
+        if grapheme.contains(['\n', '\r']) {
+            col = 0;
+        } else if col + width > cols {
+            col = width % cols; // Wrapped before the character
+        } else {
+            col = (col + width) % cols;
+        }
+    }
+    col as u16
+}
+
+
 // Drop implementation ensures cleanup happens when Terminal goes out of scope.
 impl Drop for Terminal {
     fn drop(&mut self) {
         self.cleanup();
     }
 }
+
+/// Terminals writing into a buffer, shared with the tests of other modules.
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+    use crate::config::ColorMode;
+    use std::sync::{Arc, Mutex};
+
+    /// Output shared with a test, which reads what the terminal wrote.
+    #[derive(Clone, Default)]
+    pub(crate) struct Output {
+        written: Arc<Mutex<Vec<u8>>>,
+        failure: Arc<Mutex<Option<io::ErrorKind>>>, // Error every write returns, if set
+    }
+
+    impl Output {
+        /// Takes everything written since the last call.
+        pub(crate) fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.written.lock().unwrap())).unwrap()
+        }
+
+        /// Makes every write fail with `kind` until called again with `None`.
+        pub(crate) fn fail_with(&self, kind: Option<io::ErrorKind>) {
+            *self.failure.lock().unwrap() = kind;
+        }
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(kind) = *self.failure.lock().unwrap() {
+                return Err(kind.into());
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A terminal writing into a buffer, without styling so the output is plain text and
+    /// cursor movements.
+    pub(crate) fn capture() -> (Terminal, Output) {
+        capture_with(Config::default())
+    }
+
+    /// Like `capture`, with the given configuration (styling is still turned off).
+    pub(crate) fn capture_with(config: Config) -> (Terminal, Output) {
+        let config = Config { color: ColorMode::Never, ..config };
+        let output = Output::default();
+        let terminal = Terminal::with_output(&config, Box::new(output.clone()), Vec::new(), false).unwrap();
+        (terminal, output)
+    }
+
+    // Sequences crossterm writes for the commands used by `draw`
+    pub(crate) const CLEAR_FROM_ORIGIN: &str = "\x1b[1G\x1b[J"; // MoveToColumn(0), Clear(FromCursorDown)
+    pub(crate) const SAVE_ORIGIN: &str = "\x1b7";
+    pub(crate) const RESTORE_ORIGIN: &str = "\x1b8";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::*;
+    use super::*;
+    use crate::state::FcitxUpdate;
+
+    fn preedit(text: &str, cursor_pos: i32) -> FcitxUpdate {
+        FcitxUpdate::UpdatePreedit { text: text.to_string(), cursor_pos }
+    }
+
+    #[test]
+    fn cursor_placement_at_the_start_middle_and_end_of_a_multibyte_preedit() {
+        // "にほん" is six cells; Fcitx reports the cursor in bytes (three per character)
+        for (byte_pos, column) in [(0, 1), (3, 3), (9, 7)] {
+            let (mut terminal, output) = capture();
+            let mut state = AppState::new();
+            state.apply_update(preedit("にほん", byte_pos));
+            terminal.render(&mut state).unwrap();
+            let frame = output.take();
+            // The cursor goes straight to its column (1-based in the sequence), never by a relative move
+            assert!(frame.ends_with(&format!("にほん\x1b[{}G", column)), "byte {}: {:?}", byte_pos, frame);
+            assert!(!frame.contains('D'), "relative move in {:?}", frame);
+        }
+    }
+
+    #[test]
+    fn transient_render_failures_are_survived_and_redrawn_from_the_origin() {
+        let (mut terminal, output) = capture();
+        let mut state = AppState::new();
+        state.apply_update(preedit("あ", 3));
+        terminal.render(&mut state).unwrap();
+        output.take();
+
+        output.fail_with(Some(io::ErrorKind::WouldBlock));
+        for _ in 1..MAX_RENDER_FAILURES {
+            terminal.render(&mut state).unwrap();
+        }
+        output.fail_with(None);
+        terminal.render(&mut state).unwrap();
+        // The cursor may have been left anywhere: the redraw starts from the saved origin
+        let frame = output.take();
+        assert!(frame.starts_with(&format!("{}{}", RESTORE_ORIGIN, CLEAR_FROM_ORIGIN)), "{:?}", frame);
+        assert!(frame.contains('あ'));
+
+        // A successful render resets the count
+        output.fail_with(Some(io::ErrorKind::TimedOut));
+        for _ in 1..MAX_RENDER_FAILURES {
+            terminal.render(&mut state).unwrap();
+        }
+        assert!(matches!(terminal.render(&mut state), Err(FepError::Io(_))));
+    }
+
+    #[test]
+    fn fatal_render_failure_is_returned_at_once() {
+        let (mut terminal, output) = capture();
+        let mut state = AppState::new();
+        state.apply_update(preedit("あ", 3));
+        output.fail_with(Some(io::ErrorKind::BrokenPipe));
+        assert!(matches!(terminal.render(&mut state), Err(FepError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe));
+    }
+
+    #[test]
+    fn text_after_a_commit_cursor_stays_on_screen() {
+        let (mut terminal, output) = capture();
+        let mut state = AppState::new();
+        state.apply_update(FcitxUpdate::CommitStringWithCursor { text: "()".to_string(), cursor: 1 });
+        terminal.render(&mut state).unwrap();
+        output.take();
+
+        // Composing inside the parentheses draws ")" behind the preedit
+        state.apply_update(preedit("あ", 3));
+        terminal.render(&mut state).unwrap();
+        let frame = output.take();
+        // The origin is the cursor inside the commit, behind "(" (column 2 in the sequence)
+        assert_eq!(frame, format!("\x1b[2G\x1b[J{}あ)\x1b[4G", SAVE_ORIGIN));
+
+        // The commit goes in before ")", which is still drawn after it
+        state.apply_update(FcitxUpdate::CommitString("亜".to_string()));
+        state.apply_update(preedit("", 0));
+        terminal.render(&mut state).unwrap();
+        let frame = output.take();
+        assert!(frame.starts_with(&format!("\x1b[2G\x1b[J\x1b[2G\x1b[J亜{}){}", SAVE_ORIGIN, RESTORE_ORIGIN)), "{:?}", frame);
+        assert!(frame.ends_with(&format!("{}){}", SAVE_ORIGIN, "\x1b[4G")), "{:?}", frame);
+        assert_eq!(terminal.origin_col, 3);
+    }
+
+    #[test]
+    fn cursor_placement_handles_both_ends_and_wrapping() {
+        // (row the text ends on, cursor row, cursor column) for 6 cells from column 0 of 80
+        assert_eq!(cursor_placement(0, 6, 0, 80), (0, 0, 0));
+        assert_eq!(cursor_placement(0, 6, 2, 80), (0, 0, 2));
+        assert_eq!(cursor_placement(0, 6, 6, 80), (0, 0, 6));
+        // Text ending exactly at the right edge: the cursor stays on its last column
+        assert_eq!(cursor_placement(2, 6, 6, 8), (0, 0, 7));
+        // Wrapped text, cursor on the second row
+        assert_eq!(cursor_placement(4, 6, 5, 8), (1, 1, 1));
+        // A cursor beyond the text is clamped to its end
+        assert_eq!(cursor_placement(0, 6, 100, 80), (0, 0, 6));
+        // Rows of a very long preedit stay countable (the caller clamps them for MoveUp)
+        assert_eq!(cursor_placement(0, 1_000_000, 0, 10), (99_999, 0, 0));
+    }
+
+    #[test]
+    fn zwj_sequence_split_across_commits_advances_one_cell_pair() {
+        assert_eq!(advance_column(0, "", "👨\u{200d}👩\u{200d}👧", 80), 2);
+        // The second fragment continues the first one's cluster, which already took two cells
+        assert_eq!(advance_column(2, "👨\u{200d}", "👩\u{200d}👧", 80), 2);
+        assert_eq!(advance_column(2, "👨\u{200d}👩\u{200d}👧", "x", 80), 3);
+    }
+
+    #[test]
+    fn preedit_after_a_split_zwj_commit_starts_behind_the_emoji() {
+        let (mut terminal, output) = capture();
+        let mut state = AppState::new();
+        for fragment in ["👨\u{200d}", "👩\u{200d}👧"] {
+            state.apply_update(FcitxUpdate::CommitString(fragment.to_string()));
+            terminal.render(&mut state).unwrap();
+        }
+        output.take();
+        state.apply_update(preedit("あ", 3));
+        terminal.render(&mut state).unwrap();
+        // The region cleared for the preedit starts at column 2, right after the one emoji
+        assert_eq!(output.take(), format!("\x1b[3G\x1b[J{}あ\x1b[5G", SAVE_ORIGIN));
+    }
+
+    fn with_candidates(candidates: &[&str], highlighted: Option<usize>) -> AppState {
+        let mut state = AppState::new();
+        state.apply_update(FcitxUpdate::ClientSideUi {
+            candidates: candidates.iter().map(|c| c.to_string()).collect(),
+            highlighted,
+            aux_visible: false,
+            has_prev: false,
+            has_next: false,
+        });
+        state
+    }
+
+    #[test]
+    fn max_candidates_keeps_fcitx_labels_on_the_shown_candidates() {
+        let config = Config { candidate_rows: 10, max_candidates: 3, ..Config::default() };
+        let (mut terminal, _output) = capture_with(config);
+        let state = with_candidates(&["1. 日本", "2. 二本", "3. 煮本", "4. 似本", "5. 荷本"], Some(1));
+        let rows = terminal.candidate_window(&state);
+        let lines: Vec<&str> = rows.iter().map(|(line, _)| line.as_str()).collect();
+        // Labels are Fcitx's own, so "2" still picks index 1, and the hidden ones are counted.
+        // Without styling the highlighted candidate is marked with '*'.
+        assert_eq!(lines, [" 1. 日本", " *2. 二本", " 3. 煮本", " … +2 more"]);
+        let highlighted: Vec<bool> = rows.iter().map(|&(_, highlighted)| highlighted).collect();
+        assert_eq!(highlighted, [false, true, false, false]);
+    }
+
+    #[test]
+    fn zero_terminal_width_is_clamped_to_one_column() {
+        assert_eq!(usable_width(Ok((0, 0))), 1);
+        assert_eq!(usable_width(Ok((0, 24))), 1);
+        assert_eq!(usable_width(Ok((100, 24))), 100);
+        assert_eq!(usable_width(Err(io::Error::other("no terminal"))), 80);
+    }
+
+    #[test]
+    fn layout_works_in_a_single_column() {
+        // Every cell is its own row; wide characters can't fit and still advance sanely
+        assert_eq!(cursor_placement(0, 3, 1, 1), (2, 1, 0));
+        assert_eq!(cursor_placement(0, 3, 3, 1), (2, 2, 0));
+        assert_eq!(cursor_placement(0, 0, 0, 1), (0, 0, 0));
+        assert_eq!(advance_column(0, "", "abc", 1), 0);
+        assert_eq!(advance_column(0, "", "あい", 1), 0);
+        // A zero width passed through anyway is treated as one column
+        assert_eq!(cursor_placement(0, 3, 1, 0), cursor_placement(0, 3, 1, 1));
+        assert_eq!(advance_column(0, "", "あい", 0), 0);
+    }
+
+    #[test]
+    fn empty_preedit_after_a_preedit_renders_a_clean_line() {
+        let (mut terminal, output) = capture();
+        let mut state = AppState::new();
+        state.apply_update(preedit("にほん", 9));
+        terminal.render(&mut state).unwrap();
+        assert_eq!(output.take(), format!("{}{}にほん\x1b[7G", CLEAR_FROM_ORIGIN, SAVE_ORIGIN));
+
+        state.apply_update(preedit("", 0));
+        terminal.render(&mut state).unwrap();
+        assert_eq!(output.take(), format!("{}{}", CLEAR_FROM_ORIGIN, SAVE_ORIGIN));
+    }
+}
@@ -0,0 +1,114 @@
+// src/utf8.rs
+// Incremental UTF-8 decoding for byte streams (e.g. output relayed from a child PTY),
+// where reads can split a multibyte sequence across buffer boundaries.
+
+/// Decodes UTF-8 chunk by chunk, carrying incomplete trailing bytes over to the next chunk.
+#[derive(Debug, Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>, // Incomplete sequence left over from the previous chunk (at most 3 bytes)
+}
+
+impl Utf8Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes the complete sequences of `pending + chunk` and keeps an incomplete tail
+    /// for the next call. Invalid bytes are replaced with U+FFFD instead of panicking.
+    pub fn decode(&mut self, chunk: &[u8]) -> String {
+        self.pending.extend_from_slice(chunk);
+        let mut out = String::with_capacity(self.pending.len());
+        let mut rest = &self.pending[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    out.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    // `valid_up_to` guarantees this prefix is valid UTF-8.
+                    out.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match e.error_len() {
+                        // Truncated sequence at the end: wait for more bytes.
+                        None => {
+                            rest = after;
+                            break;
+                        }
+                        // Invalid sequence in the middle: replace it and keep going.
+                        Some(len) => {
+                            out.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[len..];
+                        }
+                    }
+                }
+            }
+        }
+        self.pending = rest.to_vec();
+        out
+    }
+
+    /// Flushes any leftover incomplete sequence (e.g. at end of stream) as U+FFFD.
+    pub fn finish(&mut self) -> String {
+        if self.pending.is_empty() {
+            String::new()
+        } else {
+            self.pending.clear();
+            char::REPLACEMENT_CHARACTER.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `bytes` split at `at` and returns what each call decoded.
+    fn split_decode(bytes: &[u8], at: usize) -> (String, String, String) {
+        let mut decoder = Utf8Decoder::new();
+        let first = decoder.decode(&bytes[..at]);
+        let second = decoder.decode(&bytes[at..]);
+        (first, second, decoder.finish())
+    }
+
+    #[test]
+    fn reassembles_sequences_split_across_chunks() {
+        for s in ["é", "あ", "🦀"] {
+            let bytes = s.as_bytes();
+            for at in 1..bytes.len() {
+                assert_eq!(split_decode(bytes, at), (String::new(), s.to_string(), String::new()), "{s} split at {at}");
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_complete_prefix_and_carries_the_tail() {
+        let bytes = "aあb".as_bytes();
+        assert_eq!(split_decode(bytes, 2), ("a".to_string(), "あb".to_string(), String::new()));
+    }
+
+    #[test]
+    fn carries_a_tail_over_several_chunks() {
+        let mut decoder = Utf8Decoder::new();
+        let bytes = "🦀".as_bytes();
+        let decoded: String = bytes.iter().map(|&b| decoder.decode(&[b])).collect();
+        assert_eq!(decoded, "🦀");
+    }
+
+    #[test]
+    fn replaces_invalid_bytes() {
+        let mut decoder = Utf8Decoder::new();
+        assert_eq!(decoder.decode(b"a\xffb\x80c"), "a\u{fffd}b\u{fffd}c");
+        // A lead byte followed by a non-continuation byte is invalid, not truncated
+        assert_eq!(decoder.decode(b"\xe3a"), "\u{fffd}a");
+    }
+
+    #[test]
+    fn finish_flushes_a_truncated_sequence() {
+        let mut decoder = Utf8Decoder::new();
+        assert_eq!(decoder.decode(&"あ".as_bytes()[..2]), "");
+        assert_eq!(decoder.finish(), "\u{fffd}");
+        assert_eq!(decoder.finish(), "");
+    }
+}
@@ -0,0 +1,64 @@
+// src/width.rs
+// Display width of text in terminal cells, measured per grapheme cluster.
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthChar;
+
+/// Display width of one grapheme cluster.
+///
+/// A cluster occupies the cells of its widest character, so combining marks and joiners add
+/// nothing. Emoji sequences (ZWJ sequences such as a family, emoji with the VS16 presentation
+/// selector, flag pairs) are drawn as a single wide glyph by terminals that render them, even
+/// when every part would be narrow on its own, so they count as two cells.
+pub fn grapheme_width(grapheme: &str) -> usize {
+    let mut chars = grapheme.chars();
+    let Some(first) = chars.next() else { return 0 };
+    let is_emoji_sequence = chars.clone().next().is_some()
+        && (grapheme.contains(['\u{200d}', '\u{fe0f}']) || ('\u{1f1e6}'..='\u{1f1ff}').contains(&first));
+    if is_emoji_sequence {
+        return 2;
+    }
+    std::iter::once(first)
+        .chain(chars)
+        .map(|c| c.width().unwrap_or(0))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Display width of `text`: the sum of its grapheme clusters' widths.
+pub fn display_width(text: &str) -> usize {
+    text.graphemes(true).map(grapheme_width).sum()
+}
+
+/// The last grapheme cluster of `text`, which a following fragment may still extend.
+pub fn last_grapheme(text: &str) -> &str {
+    text.graphemes(true).next_back().unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAMILY: &str = "👨\u{200d}👩\u{200d}👧";
+
+    #[test]
+    fn emoji_sequences_take_one_cell_pair() {
+        assert_eq!(grapheme_width(FAMILY), 2);
+        assert_eq!(grapheme_width("❤\u{fe0f}"), 2);
+        assert_eq!(grapheme_width("🇯🇵"), 2);
+        assert_eq!(display_width(&format!("a{}b", FAMILY)), 4);
+    }
+
+    #[test]
+    fn combining_marks_add_no_width() {
+        assert_eq!(grapheme_width("e\u{301}"), 1);
+        assert_eq!(display_width("e\u{301}あ"), 3);
+        assert_eq!(grapheme_width(""), 0);
+    }
+
+    #[test]
+    fn last_grapheme_keeps_the_whole_cluster() {
+        assert_eq!(last_grapheme(&format!("x{}", FAMILY)), FAMILY);
+        assert_eq!(last_grapheme(""), "");
+    }
+}
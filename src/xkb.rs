@@ -0,0 +1,75 @@
+// src/xkb.rs
+// Keycodes for forwarded keys from the user's xkb keymap (the `xkb` feature).
+
+use std::collections::HashMap;
+
+/// Resolves keysyms to the keycode (and level modifiers) that produce them in the user's
+/// keymap, for engines that look at keycodes rather than keysyms.
+///
+/// Terminals only deliver characters, so the keymap is compiled from the xkb defaults
+/// (`XKB_DEFAULT_LAYOUT` and friends, else the system default) rather than read from the
+/// display server. Without the `xkb` feature, or when no keymap can be compiled (e.g. on a
+/// bare console without xkb data), keys are forwarded with keycode 0 as before.
+#[derive(Debug, Default)]
+pub struct KeycodeResolver {
+    // Keysym -> (keycode, modifiers selecting the shift level)
+    keys: HashMap<u32, (u32, u32)>,
+}
+
+impl KeycodeResolver {
+    /// Compiles the default keymap and indexes its keysyms, logging which source is used.
+    pub fn new() -> Self {
+        match Self::from_xkb() {
+            Some(resolver) => {
+                println!("Keycodes: from the xkb keymap ({} keysyms).", resolver.keys.len());
+                resolver
+            }
+            None => {
+                println!("Keycodes: none (no xkb keymap); forwarding keycode 0.");
+                Self::default()
+            }
+        }
+    }
+
+    /// Fills in the keycode for a mapped key, adding the modifiers of the shift level the
+    /// keysym sits on. Keys that already have a keycode, or aren't in the keymap, are unchanged.
+    pub fn resolve(&self, (keysym, keycode, state): (u32, u32, u32)) -> (u32, u32, u32) {
+        match self.keys.get(&keysym) {
+            Some(&(resolved, level_state)) if keycode == 0 => (keysym, resolved, state | level_state),
+            _ => (keysym, keycode, state),
+        }
+    }
+
+    #[cfg(feature = "xkb")]
+    fn from_xkb() -> Option<Self> {
+        use crate::keymap::masks;
+        use xkbcommon::xkb;
+
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_names(&context, "", "", "", "", None, xkb::KEYMAP_COMPILE_NO_FLAGS)?;
+        // Levels 1-4 of the first layout: plain, Shift, AltGr (Mod5), Shift+AltGr
+        let level_states = [0, masks::ShiftMask, masks::Mod5Mask, masks::ShiftMask | masks::Mod5Mask];
+        let mut keys: HashMap<u32, (u32, u32, usize)> = HashMap::new();
+        keymap.key_for_each(|keymap, key| {
+            let levels = keymap.num_levels_for_key(key, 0) as usize;
+            for (level, &level_state) in level_states.iter().enumerate().take(levels) {
+                for keysym in keymap.key_get_syms_by_level(key, 0, level as u32) {
+                    // Prefer the lowest level, then the lowest keycode (keys are visited in order)
+                    let entry = keys.entry(keysym.raw()).or_insert((key.raw(), level_state, level));
+                    if level < entry.2 {
+                        *entry = (key.raw(), level_state, level);
+                    }
+                }
+            }
+        });
+        if keys.is_empty() {
+            return None;
+        }
+        Some(KeycodeResolver { keys: keys.into_iter().map(|(keysym, (keycode, state, _))| (keysym, (keycode, state))).collect() })
+    }
+
+    #[cfg(not(feature = "xkb"))]
+    fn from_xkb() -> Option<Self> {
+        None
+    }
+}